@@ -0,0 +1,76 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A group of writes that is applied atomically.
+///
+/// Operations are applied in the order they were added, and either all of them take effect
+/// or none do. Readers never observe a batch that is only partially applied.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+// A single write within a batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds a write setting `key` to `value`.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {key: key.into(), value: value.into()});
+        self
+    }
+
+    /// Adds the removal of `key`.
+    ///
+    /// Applying the batch fails with [`KvsError::KeyNotFound`](crate::KvsError::KeyNotFound)
+    /// if the key does not exist at that point in the batch.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove {key: key.into()});
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no operations.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    // Checks that every key the batch removes exists at that point in the batch, given
+    // whether each key exists before the batch is applied.
+    pub(crate) fn check_removes(&self, mut exists: impl FnMut(&[u8]) -> bool) -> Result<()> {
+        let mut overlay: HashMap<&[u8], bool> = HashMap::new();
+        for op in &self.ops {
+            match op {
+                BatchOp::Set {key, ..} => {
+                    overlay.insert(key, true);
+                }
+                BatchOp::Remove {key} => {
+                    let present = overlay.get(key.as_slice()).copied().unwrap_or_else(|| exists(key));
+                    if !present {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    overlay.insert(key, false);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::{Result, bail};
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_kv::inspect::{self, FileReport, Op, RepairMode};
+use rust_kv::{KvStore, KvStoreOptions};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The ways `dump` can print records.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    /// One line of text per record.
+    Text,
+    /// One JSON object per record, on its own line.
+    Json,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Inspects and repairs the data files of a stopped RustKV store")]
+struct Args {
+    /// The directory the store keeps its data in.
+    #[arg(long, global = true, default_value = "data")]
+    data_dir: PathBuf,
+
+    /// A file of encryption keys, to read an encrypted store.
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Prints every record with its offset.
+    Dump {
+        /// How to print the records.
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+
+        /// The segments or checkpoints to print, instead of every one in the data directory.
+        files: Vec<PathBuf>,
+    },
+    /// Checks the checksum of every record and reports the damaged ones.
+    Verify {
+        /// The segments or checkpoints to check, instead of every one in the data directory.
+        files: Vec<PathBuf>,
+    },
+    /// Writes a copy of a segment or checkpoint without its damaged records.
+    Repair {
+        /// The damaged segment or checkpoint.
+        file: PathBuf,
+
+        /// Where to write the repaired copy, such as a file of the same name in another directory.
+        #[arg(long)]
+        output: PathBuf,
+
+        /// Keep the intact records after the first damaged one, instead of truncating there.
+        #[arg(long)]
+        skip: bool,
+    },
+    /// Prints the number of keys and how much of the log is live.
+    Stats,
+    /// Compacts the store.
+    Compact,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut options = KvStoreOptions::new();
+    if let Some(key_file) = &args.key_file {
+        options = options.key_file(key_file);
+    }
+
+    match args.command {
+        Command::Dump {format, files} => {
+            let mut out = io::stdout().lock();
+            for path in files_or_all(&args.data_dir, files)? {
+                dump(&mut out, &inspect::inspect_file(&path, &options)?, format)?;
+            }
+        }
+        Command::Verify {files} => {
+            let (mut checked, mut damaged, mut unreadable) = (0, 0, 0);
+            for path in files_or_all(&args.data_dir, files)? {
+                // A file that cannot be read at all is reported, and the others are still checked.
+                let report = match inspect::inspect_file(&path, &options) {
+                    Ok(report) => report,
+                    Err(e) => {
+                        println!("{}: {}", path.display(), e);
+                        unreadable += 1;
+                        continue;
+                    }
+                };
+                for damage in &report.damage {
+                    println!("{}: {} bytes at offset {}: {}", path.display(), damage.len, damage.offset, damage.reason);
+                }
+                checked += report.records.len() + report.damage.len();
+                damaged += report.damage.len();
+            }
+            println!("{} records checked, {} damaged, {} files unreadable", checked, damaged, unreadable);
+            if damaged > 0 || unreadable > 0 {
+                bail!("found {} damaged records and {} unreadable files", damaged, unreadable);
+            }
+        }
+        Command::Repair {file, output, skip} => {
+            let mode = if skip { RepairMode::Skip } else { RepairMode::Truncate };
+            let before = inspect::inspect_file(&file, &options)?;
+            let after = inspect::repair_file(&file, &output, mode, &options)?;
+            println!(
+                "Wrote {} of {} records to {}, dropping {} damaged records",
+                after.records.len(),
+                before.records.len(),
+                output.display(),
+                before.damage.len()
+            );
+        }
+        Command::Stats => {
+            let stats = inspect::log_stats(&args.data_dir, &options)?;
+            println!("files:           {}", stats.files);
+            println!("records:         {}", stats.records);
+            println!("damaged records: {}", stats.damaged_records);
+            println!("keys:            {}", stats.keys);
+            println!("total bytes:     {}", stats.total_bytes);
+            println!("live bytes:      {}", stats.live_bytes);
+            println!("dead bytes:      {}", stats.dead_bytes);
+        }
+        Command::Compact => {
+            KvStore::open_with_options(&args.data_dir, options)?.compact()?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the given files, or every data file in `dir` if there are none.
+fn files_or_all(dir: &Path, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    if files.is_empty() {
+        Ok(inspect::data_files(dir)?)
+    } else {
+        Ok(files)
+    }
+}
+
+fn dump(out: &mut impl Write, report: &FileReport, format: Format) -> io::Result<()> {
+    // Records and damage are printed together, in the order they appear in the file.
+    let mut records = report.records.iter().peekable();
+    let mut damage = report.damage.iter().peekable();
+    loop {
+        let next_record = records.peek().map(|record| record.offset);
+        let next_damage = damage.peek().map(|damage| damage.offset);
+        match (next_record, next_damage) {
+            (Some(record), Some(damage)) if damage < record => {}
+            (Some(_), _) => {
+                let record = records.next().unwrap();
+                match format {
+                    Format::Text => writeln!(
+                        out,
+                        "{}@{} len={} seq={} ts={} {}",
+                        report.path.display(),
+                        record.offset,
+                        record.len,
+                        optional(record.seq),
+                        optional(record.timestamp),
+                        op_text(&record.op)
+                    )?,
+                    Format::Json => writeln!(
+                        out,
+                        "{}",
+                        json!({
+                            "file": report.path,
+                            "offset": record.offset,
+                            "len": record.len,
+                            "seq": record.seq,
+                            "timestamp": record.timestamp,
+                            "op": op_json(&record.op),
+                        })
+                    )?,
+                }
+                continue;
+            }
+            (None, None) => return Ok(()),
+            (None, Some(_)) => {}
+        }
+        let damage = damage.next().unwrap();
+        match format {
+            Format::Text => {
+                writeln!(out, "{}@{} len={} DAMAGED: {}", report.path.display(), damage.offset, damage.len, damage.reason)?
+            }
+            Format::Json => writeln!(
+                out,
+                "{}",
+                json!({
+                    "file": report.path,
+                    "offset": damage.offset,
+                    "len": damage.len,
+                    "damage": damage.reason,
+                })
+            )?,
+        }
+    }
+}
+
+fn optional(value: Option<u64>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+fn op_text(op: &Op) -> String {
+    match op {
+        Op::Set {key, value, expires_at: None} => format!("set {} = {}", bytes(key), bytes(value)),
+        Op::Set {key, value, expires_at: Some(expires_at)} => {
+            format!("set {} = {} expires_at={}", bytes(key), bytes(value), expires_at)
+        }
+        Op::Remove {key} => format!("remove {}", bytes(key)),
+        Op::Batch {ops} => format!("batch [{}]", ops.iter().map(op_text).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+fn op_json(op: &Op) -> Value {
+    match op {
+        Op::Set {key, value, expires_at} => {
+            json!({"op": "set", "key": bytes(key), "value": bytes(value), "expires_at": expires_at})
+        }
+        Op::Remove {key} => json!({"op": "remove", "key": bytes(key)}),
+        Op::Batch {ops} => json!({"op": "batch", "ops": ops.iter().map(op_json).collect::<Vec<_>>()}),
+    }
+}
+
+// Shows keys and values as text when they are UTF-8, and in hex otherwise.
+fn bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_owned(),
+        Err(_) => format!("0x{}", bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+    }
+}
@@ -1,7 +1,58 @@
 use anyhow::Result;
-use tracing::{info, error};
+use clap::{Parser, ValueEnum};
+use rust_kv::engine::claim_data_dir;
+use rust_kv::{BTreeEngine, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmEngine, MemoryEngine, Request, Response, ScanOptions, Transaction};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
+// The number of key-value pairs sent in each page of a scan.
+const SCAN_PAGE_SIZE: usize = 1000;
+
+/// The storage engines the server can run on.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Engine {
+    /// The log-structured store, `KvStore`.
+    Kvs,
+    /// A copy-on-write B-tree in a single page file.
+    #[value(name = "btree")]
+    BTree,
+    /// A log-structured merge tree of sorted SSTables, `LsmEngine`.
+    Lsm,
+    /// Keeps everything in memory. Nothing is persisted.
+    Memory,
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Serves a RustKV store over TCP")]
+struct Args {
+    /// The address to listen on.
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+
+    /// The storage engine to use.
+    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+
+    /// The directory the engine keeps its data in.
+    #[arg(long, default_value = "data")]
+    data_dir: PathBuf,
+
+    /// How often the `kvs` engine logs its statistics, in seconds, or 0 to never log them.
+    #[arg(long, default_value_t = 60)]
+    stats_interval: u64,
+
+    /// The directory backups are written to and restored from. Clients name a directory
+    /// within it. Backup and restore requests are refused unless this is set.
+    #[arg(long)]
+    backup_root: Option<PathBuf>,
+}
+
 fn main() -> Result<()> {
 
     tracing_subscriber::fmt()
@@ -10,14 +61,318 @@ fn main() -> Result<()> {
 
     info!("RustKV Server is starting...");
 
-    if let Err(e) = run() {
+    if let Err(e) = run(Args::parse()) {
         error!("Fatal error: {}", e);
     }
 
     Ok(())
 }
 
-fn run() -> Result<()> {
-    info!("RustKV Server is running...");
+fn run(args: Args) -> Result<()> {
+    let name = args.engine.to_possible_value().expect("engines are never skipped");
+    info!("RustKV Server is running with the {} engine", name.get_name());
+
+    // Persistent engines refuse to start on a directory written by a different engine.
+    match args.engine {
+        Engine::Kvs => {
+            claim_data_dir(&args.data_dir, name.get_name())?;
+            let mut options = KvStoreOptions::new();
+            if args.stats_interval > 0 {
+                options = options.stats_interval(Duration::from_secs(args.stats_interval));
+            }
+            serve(KvStore::open_with_options(&args.data_dir, options)?, args.addr, args.backup_root)
+        }
+        Engine::BTree => {
+            claim_data_dir(&args.data_dir, name.get_name())?;
+            serve(BTreeEngine::open(&args.data_dir)?, args.addr, args.backup_root)
+        }
+        Engine::Lsm => {
+            claim_data_dir(&args.data_dir, name.get_name())?;
+            serve(LsmEngine::open(&args.data_dir)?, args.addr, args.backup_root)
+        }
+        Engine::Memory => serve(MemoryEngine::new(), args.addr, args.backup_root),
+    }
+}
+
+// Accepts connections and serves each one on its own thread.
+fn serve(engine: impl KvsEngine, addr: SocketAddr, backup_root: Option<PathBuf>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let engine = engine.clone();
+                let backup_root = backup_root.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(engine, stream, backup_root.as_deref()) {
+                        warn!("Connection closed with an error: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a connection: {}", e),
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Answers the requests sent over one connection until the client hangs up.
+fn handle_connection(engine: impl KvsEngine, stream: TcpStream, backup_root: Option<&Path>) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    // The transaction started by the client, if any.
+    let mut txn: Option<Transaction> = None;
+
+    loop {
+        let request: Request = match bincode::deserialize_from(&mut reader) {
+            Ok(request) => request,
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(e.into()),
+            },
+        };
+        debug!("Request from {}: {:?}", peer, request);
+
+        // Scans are answered with a stream of responses rather than a single one.
+        if let Request::Scan {start, end, options} = request {
+            match &mut txn {
+                Some(txn) => send_transaction_scan(txn, start, end, options, &mut writer)?,
+                None => send_scan(&engine, start, end, options, &mut writer)?,
+            }
+            continue;
+        }
+
+        let response = match request {
+            Request::Begin | Request::Commit | Request::Abort => handle_transaction(&engine, &mut txn, request),
+            _ => match &mut txn {
+                Some(txn) => handle_in_transaction(txn, request),
+                None => handle_request(&engine, request, backup_root),
+            },
+        };
+        bincode::serialize_into(&mut writer, &response)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Sends the results of a scan one page at a time, so that neither side has to hold all of
+// them at once. Each page continues after the last key of the previous page, and is read from
+// a snapshot taken before the first one if the engine supports them, so that writes made in
+// between do not move keys past the cursor.
+fn send_scan(
+    engine: &impl KvsEngine,
+    mut start: Bound<Vec<u8>>,
+    mut end: Bound<Vec<u8>>,
+    options: ScanOptions,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let snapshot = match engine.snapshot() {
+        Ok(snapshot) => Some(snapshot),
+        Err(KvsError::Unsupported(_)) => None,
+        Err(e) => {
+            bincode::serialize_into(&mut *writer, &Response::Error(e.to_string()))?;
+            writer.flush()?;
+            return Ok(());
+        }
+    };
+
+    let mut remaining = options.limit.unwrap_or(usize::MAX);
+    loop {
+        let page_options = ScanOptions {limit: Some(remaining.min(SCAN_PAGE_SIZE)), ..options};
+        let range = (start.clone(), end.clone());
+        let page = match &snapshot {
+            Some(snapshot) => snapshot.scan_bytes(range, page_options),
+            None => engine.scan_bytes(range, page_options),
+        };
+        let entries = match page {
+            Ok(entries) => entries,
+            Err(e) => {
+                bincode::serialize_into(&mut *writer, &Response::Error(e.to_string()))?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+
+        remaining -= entries.len();
+        let done = entries.len() < SCAN_PAGE_SIZE || remaining == 0;
+        if let Some((last, _)) = entries.last() {
+            if options.reverse {
+                end = Bound::Excluded(last.clone());
+            } else {
+                start = Bound::Excluded(last.clone());
+            }
+        }
+
+        bincode::serialize_into(&mut *writer, &Response::ScanPage {entries, done})?;
+        writer.flush()?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+// Sends the results of a scan within a transaction. The transaction reads the whole range at
+// once, so the results are only split into pages for the client's sake.
+fn send_transaction_scan(
+    txn: &mut Transaction,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    options: ScanOptions,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let entries = match txn.scan((start, end), options) {
+        Ok(entries) => entries,
+        Err(e) => {
+            bincode::serialize_into(&mut *writer, &Response::Error(e.to_string()))?;
+            writer.flush()?;
+            return Ok(());
+        }
+    };
+
+    let mut pages = entries.chunks(SCAN_PAGE_SIZE).peekable();
+    if pages.peek().is_none() {
+        bincode::serialize_into(&mut *writer, &Response::ScanPage {entries: Vec::new(), done: true})?;
+    }
+    while let Some(page) = pages.next() {
+        let done = pages.peek().is_none();
+        bincode::serialize_into(&mut *writer, &Response::ScanPage {entries: page.to_vec(), done})?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Starts, commits or aborts the connection's transaction.
+fn handle_transaction(engine: &impl KvsEngine, txn: &mut Option<Transaction>, request: Request) -> Response {
+    let result = match (request, txn.take()) {
+        (Request::Begin, None) => engine.begin().map(|begun| {
+            *txn = Some(begun);
+            Response::Success(None)
+        }),
+        (Request::Begin, Some(open)) => {
+            *txn = Some(open);
+            return Response::Error("a transaction is already open".to_owned());
+        }
+        (Request::Commit, Some(open)) => open.commit().map(|()| Response::Success(None)),
+        (Request::Abort, Some(open)) => {
+            open.abort();
+            Ok(Response::Success(None))
+        }
+        (_, None) => return Response::Error("no transaction is open".to_owned()),
+        _ => unreachable!("only transaction requests are handled here"),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(KvsError::Conflict) => Response::Conflict,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+// Answers a request sent while a transaction is open. Only plain reads and writes can take
+// part in a transaction.
+fn handle_in_transaction(txn: &mut Transaction, request: Request) -> Response {
+    let result = match request {
+        Request::Get {key} => txn.get(&key).map(Response::Success),
+        Request::Set {key, value} => {
+            txn.set(key, value);
+            Ok(Response::Success(None))
+        }
+        Request::Remove {key} => txn.remove(&key).map(|()| Response::Success(None)),
+        _ => return Response::Error("request is not supported within a transaction".to_owned()),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn handle_request(engine: &impl KvsEngine, request: Request, backup_root: Option<&Path>) -> Response {
+    let result = match request {
+        Request::Get {key} => engine.get_bytes(&key).map(Response::Success),
+        Request::Set {key, value} => engine.set_bytes(key, value).map(|()| Response::Success(None)),
+        Request::Remove {key} => engine.remove_bytes(&key).map(|()| Response::Success(None)),
+        Request::SetEx {key, value, ttl} => engine.set_with_ttl(key, value, ttl).map(|()| Response::Success(None)),
+        Request::Ttl {key} => engine.ttl(&key).map(Response::Ttl),
+        Request::Batch {batch} => engine.write(batch).map(|()| Response::Success(None)),
+        Request::CompareAndSwap {key, expected, new} => {
+            engine.compare_and_swap(key, expected, new).map(|()| Response::Success(None))
+        }
+        Request::SetIfAbsent {key, value} => engine.set_if_absent(key, value).map(|()| Response::Success(None)),
+        Request::SetIfPresent {key, value} => engine.set_if_present(key, value).map(|()| Response::Success(None)),
+        Request::Backup {dir} => match backup_dir(backup_root, &dir) {
+            Ok(dir) => engine.backup_to(&dir).map(|()| Response::Success(None)),
+            Err(refusal) => return Response::Error(refusal),
+        },
+        Request::Restore {dir} => match backup_dir(backup_root, &dir) {
+            Ok(dir) => engine.restore_from(&dir).map(|()| Response::Success(None)),
+            Err(refusal) => return Response::Error(refusal),
+        },
+        Request::Stats => engine.stats().map(Response::Stats),
+        Request::Scan {..} => unreachable!("scans are streamed by handle_connection"),
+        Request::Begin | Request::Commit | Request::Abort => {
+            unreachable!("transactions are handled by handle_transaction")
+        }
+    };
+
+    match result {
+        Ok(response) => response,
+        // Clients building locks on top of conditional writes need to tell a lost race apart from a failure.
+        Err(KvsError::ConditionFailed) => Response::ConditionFailed,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+// Resolves the directory a backup or restore request names within the backup root. Only
+// relative names that stay within it are accepted, so that clients cannot have the store
+// copied to, or replaced from, anywhere else on the server.
+fn backup_dir(backup_root: Option<&Path>, dir: &Path) -> std::result::Result<PathBuf, String> {
+    let Some(backup_root) = backup_root else {
+        return Err("backups are disabled; start the server with --backup-root to enable them".to_owned());
+    };
+    let plain = dir.components().all(|component| matches!(component, Component::Normal(_)));
+    if !plain || dir.as_os_str().is_empty() {
+        return Err(format!("{} is not a directory name within the backup root", dir.display()));
+    }
+    Ok(backup_root.join(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backups_stay_within_the_backup_root() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path().join("data")).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let root = temp_dir.path().join("backups");
+        let outside = temp_dir.path().join("outside");
+
+        // Without a backup root, both requests are refused.
+        let request = Request::Backup {dir: PathBuf::from("nightly")};
+        assert!(matches!(handle_request(&store, request, None), Response::Error(_)));
+        let request = Request::Restore {dir: PathBuf::from("nightly")};
+        assert!(matches!(handle_request(&store, request, None), Response::Error(_)));
+
+        // So are paths that lead out of it.
+        let escapes = [outside.clone(), PathBuf::from("../outside"), PathBuf::from("nightly/../../outside"), PathBuf::new()];
+        for dir in escapes {
+            let request = Request::Backup {dir: dir.clone()};
+            assert!(matches!(handle_request(&store, request, Some(&root)), Response::Error(_)), "{}", dir.display());
+            let request = Request::Restore {dir: dir.clone()};
+            assert!(matches!(handle_request(&store, request, Some(&root)), Response::Error(_)), "{}", dir.display());
+        }
+        assert!(!outside.exists());
+
+        let request = Request::Backup {dir: PathBuf::from("nightly")};
+        assert!(matches!(handle_request(&store, request, Some(&root)), Response::Success(None)));
+        assert!(root.join("nightly").is_dir());
+        store.set("key1".to_owned(), "changed".to_owned()).unwrap();
+        let request = Request::Restore {dir: PathBuf::from("nightly")};
+        assert!(matches!(handle_request(&store, request, Some(&root)), Response::Success(None)));
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The most hash functions a filter uses, however many bits per key it has.
+const MAX_HASHES: u32 = 30;
+
+/// How well the bloom filters of an engine's on-disk files are doing, counted since it was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterStats {
+    /// The number of times a filter was consulted before reading a file.
+    pub checks: u64,
+    /// The number of reads skipped because a filter ruled the key out.
+    pub skipped: u64,
+    /// The number of reads a filter let through for a key the file did not hold.
+    pub false_positives: u64,
+}
+
+impl FilterStats {
+    /// Returns the share of lookups for keys missing from a file that its filter failed to
+    /// rule out, or 0 if there were none. Raising the bits per key lowers it.
+    pub fn false_positive_rate(&self) -> f64 {
+        let missing = self.skipped + self.false_positives;
+        if missing == 0 {
+            return 0.0;
+        }
+        self.false_positives as f64 / missing as f64
+    }
+}
+
+// The counters behind `FilterStats`, shared by everything reading through the filters.
+#[derive(Debug, Default)]
+pub(crate) struct FilterCounters {
+    checks: AtomicU64,
+    skipped: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterCounters {
+    // Records that a filter was consulted, and whether it ruled the key out.
+    pub(crate) fn checked(&self, may_contain: bool) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        if !may_contain {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Records that a file the filter let through did not hold the key.
+    pub(crate) fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> FilterStats {
+        FilterStats {
+            checks: self.checks.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// A bloom filter over the keys of one file. It never rules out a key the file holds, but lets
+// through some keys it does not, at a rate set by the number of bits per key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BloomFilter {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    // Builds a filter from the hashes of every key, as returned by `hash_key`. A filter with no
+    // bits per key lets every key through.
+    pub(crate) fn build(key_hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        if bits_per_key == 0 {
+            return BloomFilter {hashes: 0, bits: Vec::new()};
+        }
+
+        // ln(2) times the bits per key is the number of hash functions that minimizes false positives.
+        let hashes = ((bits_per_key as f64 * std::f64::consts::LN_2).round() as u32).clamp(1, MAX_HASHES);
+        // Very small filters have a high false positive rate, so every filter gets at least 64 bits.
+        let len = (key_hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut filter = BloomFilter {hashes, bits: vec![0; len]};
+        for &hash in key_hashes {
+            for bit in filter.bit_positions(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    // Returns `false` if the key is certainly not in the file.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let hash = hash_key(key);
+        self.bit_positions(hash).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Returns whether the filter can rule out any keys at all.
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.bits.is_empty()
+    }
+
+    // Derives the bits for a key from the two halves of its hash, which works as well as
+    // independent hash functions.
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> + use<> {
+        let len = self.bits.len() as u64 * 8;
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+// Hashes a key for a bloom filter. Filters are stored on disk, so unlike the standard
+// library's hashers this must never change: it is 64-bit FNV-1a with a final mix that spreads
+// the bits of short keys.
+pub(crate) fn hash_key(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_false_positive_rate() {
+        let keys: Vec<u64> = (0..10_000).map(|i| hash_key(format!("key{}", i).as_bytes())).collect();
+        let filter = BloomFilter::build(&keys, 10);
+        assert!((0..10_000).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+
+        // Ten bits per key should let through about 1% of missing keys.
+        let passed = (0..10_000).filter(|i| filter.may_contain(format!("missing{}", i).as_bytes())).count();
+        assert!(passed < 300, "{} of 10000 missing keys passed the filter", passed);
+
+        let disabled = BloomFilter::build(&keys, 0);
+        assert!(!disabled.is_enabled());
+        assert!(disabled.may_contain(b"missing"));
+    }
+}
@@ -0,0 +1,743 @@
+use crate::batch::BatchOp;
+use crate::util::{DirLock, read_exact_at, write_all_at};
+use crate::{Condition, KvsEngine, KvsError, Result, ScanOptions, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+// Name of the page file inside the data directory.
+const PAGE_FILE: &str = "btree.db";
+// Size of every page in the file. The first two pages hold the alternating meta pages.
+const PAGE_SIZE: u64 = 4096;
+// Identifies a page file written by this engine.
+const MAGIC: &[u8; 8] = b"RKVBTREE";
+// Length of an encoded meta page: magic, transaction id, root page, page count and a CRC32.
+const META_LEN: usize = 36;
+// Bytes in front of each node: a CRC32 of the encoded node and its length.
+const NODE_HEADER_LEN: usize = 8;
+// The largest encoded node that fits in a page.
+const NODE_CAPACITY: u64 = PAGE_SIZE - NODE_HEADER_LEN as u64;
+// The longest key accepted. Together with the inline value limit, it guarantees that a node
+// too large for its page can always be split into two halves that fit.
+const MAX_KEY_LEN: usize = 1024;
+// Values longer than this are stored in overflow pages instead of inside their leaf.
+const MAX_INLINE_VALUE_LEN: usize = 512;
+
+// A value as it is stored in a leaf.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Value {
+    Inline(Vec<u8>),
+    // A value stored in consecutive overflow pages starting at `page`, prefixed by a CRC32.
+    Overflow { page: u64, len: u64 },
+}
+
+// A node of the tree, stored in a single page.
+#[derive(Debug, Serialize, Deserialize)]
+enum Node {
+    // Entries sorted by key.
+    Leaf(Vec<(Vec<u8>, Value)>),
+    // `children[i]` holds the keys from `keys[i - 1]` up to, but excluding, `keys[i]`.
+    Branch { keys: Vec<Vec<u8>>, children: Vec<u64> },
+}
+
+// The page or pages a modified node was written to.
+enum Written {
+    One(u64),
+    // The node was split: the left page, the first key of the right page, and the right page.
+    Split(u64, Vec<u8>, u64),
+}
+
+// The contents of a meta page, which describes one committed version of the tree.
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    // Incremented by every commit. The meta page with the highest valid id is the current one.
+    txid: u64,
+    root: Option<u64>,
+    // The number of pages in use by this version, including free ones.
+    page_count: u64,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(META_LEN);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&self.txid.to_le_bytes());
+        // Page 0 always holds a meta page, so it doubles as the marker for an empty tree.
+        buf.extend_from_slice(&self.root.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&self.page_count.to_le_bytes());
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Meta> {
+        let field = |i: usize| u64::from_le_bytes(buf[8 + i * 8..16 + i * 8].try_into().unwrap());
+        let crc = u32::from_le_bytes(buf[32..36].try_into().unwrap());
+        if &buf[..8] != MAGIC || crc != crc32fast::hash(&buf[..32]) {
+            return None;
+        }
+
+        Some(Meta {
+            txid: field(0),
+            root: Some(field(1)).filter(|&root| root != 0),
+            page_count: field(2),
+        })
+    }
+}
+
+// The page file along with the bookkeeping needed to modify it.
+struct Tree {
+    file: File,
+    // The most recently committed version of the tree.
+    meta: Meta,
+    // The number of pages allocated, including ones written by the write in progress.
+    page_count: u64,
+    // Pages that no committed version refers to, which can be reused.
+    free: Vec<u64>,
+    // Pages replaced by the write in progress. The committed version still refers to them,
+    // so they only become free once the write has committed.
+    released: Vec<u64>,
+}
+
+impl Tree {
+    fn read_node(&self, page: u64) -> Result<Node> {
+        let offset = page * PAGE_SIZE;
+        let mut header = [0; NODE_HEADER_LEN];
+        read_exact_at(&self.file, &mut header, offset)?;
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+        if len > NODE_CAPACITY {
+            return Err(KvsError::Corruption {segment: 0, offset});
+        }
+
+        let mut buf = vec![0; len as usize];
+        read_exact_at(&self.file, &mut buf, offset + NODE_HEADER_LEN as u64)?;
+        if crc != crc32fast::hash(&buf) {
+            return Err(KvsError::Corruption {segment: 0, offset});
+        }
+        Ok(bincode::deserialize(&buf)?)
+    }
+
+    // Writes a node to a newly allocated page and returns the page.
+    fn write_node(&mut self, node: &Node) -> Result<u64> {
+        let encoded = bincode::serialize(node)?;
+        if encoded.len() as u64 > NODE_CAPACITY {
+            return Err(KvsError::Internal(format!("B-tree node of {} bytes does not fit in a page", encoded.len())));
+        }
+
+        let mut buf = Vec::with_capacity(NODE_HEADER_LEN + encoded.len());
+        buf.extend_from_slice(&crc32fast::hash(&encoded).to_le_bytes());
+        buf.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&encoded);
+
+        let page = self.alloc();
+        write_all_at(&self.file, &buf, page * PAGE_SIZE)?;
+        Ok(page)
+    }
+
+    // Writes a leaf, splitting it in two if it does not fit in a page.
+    fn write_leaf(&mut self, entries: Vec<(Vec<u8>, Value)>) -> Result<Written> {
+        let node = Node::Leaf(entries);
+        if bincode::serialized_size(&node)? <= NODE_CAPACITY {
+            return Ok(Written::One(self.write_node(&node)?));
+        }
+        let Node::Leaf(mut entries) = node else { unreachable!() };
+
+        let sizes = entries
+            .iter()
+            .map(bincode::serialized_size)
+            .collect::<bincode::Result<Vec<_>>>()?;
+        let right = entries.split_off(balanced_split(&sizes));
+        let separator = right[0].0.clone();
+
+        let left = self.write_node(&Node::Leaf(entries))?;
+        let right = self.write_node(&Node::Leaf(right))?;
+        Ok(Written::Split(left, separator, right))
+    }
+
+    // Writes a branch, splitting it in two if it does not fit in a page.
+    fn write_branch(&mut self, keys: Vec<Vec<u8>>, children: Vec<u64>) -> Result<Written> {
+        let node = Node::Branch {keys, children};
+        if bincode::serialized_size(&node)? <= NODE_CAPACITY {
+            return Ok(Written::One(self.write_node(&node)?));
+        }
+        let Node::Branch {mut keys, mut children} = node else { unreachable!() };
+
+        // The key at the split point moves up into the parent, so it belongs to neither half.
+        let sizes = keys
+            .iter()
+            .map(|key| bincode::serialized_size(key).map(|size| size + 8))
+            .collect::<bincode::Result<Vec<_>>>()?;
+        let split = balanced_split(&sizes);
+        let right_keys = keys.split_off(split + 1);
+        let separator = keys.pop().unwrap();
+        let right_children = children.split_off(split + 1);
+
+        let left = self.write_node(&Node::Branch {keys, children})?;
+        let right = self.write_node(&Node::Branch {keys: right_keys, children: right_children})?;
+        Ok(Written::Split(left, separator, right))
+    }
+
+    // Returns a page for a new node, reusing a free page when there is one.
+    fn alloc(&mut self) -> u64 {
+        self.free.pop().unwrap_or_else(|| {
+            self.page_count += 1;
+            self.page_count - 1
+        })
+    }
+
+    fn read_value(&self, value: &Value) -> Result<Vec<u8>> {
+        match *value {
+            Value::Inline(ref value) => Ok(value.clone()),
+            Value::Overflow {page, len} => {
+                let offset = page * PAGE_SIZE;
+                let mut buf = vec![0; 4 + len as usize];
+                read_exact_at(&self.file, &mut buf, offset)?;
+                if u32::from_le_bytes(buf[..4].try_into().unwrap()) != crc32fast::hash(&buf[4..]) {
+                    return Err(KvsError::Corruption {segment: 0, offset});
+                }
+                buf.drain(..4);
+                Ok(buf)
+            }
+        }
+    }
+
+    // Prepares a value for storage in a leaf, moving long values out into overflow pages.
+    fn write_value(&mut self, value: Vec<u8>) -> Result<Value> {
+        if value.len() <= MAX_INLINE_VALUE_LEN {
+            return Ok(Value::Inline(value));
+        }
+
+        let mut buf = Vec::with_capacity(4 + value.len());
+        buf.extend_from_slice(&crc32fast::hash(&value).to_le_bytes());
+        buf.extend_from_slice(&value);
+
+        // Values spanning several pages need a contiguous run, which is taken from the end of the file.
+        let pages = overflow_pages(value.len() as u64);
+        let page = if pages == 1 {
+            self.alloc()
+        } else {
+            self.page_count += pages;
+            self.page_count - pages
+        };
+        write_all_at(&self.file, &buf, page * PAGE_SIZE)?;
+        Ok(Value::Overflow {page, len: value.len() as u64})
+    }
+
+    fn release_value(&mut self, value: &Value) {
+        if let Value::Overflow {page, len} = *value {
+            self.released.extend(page..page + overflow_pages(len));
+        }
+    }
+
+    // Inserts or replaces an entry in the subtree rooted at `page`, copying every node on the
+    // path to it. Returns the new root of the subtree.
+    fn insert(&mut self, page: u64, key: Vec<u8>, value: Value) -> Result<Written> {
+        let node = self.read_node(page)?;
+        self.released.push(page);
+
+        match node {
+            Node::Leaf(mut entries) => {
+                match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => {
+                        let old = std::mem::replace(&mut entries[i].1, value);
+                        self.release_value(&old);
+                    }
+                    Err(i) => entries.insert(i, (key, value)),
+                }
+                self.write_leaf(entries)
+            }
+            Node::Branch {mut keys, mut children} => {
+                let i = keys.partition_point(|k| *k <= key);
+                match self.insert(children[i], key, value)? {
+                    Written::One(child) => children[i] = child,
+                    Written::Split(left, separator, right) => {
+                        children[i] = left;
+                        keys.insert(i, separator);
+                        children.insert(i + 1, right);
+                    }
+                }
+                self.write_branch(keys, children)
+            }
+        }
+    }
+
+    // Removes an entry from the subtree rooted at `page`. Returns the new root of the subtree,
+    // or `None` if it is now empty. Nodes are not merged, so the tree may contain underfull nodes.
+    fn remove(&mut self, page: u64, key: &[u8]) -> Result<Option<u64>> {
+        match self.read_node(page)? {
+            Node::Leaf(mut entries) => {
+                let i = entries
+                    .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                    .map_err(|_| KvsError::KeyNotFound)?;
+                let (_, old) = entries.remove(i);
+                self.release_value(&old);
+                self.released.push(page);
+
+                if entries.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(self.write_node(&Node::Leaf(entries))?))
+            }
+            Node::Branch {mut keys, mut children} => {
+                let i = keys.partition_point(|k| k.as_slice() <= key);
+                let child = self.remove(children[i], key)?;
+                self.released.push(page);
+
+                match child {
+                    Some(child) => children[i] = child,
+                    None => {
+                        children.remove(i);
+                        if !keys.is_empty() {
+                            keys.remove(i.saturating_sub(1));
+                        }
+                    }
+                }
+
+                if children.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(self.write_node(&Node::Branch {keys, children})?))
+            }
+        }
+    }
+
+    // Collects the entries of the subtree rooted at `page` that fall within `range`, in the
+    // order and up to the limit set by `options`.
+    fn scan(
+        &self,
+        page: u64,
+        range: &impl RangeBounds<Vec<u8>>,
+        options: &ScanOptions,
+        out: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let limit = options.limit.unwrap_or(usize::MAX);
+        match self.read_node(page)? {
+            Node::Leaf(mut entries) => {
+                if options.reverse {
+                    entries.reverse();
+                }
+                for (key, value) in entries {
+                    if out.len() >= limit {
+                        break;
+                    }
+                    if range.contains(&key) {
+                        let value = self.read_value(&value)?;
+                        out.push((key, value));
+                    }
+                }
+            }
+            Node::Branch {keys, children} => {
+                let mut order: Vec<usize> = (0..children.len()).collect();
+                if options.reverse {
+                    order.reverse();
+                }
+                for i in order {
+                    if out.len() >= limit {
+                        break;
+                    }
+                    // Skip children whose keys all fall before the start or after the end of the range.
+                    let before_start = match (keys.get(i), range.start_bound()) {
+                        (Some(upper), Bound::Included(start) | Bound::Excluded(start)) => upper <= start,
+                        _ => false,
+                    };
+                    let after_end = match (i.checked_sub(1).map(|j| &keys[j]), range.end_bound()) {
+                        (Some(lower), Bound::Included(end)) => lower > end,
+                        (Some(lower), Bound::Excluded(end)) => lower >= end,
+                        _ => false,
+                    };
+                    if !before_start && !after_end {
+                        self.scan(children[i], range, options, out)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds every page reachable from `page` to `pages`.
+    fn mark_reachable(&self, page: u64, pages: &mut HashSet<u64>) -> Result<()> {
+        pages.insert(page);
+        match self.read_node(page)? {
+            Node::Leaf(entries) => {
+                for (_, value) in entries {
+                    if let Value::Overflow {page, len} = value {
+                        pages.extend(page..page + overflow_pages(len));
+                    }
+                }
+            }
+            Node::Branch {children, ..} => {
+                for child in children {
+                    self.mark_reachable(child, pages)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Looks up the value of a key in the committed tree.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(mut page) = self.meta.root else {
+            return Ok(None);
+        };
+
+        loop {
+            match self.read_node(page)? {
+                Node::Leaf(entries) => {
+                    return match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                        Ok(i) => Ok(Some(self.read_value(&entries[i].1)?)),
+                        Err(_) => Ok(None),
+                    };
+                }
+                Node::Branch {keys, children} => page = children[keys.partition_point(|k| k.as_slice() <= key)],
+            }
+        }
+    }
+
+    // Sets a key in the tree with the given root. Returns the new root.
+    fn put(&mut self, root: Option<u64>, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        let value = self.write_value(value)?;
+        let written = match root {
+            Some(root) => self.insert(root, key, value)?,
+            None => self.write_leaf(vec![(key, value)])?,
+        };
+        match written {
+            Written::One(root) => Ok(root),
+            Written::Split(left, separator, right) => self.write_node(&Node::Branch {
+                keys: vec![separator],
+                children: vec![left, right],
+            }),
+        }
+    }
+
+    // Removes a key from the tree with the given root. Returns the new root, or `None` if the
+    // tree is now empty.
+    fn delete(&mut self, root: Option<u64>, key: &[u8]) -> Result<Option<u64>> {
+        let root = root.ok_or(KvsError::KeyNotFound)?;
+        let mut root = self.remove(root, key)?;
+
+        // Collapse branches left with a single child, so the tree doesn't grow taller than it needs to be.
+        while let Some(page) = root {
+            match self.read_node(page)? {
+                Node::Branch {children, ..} if children.len() == 1 => {
+                    self.released.push(page);
+                    root = Some(children[0]);
+                }
+                _ => break,
+            }
+        }
+        Ok(root)
+    }
+
+    // Runs a write that produces a new root, then commits it. If anything fails, the pages
+    // allocated by the write are given back and the committed tree is left as it was.
+    fn write(&mut self, f: impl FnOnce(&mut Tree) -> Result<Option<u64>>) -> Result<()> {
+        let free = self.free.clone();
+        let result = f(self).and_then(|root| self.commit(root));
+        if result.is_err() {
+            self.free = free;
+            self.released.clear();
+            self.page_count = self.meta.page_count;
+        }
+        result
+    }
+
+    // Makes a new version of the tree durable by pointing the older of the two meta pages at it.
+    fn commit(&mut self, root: Option<u64>) -> Result<()> {
+        // The new pages must be on disk before a meta page refers to them.
+        self.file.sync_data()?;
+
+        let meta = Meta {txid: self.meta.txid + 1, root, page_count: self.page_count};
+        write_all_at(&self.file, &meta.encode(), (meta.txid % 2) * PAGE_SIZE)?;
+        self.file.sync_data()?;
+
+        self.meta = meta;
+        self.free.append(&mut self.released);
+        Ok(())
+    }
+}
+
+/// A storage engine keeping its data in a copy-on-write B+tree inside a single page file.
+///
+/// Every write copies the path from the root to the modified leaf into fresh pages and then
+/// commits by updating one of two alternating meta pages, so the previous version of the tree
+/// stays intact until the new one is fully on disk. Each write is synced before it returns.
+///
+/// Keys are limited to 1 KiB. Values longer than 512 bytes are kept in overflow pages.
+#[derive(Clone)]
+pub struct BTreeEngine {
+    tree: Arc<RwLock<Tree>>,
+    // The lock on the directory, held for as long as any handle to the engine is open.
+    _dir_lock: Arc<DirLock>,
+}
+
+impl BTreeEngine {
+    /// Opens a `BTreeEngine` in the given directory, creating it if it doesn't exist.
+    ///
+    /// Errors with [`KvsError::AlreadyLocked`] if another process has the directory open already.
+    pub fn open(path: impl Into<PathBuf>) -> Result<BTreeEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let dir_lock = Arc::new(DirLock::acquire(&dir)?);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(PAGE_FILE))?;
+
+        let meta = if file.metadata()?.len() == 0 {
+            let meta = Meta {txid: 0, root: None, page_count: 2};
+            write_all_at(&file, &meta.encode(), 0)?;
+            file.sync_all()?;
+            meta
+        } else {
+            // Use the newest meta page that is intact. A torn write of the other one leaves
+            // the previous version of the tree in place.
+            let mut newest: Option<Meta> = None;
+            for slot in 0..2 {
+                let mut buf = [0; META_LEN];
+                if read_exact_at(&file, &mut buf, slot * PAGE_SIZE).is_err() {
+                    continue;
+                }
+                if let Some(meta) = Meta::decode(&buf)
+                    && newest.is_none_or(|newest| meta.txid > newest.txid)
+                {
+                    newest = Some(meta);
+                }
+            }
+            newest.ok_or(KvsError::Corruption {segment: 0, offset: 0})?
+        };
+
+        let mut tree = Tree {
+            file,
+            meta,
+            page_count: meta.page_count,
+            free: Vec::new(),
+            released: Vec::new(),
+        };
+
+        // Free pages are not recorded on disk. Any page the committed tree does not reach is free.
+        let mut reachable = HashSet::new();
+        if let Some(root) = meta.root {
+            tree.mark_reachable(root, &mut reachable)?;
+        }
+        tree.free = (2..meta.page_count).rev().filter(|page| !reachable.contains(page)).collect();
+
+        Ok(BTreeEngine {
+            tree: Arc::new(RwLock::new(tree)),
+            _dir_lock: dir_lock,
+        })
+    }
+}
+
+impl KvsEngine for BTreeEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+
+        let mut tree = self.tree.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        tree.write(|tree| tree.put(tree.meta.root, key, value).map(Some))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.tree.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        tree.lookup(key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut tree = self.tree.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        tree.write(|tree| tree.delete(tree.meta.root, key))
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree = self.tree.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let mut entries = Vec::new();
+        if let Some(root) = tree.meta.root {
+            tree.scan(root, &range, &options, &mut entries)?;
+        }
+        Ok(entries)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut tree = self.tree.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        // All operations build on the same uncommitted version, which is committed once at the end.
+        tree.write(|tree| {
+            let mut root = tree.meta.root;
+            for op in batch.into_ops() {
+                root = match op {
+                    BatchOp::Set {key, value} => {
+                        if key.len() > MAX_KEY_LEN {
+                            return Err(KvsError::KeyTooLarge(key.len()));
+                        }
+                        Some(tree.put(root, key, value)?)
+                    }
+                    BatchOp::Remove {key} => tree.delete(root, &key)?,
+                };
+            }
+            Ok(root)
+        })
+    }
+
+    fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvsError::KeyTooLarge(key.len()));
+        }
+
+        // The write lock is held from the check until the write is committed.
+        let mut tree = self.tree.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let current = tree.lookup(&key)?;
+        if !condition.matches(current.as_deref()) {
+            return Err(KvsError::ConditionFailed);
+        }
+        match new {
+            Some(value) => tree.write(|tree| tree.put(tree.meta.root, key, value).map(Some)),
+            None if current.is_some() => tree.write(|tree| tree.delete(tree.meta.root, &key)),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Every write is committed durably before it returns, so this only has to sync the file.
+        let tree = self.tree.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        tree.file.sync_data()?;
+        Ok(())
+    }
+}
+
+// Returns the number of pages an overflow value of the given length occupies, including its checksum.
+fn overflow_pages(len: u64) -> u64 {
+    (len + 4).div_ceil(PAGE_SIZE)
+}
+
+// Returns the index at which to split items of the given sizes into two halves whose larger
+// half is as small as possible. Both halves are non-empty.
+fn balanced_split(sizes: &[u64]) -> usize {
+    let total: u64 = sizes.iter().sum();
+    let mut left = 0;
+    let mut best = (u64::MAX, 1);
+    for (i, size) in sizes.iter().enumerate().take(sizes.len() - 1) {
+        left += size;
+        let larger = left.max(total - left);
+        if larger < best.0 {
+            best = (larger, i + 1);
+        }
+    }
+    best.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_many_keys_persist() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        {
+            let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+            for i in 0..2000 {
+                engine.set(format!("key{:05}", i), format!("value{}", i)).unwrap();
+            }
+            for i in (0..2000).step_by(3) {
+                engine.remove(format!("key{:05}", i)).unwrap();
+            }
+        }
+
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+        for i in 0..2000 {
+            let expected = if i % 3 == 0 { None } else { Some(format!("value{}", i)) };
+            assert_eq!(engine.get(format!("key{:05}", i)).unwrap(), expected);
+        }
+
+        let scanned = engine.scan("key00100".to_owned().."key00110".to_owned()).unwrap();
+        let keys: Vec<_> = scanned.into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["key00100", "key00101", "key00103", "key00104", "key00106", "key00107", "key00109"]);
+    }
+
+    #[test]
+    fn test_directory_lock() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+        engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+        match BTreeEngine::open(temp_dir.path()) {
+            Err(KvsError::AlreadyLocked {pid}) => assert_eq!(pid, Some(std::process::id())),
+            other => panic!("expected the directory to be locked, got {:?}", other.map(|_| ())),
+        }
+
+        // The lock is released once every handle is dropped.
+        let clone = engine.clone();
+        drop(engine);
+        assert!(matches!(BTreeEngine::open(temp_dir.path()), Err(KvsError::AlreadyLocked {..})));
+        drop(clone);
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+        assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+    }
+
+    #[test]
+    fn test_large_keys_and_values() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+
+        for i in 0..50 {
+            engine.set(format!("{:04}{}", i, "k".repeat(1000)), "v".repeat(i * 300)).unwrap();
+        }
+        for i in 0..50 {
+            assert_eq!(engine.get(format!("{:04}{}", i, "k".repeat(1000))).unwrap(), Some("v".repeat(i * 300)));
+        }
+
+        assert!(matches!(engine.set("k".repeat(MAX_KEY_LEN + 1), String::new()), Err(KvsError::KeyTooLarge(_))));
+    }
+
+    #[test]
+    fn test_pages_are_reused() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+
+        for i in 0..100 {
+            engine.set(format!("key{}", i), "x".repeat(1000)).unwrap();
+        }
+        let size = fs::metadata(temp_dir.path().join(PAGE_FILE)).unwrap().len();
+        for _ in 0..10 {
+            for i in 0..100 {
+                engine.set(format!("key{}", i), "y".repeat(1000)).unwrap();
+            }
+        }
+
+        assert!(fs::metadata(temp_dir.path().join(PAGE_FILE)).unwrap().len() <= size * 2);
+        assert_eq!(engine.get("key7".to_owned()).unwrap(), Some("y".repeat(1000)));
+    }
+
+    #[test]
+    fn test_torn_meta_page_falls_back() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        {
+            let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+            engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            engine.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        }
+
+        // The second commit went to the meta page in slot 0. Damage it as a torn write would.
+        let file = OpenOptions::new().write(true).open(temp_dir.path().join(PAGE_FILE)).unwrap();
+        write_all_at(&file, &[0xff; 16], 8).unwrap();
+        drop(file);
+
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+        assert_eq!(engine.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(engine.get("key2".to_owned()).unwrap(), None);
+
+        // The recovered tree accepts new writes.
+        engine.set("key3".to_owned(), "value3".to_owned()).unwrap();
+        drop(engine);
+        let engine = BTreeEngine::open(temp_dir.path()).unwrap();
+        assert_eq!(engine.get("key3".to_owned()).unwrap(), Some("value3".to_owned()));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The bits of a record's flags byte that name the codec its payload is compressed with.
+// A value of zero means the payload is stored as is.
+pub(crate) const CODEC_MASK: u8 = 0b11;
+const LZ4_FLAG: u8 = 1;
+const ZSTD_FLAG: u8 = 2;
+// The zstd level records are compressed at, which is the library's default trade-off.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The codec log records are compressed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Records are stored as they are.
+    #[default]
+    None,
+    /// LZ4, which is very fast but shrinks records less.
+    Lz4,
+    /// Zstandard, which shrinks records more at some cost in speed.
+    Zstd,
+}
+
+/// How much compression has shrunk the records written since a store was opened. Records
+/// that compactions and checkpoints copy are not counted again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    /// The number of records written.
+    pub records: u64,
+    /// The number of those that were stored compressed.
+    pub compressed: u64,
+    /// The size of the record payloads before compression, in bytes.
+    pub raw_bytes: u64,
+    /// The size of the record payloads as stored, in bytes.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Returns how many times smaller the payloads are as stored than they were raw, or 1 if
+    /// nothing was written.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+// Compresses record payloads with the configured codec and counts the results.
+#[derive(Debug, Default)]
+pub(crate) struct Compressor {
+    codec: Compression,
+    // Payloads smaller than this are stored as they are, since they rarely shrink.
+    min_size: usize,
+    records: AtomicU64,
+    compressed: AtomicU64,
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub(crate) fn new(codec: Compression, min_size: usize) -> Compressor {
+        Compressor {codec, min_size, ..Compressor::default()}
+    }
+
+    // Compresses a payload if it is at least the minimum size and the codec shrinks it.
+    // Returns the flags naming the codec that was used, if any, and the payload to store.
+    pub(crate) fn compress(&self, payload: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        let raw_len = payload.len();
+        let compressed = match self.codec {
+            _ if raw_len < self.min_size => None,
+            Compression::None => None,
+            Compression::Lz4 => Some((LZ4_FLAG, lz4_flex::compress_prepend_size(&payload))),
+            Compression::Zstd => Some((ZSTD_FLAG, zstd::bulk::compress(&payload, ZSTD_LEVEL)?)),
+        };
+        let (flags, stored) = match compressed {
+            Some((flags, compressed)) if compressed.len() < raw_len => (flags, compressed),
+            _ => (0, payload),
+        };
+
+        self.records.fetch_add(1, Ordering::Relaxed);
+        if flags != 0 {
+            self.compressed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.raw_bytes.fetch_add(raw_len as u64, Ordering::Relaxed);
+        self.stored_bytes.fetch_add(stored.len() as u64, Ordering::Relaxed);
+        Ok((flags, stored))
+    }
+
+    pub(crate) fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            records: self.records.load(Ordering::Relaxed),
+            compressed: self.compressed.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Restores a payload stored with the codec named by the given flags. Returns `None` if the
+// codec is unknown or the payload does not decompress.
+pub(crate) fn decompress(flags: u8, payload: &[u8]) -> Option<Cow<'_, [u8]>> {
+    match flags & CODEC_MASK {
+        0 => Some(Cow::Borrowed(payload)),
+        LZ4_FLAG => lz4_flex::decompress_size_prepended(payload).ok().map(Cow::Owned),
+        ZSTD_FLAG => zstd::decode_all(payload).ok().map(Cow::Owned),
+        _ => None,
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{KvStoreOptions, KvsError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// The bit of a record's flags byte that marks its payload as encrypted.
+pub(crate) const ENCRYPTED_FLAG: u8 = 0b100;
+// The length of the random nonce in front of every encrypted payload.
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key for encrypting data at rest with ChaCha20-Poly1305.
+///
+/// Every file written with the key records its ID, so the key can be rotated: after a new key
+/// is introduced, files written with an older key stay readable as long as that key is still
+/// supplied as a retired key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey {
+    id: u32,
+    bytes: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Creates a key from its ID and key material.
+    pub fn new(id: u32, bytes: [u8; 32]) -> EncryptionKey {
+        EncryptionKey {id, bytes}
+    }
+
+    /// Returns the ID stored with the data encrypted under the key.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// The key material is left out, so keys do not end up in logs.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+// Reads the keys in a key file. Each line holds the ID of a key and its 32 bytes as 64 hex
+// digits, separated by whitespace. Empty lines and lines starting with `#` are skipped.
+fn read_key_file(path: &Path) -> Result<Vec<EncryptionKey>> {
+    let invalid = |line: usize| KvsError::InvalidKey(format!("{} line {}", path.display(), line + 1));
+
+    let mut keys = Vec::new();
+    for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, hex) = line.split_once(char::is_whitespace).ok_or_else(|| invalid(n))?;
+        let id = id.parse().map_err(|_| invalid(n))?;
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid(n));
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid(n))?;
+        }
+        keys.push(EncryptionKey::new(id, bytes));
+    }
+    Ok(keys)
+}
+
+// Encrypts and decrypts record payloads with one key.
+#[derive(Clone)]
+pub(crate) struct RecordCipher {
+    key_id: u32,
+    cipher: ChaCha20Poly1305,
+}
+
+impl RecordCipher {
+    fn new(key: &EncryptionKey) -> RecordCipher {
+        RecordCipher {key_id: key.id, cipher: ChaCha20Poly1305::new(Key::from_slice(&key.bytes))}
+    }
+
+    pub(crate) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    // Encrypts a payload under a fresh random nonce, which is put in front of the result. The
+    // record's flags are authenticated along with it, so they cannot be altered unnoticed.
+    pub(crate) fn encrypt(&self, flags: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload {msg: payload, aad: &[flags]})
+            .map_err(|_| KvsError::Internal("Encrypting a record failed".into()))?;
+
+        let mut encrypted = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    // Decrypts a payload made by `encrypt`. Returns `None` if it was encrypted under another
+    // key, or if it or its flags have been tampered with.
+    pub(crate) fn decrypt(&self, flags: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        self.cipher.decrypt(Nonce::from_slice(nonce), Payload {msg: ciphertext, aad: &[flags]}).ok()
+    }
+}
+
+// Every key a store can read data with, and the one it encrypts new data with, if any.
+#[derive(Default)]
+pub(crate) struct Keyring {
+    current: Option<RecordCipher>,
+    ciphers: HashMap<u32, RecordCipher>,
+}
+
+impl Keyring {
+    // Builds a keyring from the current key and any retired keys that may still be needed to
+    // read older files.
+    fn new(current: Option<&EncryptionKey>, retired: &[EncryptionKey]) -> Keyring {
+        let current = current.map(RecordCipher::new);
+        let ciphers = retired
+            .iter()
+            .map(RecordCipher::new)
+            .chain(current.clone())
+            .map(|cipher| (cipher.key_id, cipher))
+            .collect();
+        Keyring {current, ciphers}
+    }
+
+    // Builds the keyring described by the options, reading their key file if they name one.
+    pub(crate) fn from_options(options: &KvStoreOptions) -> Result<Keyring> {
+        let mut current = options.encryption_key.clone();
+        let mut retired = options.retired_keys.clone();
+        if let Some(path) = &options.key_file {
+            let mut keys = read_key_file(path)?;
+            if current.is_none() {
+                current = keys.pop();
+            }
+            retired.extend(keys);
+        }
+        Ok(Keyring::new(current.as_ref(), &retired))
+    }
+
+    // Returns the cipher new data is encrypted with, or `None` if it is stored in the clear.
+    pub(crate) fn current(&self) -> Option<&RecordCipher> {
+        self.current.as_ref()
+    }
+
+    // Returns the ciphers for every key in the keyring, for files whose header is lost.
+    pub(crate) fn ciphers(&self) -> impl Iterator<Item = &RecordCipher> {
+        self.ciphers.values()
+    }
+
+    // Returns the cipher for the key with the given ID, which a file names in its header.
+    pub(crate) fn cipher(&self, key_id: u32) -> Result<&RecordCipher> {
+        self.ciphers.get(&key_id).ok_or(KvsError::MissingKey(key_id))
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{KvsError, Result, ScanOptions, Snapshot, StoreStats, Transaction, WriteBatch};
+use std::fs;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
+
+// Name of the file recording which engine a data directory belongs to.
+const ENGINE_FILE: &str = "engine";
+
+/// What the current value of a key must be for a conditional write to be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// The key must not exist.
+    Absent,
+    /// The key must exist, with any value.
+    Present,
+    /// The key must exist and hold exactly this value.
+    Equals(Vec<u8>),
+}
+
+impl Condition {
+    /// Returns whether a key with the given current value satisfies the condition.
+    pub fn matches(&self, current: Option<&[u8]>) -> bool {
+        match (self, current) {
+            (Condition::Absent, current) => current.is_none(),
+            (Condition::Present, current) => current.is_some(),
+            (Condition::Equals(expected), Some(current)) => expected.as_slice() == current,
+            (Condition::Equals(_), None) => false,
+        }
+    }
+}
+
+/// The interface shared by all storage engines.
+///
+/// Engines are cheap to clone, and clones share the same underlying store, so one
+/// engine can serve requests from many threads.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of a key, overwriting any previous value.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of a key, or `None` if the key does not exist.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a key.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Returns the key-value pairs whose key falls within `range`, in byte order, limited and
+    /// ordered as set by `options`.
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns the key-value pairs whose key starts with `prefix`, limited and ordered as set by `options`.
+    fn scan_prefix(&self, prefix: &[u8], options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes(prefix_range(prefix), options)
+    }
+
+    /// Applies every write in `batch` atomically: readers and recovery after a crash see
+    /// either all of them or none.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`], leaving the store unchanged, if the batch
+    /// removes a key that does not exist.
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Writes `new` to a key, or removes the key if `new` is `None`, but only if the key's
+    /// current value satisfies `condition`. The check and the write happen atomically.
+    ///
+    /// Errors with [`KvsError::ConditionFailed`], leaving the key unchanged, if it does not.
+    fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()>;
+
+    /// Forces every write made so far to stable storage.
+    fn flush(&self) -> Result<()>;
+
+    /// Atomically replaces the value of a key with `new` if it currently is `expected`.
+    ///
+    /// `None` stands for a missing key on either side, so this can also create or remove the key.
+    /// Errors with [`KvsError::ConditionFailed`] if the current value is not `expected`.
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.write_if(key, expected.map_or(Condition::Absent, Condition::Equals), new)
+    }
+
+    /// Sets a key only if it does not exist yet.
+    ///
+    /// Errors with [`KvsError::ConditionFailed`] if it does.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_if(key, Condition::Absent, Some(value))
+    }
+
+    /// Sets a key only if it already exists.
+    ///
+    /// Errors with [`KvsError::ConditionFailed`] if it does not.
+    fn set_if_present(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_if(key, Condition::Present, Some(value))
+    }
+
+    /// Starts an interactive transaction.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports transactions.
+    fn begin(&self) -> Result<Transaction> {
+        Err(KvsError::Unsupported("transactions".into()))
+    }
+
+    /// Takes a read-only view of the store as of now, which later writes do not change.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports snapshots.
+    fn snapshot(&self) -> Result<Snapshot> {
+        Err(KvsError::Unsupported("snapshots".into()))
+    }
+
+    /// Sets the value of a key that expires once `ttl` has passed.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports key expiry.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _ = (key, value, ttl);
+        Err(KvsError::Unsupported("key expiry".into()))
+    }
+
+    /// Returns how long a key has left to live, or `None` if it does not expire.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports key expiry.
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let _ = key;
+        Err(KvsError::Unsupported("key expiry".into()))
+    }
+
+    /// Writes a consistent copy of the store into the given directory while it stays in use.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports backups.
+    fn backup_to(&self, dir: &Path) -> Result<()> {
+        let _ = dir;
+        Err(KvsError::Unsupported("backups".into()))
+    }
+
+    /// Replaces the contents of the store with a backup written by [`KvsEngine::backup_to`].
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine supports backups.
+    fn restore_from(&self, dir: &Path) -> Result<()> {
+        let _ = dir;
+        Err(KvsError::Unsupported("backups".into()))
+    }
+
+    /// Returns the number of keys, how much space they take up, and how the engine has been
+    /// used since it was opened.
+    ///
+    /// Errors with [`KvsError::Unsupported`] unless the engine keeps statistics.
+    fn stats(&self) -> Result<StoreStats> {
+        Err(KvsError::Unsupported("statistics".into()))
+    }
+
+    /// Sets the value of a string key, overwriting any previous value.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Gets the value of a string key, or `None` if the key does not exist.
+    ///
+    /// Errors with [`KvsError::InvalidUtf8`] if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Removes a string key.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Returns every pair whose key falls within a range of strings, in ascending key order.
+    ///
+    /// Errors with [`KvsError::InvalidUtf8`] if a key or value in range is not valid UTF-8.
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let start = range.start_bound().map(|key| key.clone().into_bytes());
+        let end = range.end_bound().map(|key| key.clone().into_bytes());
+
+        self.scan_bytes((start, end), ScanOptions::default())?
+            .into_iter()
+            .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+            .collect()
+    }
+}
+
+/// Returns the range of keys that start with `prefix`.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // The first key past the prefix is found by incrementing its last byte that can be
+    // incremented and dropping everything after it. A prefix of only 0xff bytes has no end.
+    let end = match prefix.iter().rposition(|&byte| byte != u8::MAX) {
+        Some(i) => {
+            let mut end = prefix[..=i].to_vec();
+            end[i] += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+// Returns whether a range is empty because its start lies past its end. `BTreeMap::range`
+// panics on some of these, so they are answered without consulting the map.
+pub(crate) fn is_empty_range(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Records `engine` as the engine that owns the data directory, or checks that it already is.
+///
+/// The first engine to claim a directory writes its name into it. Errors with
+/// [`KvsError::EngineMismatch`] if the directory was claimed by a different engine.
+pub fn claim_data_dir(dir: &Path, engine: &str) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(ENGINE_FILE);
+
+    match fs::read_to_string(&path) {
+        Ok(existing) if existing.trim() == engine => Ok(()),
+        Ok(existing) => Err(KvsError::EngineMismatch {
+            requested: engine.to_owned(),
+            existing: existing.trim().to_owned(),
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::write(&path, engine)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BTreeEngine, KvStore, KvStoreOptions, LsmEngine, MemoryEngine};
+    use tempfile::TempDir;
+
+    // Runs the same operations against an engine and checks the results every engine must agree on.
+    fn check_engine(engine: impl KvsEngine) {
+        for i in 0..100 {
+            engine.set(format!("key{:03}", i), format!("value{}", i)).unwrap();
+        }
+        engine.set("key050".to_owned(), "overwritten".to_owned()).unwrap();
+        engine.remove("key051".to_owned()).unwrap();
+        assert!(matches!(engine.remove("key051".to_owned()), Err(KvsError::KeyNotFound)));
+        engine.flush().unwrap();
+
+        assert_eq!(engine.get("key050".to_owned()).unwrap(), Some("overwritten".to_owned()));
+        assert_eq!(engine.get("key051".to_owned()).unwrap(), None);
+        assert_eq!(engine.get("missing".to_owned()).unwrap(), None);
+
+        let scanned = engine.scan("key049".to_owned()..="key052".to_owned()).unwrap();
+        assert_eq!(
+            scanned,
+            vec![
+                ("key049".to_owned(), "value49".to_owned()),
+                ("key050".to_owned(), "overwritten".to_owned()),
+                ("key052".to_owned(), "value52".to_owned()),
+            ]
+        );
+        assert_eq!(engine.scan(..).unwrap().len(), 99);
+        assert_eq!(engine.scan("key2".to_owned().."key1".to_owned()).unwrap(), vec![]);
+
+        let options = ScanOptions {limit: Some(2), reverse: true};
+        let scanned = engine.scan_prefix(b"key04", options).unwrap();
+        assert_eq!(
+            scanned,
+            vec![(b"key049".to_vec(), b"value49".to_vec()), (b"key048".to_vec(), b"value48".to_vec())]
+        );
+        let options = ScanOptions {limit: Some(1), reverse: false};
+        assert_eq!(engine.scan_bytes(b"key0505".to_vec().., options).unwrap()[0].0, b"key052");
+
+        // A batch that fails part way leaves nothing behind.
+        let mut batch = WriteBatch::new();
+        batch.set("key001", "batched").remove("key002").remove("key051");
+        assert!(matches!(engine.write(batch), Err(KvsError::KeyNotFound)));
+        assert_eq!(engine.get("key001".to_owned()).unwrap(), Some("value1".to_owned()));
+
+        let mut batch = WriteBatch::new();
+        batch.set("key001", "batched").remove("key002").set("key002", "again").set("new", "key");
+        engine.write(batch).unwrap();
+        assert_eq!(engine.get("key001".to_owned()).unwrap(), Some("batched".to_owned()));
+        assert_eq!(engine.get("key002".to_owned()).unwrap(), Some("again".to_owned()));
+        assert_eq!(engine.get("new".to_owned()).unwrap(), Some("key".to_owned()));
+        engine.remove("new".to_owned()).unwrap();
+
+        // Conditional writes only go ahead if the current value matches.
+        engine.compare_and_swap(b"key003".to_vec(), Some(b"value3".to_vec()), Some(b"swapped".to_vec())).unwrap();
+        assert!(matches!(
+            engine.compare_and_swap(b"key003".to_vec(), Some(b"value3".to_vec()), None),
+            Err(KvsError::ConditionFailed)
+        ));
+        engine.compare_and_swap(b"key003".to_vec(), Some(b"swapped".to_vec()), None).unwrap();
+        assert_eq!(engine.get("key003".to_owned()).unwrap(), None);
+        engine.set_if_absent(b"key003".to_vec(), b"created".to_vec()).unwrap();
+        assert!(matches!(engine.set_if_absent(b"key003".to_vec(), b"again".to_vec()), Err(KvsError::ConditionFailed)));
+        engine.set_if_present(b"key003".to_vec(), b"value3".to_vec()).unwrap();
+        assert!(matches!(engine.set_if_present(b"lock".to_vec(), b"held".to_vec()), Err(KvsError::ConditionFailed)));
+        engine.compare_and_swap(b"lock".to_vec(), None, None).unwrap();
+        assert_eq!(engine.get("key003".to_owned()).unwrap(), Some("value3".to_owned()));
+        assert_eq!(engine.get("lock".to_owned()).unwrap(), None);
+
+        // Keys and values do not have to be UTF-8.
+        engine.set_bytes(vec![0xff, 0], vec![0, 0x80, 0xff]).unwrap();
+        assert_eq!(engine.get_bytes(&[0xff, 0]).unwrap(), Some(vec![0, 0x80, 0xff]));
+        assert_eq!(engine.scan_prefix(&[0xff], ScanOptions::default()).unwrap(), vec![(vec![0xff, 0], vec![0, 0x80, 0xff])]);
+        engine.set_bytes(b"key000".to_vec(), vec![0xc3]).unwrap();
+        assert!(matches!(engine.get("key000".to_owned()), Err(KvsError::InvalidUtf8(_))));
+        engine.remove_bytes(&[0xff, 0]).unwrap();
+        assert_eq!(engine.get_bytes(&[0xff, 0]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_engines_behave_alike() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        check_engine(MemoryEngine::new());
+        check_engine(KvStore::open(temp_dir.path().join("kvs")).unwrap());
+        check_engine(BTreeEngine::open(temp_dir.path().join("btree")).unwrap());
+        // A small memtable makes the engine flush and compact while the checks run.
+        let options = KvStoreOptions::new().memtable_size(1024);
+        check_engine(LsmEngine::open_with_options(temp_dir.path().join("lsm"), options).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "before".to_owned()).unwrap();
+
+        let snapshot = KvsEngine::snapshot(&store).unwrap();
+        store.set("key1".to_owned(), "after".to_owned()).unwrap();
+        store.set("key0".to_owned(), "new".to_owned()).unwrap();
+        let scanned = snapshot.scan_bytes(.., ScanOptions::default()).unwrap();
+        assert_eq!(scanned, vec![(b"key1".to_vec(), b"before".to_vec())]);
+
+        assert!(matches!(MemoryEngine::new().snapshot(), Err(KvsError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_claim_data_dir() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        claim_data_dir(temp_dir.path(), "kvs").unwrap();
+        claim_data_dir(temp_dir.path(), "kvs").unwrap();
+
+        match claim_data_dir(temp_dir.path(), "btree") {
+            Err(KvsError::EngineMismatch {requested, existing}) => {
+                assert_eq!(requested, "btree");
+                assert_eq!(existing, "kvs");
+            }
+            other => panic!("expected an engine mismatch, got {:?}", other),
+        }
+    }
+}
@@ -14,6 +14,69 @@ pub enum KvsError {
     #[error("Key not found")]
     KeyNotFound,
 
+    /// A conditional write was not applied because the key's current value did not match.
+    #[error("Condition failed")]
+    ConditionFailed,
+
+    /// A transaction was not committed because a key it read was written by someone else
+    /// after the transaction started.
+    #[error("Transaction conflicts with a concurrent write")]
+    Conflict,
+
+    /// A key or value was read through the `String` API but is not valid UTF-8.
+    #[error("Invalid UTF-8 data {0}")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+
+    /// Data on disk failed its checksum. Engines that keep their data in a single
+    /// file report segment 0.
+    #[error("Corrupted record in log segment {segment} at offset {offset}")]
+    Corruption { segment: u64, offset: u64 },
+
+    #[error("Key too large: {0} bytes")]
+    KeyTooLarge(usize),
+
+    #[error("Data directory belongs to the {existing} engine, not {requested}")]
+    EngineMismatch { requested: String, existing: String },
+
+    /// The engine does not implement an optional operation, such as key expiry.
+    #[error("Operation not supported by this engine: {0}")]
+    Unsupported(String),
+
+    /// Data on disk was encrypted under a key that was not supplied when the store was opened.
+    #[error("Encryption key {0} is not available")]
+    MissingKey(u32),
+
+    /// A key file could not be parsed.
+    #[error("Invalid encryption key in {0}")]
+    InvalidKey(String),
+
+    /// The data directory is already open for writing, by the process with the given PID if it
+    /// is known. This process counts too, if it has another handle to the directory open.
+    #[error("Data directory is locked by process {}", .pid.map_or_else(|| "unknown".to_owned(), |pid| pid.to_string()))]
+    AlreadyLocked { pid: Option<u32> },
+
+    /// A write was made through a store that was opened read-only.
+    #[error("Store is opened read-only")]
+    ReadOnly,
+
+    /// Data on disk is in an older on-disk format, with the given version. Upgrade it with
+    /// [`KvStore::upgrade`](crate::KvStore::upgrade) before opening it.
+    #[error("Data is in the outdated on-disk format version {0} and has to be upgraded")]
+    UpgradeRequired(u32),
+
+    /// Data on disk is in no on-disk format this version knows, such as one written by a newer version.
+    #[error("Unknown on-disk format: {0}")]
+    UnknownFormat(String),
+
+    /// A backup is incomplete or failed its checksums, so it was not restored.
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    /// The store cannot be recovered to the requested point, because the log leading up to it
+    /// was compacted or checkpointed away.
+    #[error("Cannot recover to the requested point: {0}")]
+    Unrecoverable(String),
+
     #[error("Internal error {0}")]
     Internal(String),
 }
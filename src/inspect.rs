@@ -0,0 +1,513 @@
+//! Offline inspection and repair of the data files of a [`KvStore`](crate::KvStore).
+//!
+//! Unlike opening the store, reading a file here does not stop at the first damaged record,
+//! so everything that is still intact can be looked at, or saved into a repaired copy.
+
+use crate::crypto::{Keyring, RecordCipher};
+use crate::kv::{
+    CHECKPOINT_EXTENSION, CheckpointHeader, Command, CreationOptions, FileHeader, HEADER_FLAG, HEADER_LEN, PREFIX_LEN,
+    SEGMENT_EXTENSION, Stamp, checkpoint_path, decode_record, frame_record, read_checkpoint_header, read_file_header,
+    read_frame_at, read_stamp, segment_path, sorted_files,
+};
+use crate::util::{now_millis, read_exact_at};
+use crate::{KvStoreOptions, KvsError, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The kinds of data files a `KvStore` keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// A segment of the log.
+    Segment,
+    /// A checkpoint holding every key as of some point in the log.
+    Checkpoint,
+}
+
+/// What a record does to the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Sets a key, which expires at the given time in milliseconds since the UNIX epoch, if any.
+    Set { key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64> },
+    /// Removes a key.
+    Remove { key: Vec<u8> },
+    /// Applies several operations atomically.
+    Batch { ops: Vec<Op> },
+}
+
+impl From<Command> for Op {
+    fn from(cmd: Command) -> Op {
+        match cmd {
+            Command::Set {key, value} => Op::Set {key, value, expires_at: None},
+            Command::SetWithExpiry {key, value, expires_at} => Op::Set {key, value, expires_at: Some(expires_at)},
+            Command::Remove {key} => Op::Remove {key},
+            Command::Batch {commands} => Op::Batch {ops: commands.into_iter().map(Op::from).collect()},
+        }
+    }
+}
+
+/// An intact record in a data file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The offset of the record in its file.
+    pub offset: u64,
+    /// The length of the record in bytes, including its header.
+    pub len: u64,
+    /// The sequence number of the write, unless the record predates them.
+    pub seq: Option<u64>,
+    /// When the write was made, in milliseconds since the UNIX epoch, unless the record predates it.
+    pub timestamp: Option<u64>,
+    pub op: Op,
+}
+
+/// A record that could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damage {
+    /// The offset of the record in its file.
+    pub offset: u64,
+    /// The length of the record in bytes, as far as its header can be trusted.
+    pub len: u64,
+    /// What is wrong with the record.
+    pub reason: String,
+}
+
+/// Everything found in one data file.
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub generation: u64,
+    /// The length of the file in bytes.
+    pub len: u64,
+    /// The offset the records start at, after the headers of the file.
+    pub data_start: u64,
+    /// The intact records, in log order.
+    pub records: Vec<Record>,
+    /// The records that could not be read, in log order.
+    pub damage: Vec<Damage>,
+}
+
+impl FileReport {
+    /// Returns whether every record in the file could be read.
+    pub fn is_intact(&self) -> bool {
+        self.damage.is_empty()
+    }
+}
+
+/// How [`repair_file`] deals with damaged records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairMode {
+    /// Keep the records before the first damaged one and drop everything after it, as if the
+    /// store had crashed right before the damage.
+    Truncate,
+    /// Drop only the damaged records, and keep every intact record after them.
+    Skip,
+}
+
+/// How the data files of a store use their space, as found by replaying them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogStats {
+    /// The number of segments and checkpoints.
+    pub files: u64,
+    /// The number of intact records.
+    pub records: u64,
+    /// The number of records that could not be read.
+    pub damaged_records: u64,
+    /// The number of keys that are set and have not expired.
+    pub keys: u64,
+    /// The total size of the data files in bytes.
+    pub total_bytes: u64,
+    /// The bytes of records that hold the current value of a key.
+    pub live_bytes: u64,
+    /// The bytes of records that were overwritten, removed or have expired, which a compaction
+    /// would reclaim.
+    pub dead_bytes: u64,
+}
+
+/// Returns the paths of the segments and checkpoints in a data directory, in log order.
+pub fn data_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let segments = sorted_files(dir, SEGMENT_EXTENSION)?.into_iter().map(|generation| (generation, segment_path(dir, generation)));
+    let checkpoints = sorted_files(dir, CHECKPOINT_EXTENSION)?
+        .into_iter()
+        .map(|generation| (generation, checkpoint_path(dir, generation)));
+    let mut files: Vec<_> = segments.chain(checkpoints).collect();
+    files.sort_unstable_by_key(|&(generation, _)| generation);
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Reads every record of a segment or checkpoint, going on past damaged ones. Encrypted files
+/// are read with the keys in `options`.
+///
+/// Errors if the headers of the file cannot be read, if it is in another on-disk format, or
+/// if it is encrypted with a key that was not supplied.
+pub fn inspect_file(path: &Path, options: &KvStoreOptions) -> Result<FileReport> {
+    let (kind, generation) = identify(path)?;
+    Ok(inspect(path, kind, generation, &Keyring::from_options(options)?)?.0)
+}
+
+/// Writes a copy of a segment or checkpoint to `output` that leaves out its damaged records,
+/// and returns what the copy holds. The original file is not changed, and `output` must not
+/// exist yet.
+///
+/// The copy can replace the original once the store is closed. The hint file of a repaired
+/// segment should be deleted along with it, so it is rebuilt from the segment.
+pub fn repair_file(path: &Path, output: &Path, mode: RepairMode, options: &KvStoreOptions) -> Result<FileReport> {
+    let (kind, generation) = identify(path)?;
+    let keyring = Keyring::from_options(options)?;
+    let (report, found) = inspect(path, kind, generation, &keyring)?;
+    // Damaged headers are replaced, so only damage to the records cuts a file short.
+    let first_damage = report
+        .damage
+        .iter()
+        .map(|damage| damage.offset)
+        .find(|&offset| offset >= report.data_start)
+        .unwrap_or(u64::MAX);
+    let kept: Vec<_> = report
+        .records
+        .iter()
+        .filter(|record| mode == RepairMode::Skip || record.offset < first_damage)
+        .collect();
+
+    let file = File::open(path)?;
+    let mut headers = vec![0; report.data_start as usize];
+    if !found.intact {
+        // What state the file started from was only named in its header, so recovering the
+        // store to a point before the file's last write is ruled out.
+        let base = kept.iter().rev().find_map(|record| record.seq.zip(record.timestamp));
+        let mut header = FileHeader::new(CreationOptions::new(options), found.key_id);
+        header.base = base.map(|(seq, timestamp)| Stamp {seq, timestamp});
+        headers = header.encode()?;
+        if kind == FileKind::Checkpoint {
+            let keys = kept.len() as u64;
+            let header = CheckpointHeader {generation, keys, created_at: now_millis(), key_id: found.key_id};
+            headers.extend(frame_record(&bincode::serialize(&header)?, 0)?);
+        }
+    } else if kind == FileKind::Checkpoint {
+        read_exact_at(&file, &mut headers, 0)?;
+        // A checkpoint names how many keys it holds, which is only complete with the right count.
+        let (_, header_end) = read_file_header(&file, path)?.ok_or(KvsError::Corruption {segment: generation, offset: 0})?;
+        let (_, mut header, _) = read_checkpoint_header(&file, path, generation)?;
+        headers.truncate(header_end as usize);
+        header.keys = kept.len() as u64;
+        headers.extend(frame_record(&bincode::serialize(&header)?, 0)?);
+    } else {
+        read_exact_at(&file, &mut headers, 0)?;
+    }
+
+    let mut repaired = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(output)?);
+    repaired.write_all(&headers)?;
+    for record in kept {
+        let mut buf = vec![0; record.len as usize];
+        read_exact_at(&file, &mut buf, record.offset)?;
+        repaired.write_all(&buf)?;
+    }
+    let repaired = repaired.into_inner().map_err(|e| e.into_error())?;
+    repaired.sync_all()?;
+    // A sealed segment stays sealed.
+    fs::set_permissions(output, file.metadata()?.permissions())?;
+
+    Ok(inspect(output, kind, generation, &keyring)?.0)
+}
+
+/// Replays every data file in a directory to find how much of their space holds live data.
+/// Damaged records are counted, but otherwise skipped.
+pub fn log_stats(dir: &Path, options: &KvStoreOptions) -> Result<LogStats> {
+    let keyring = Keyring::from_options(options)?;
+    let mut stats = LogStats::default();
+    // The share of its record each key accounts for, and when the key expires.
+    let mut live: HashMap<Vec<u8>, (u64, Option<u64>)> = HashMap::new();
+    let mut record_bytes = 0;
+
+    for path in data_files(dir)? {
+        let (kind, generation) = identify(&path)?;
+        let (report, _) = inspect(&path, kind, generation, &keyring)?;
+        stats.files += 1;
+        stats.total_bytes += report.len;
+        stats.records += report.records.len() as u64;
+        stats.damaged_records += report.damage.len() as u64;
+
+        for record in report.records {
+            record_bytes += record.len;
+            // Each key of a batch accounts for an equal share of it, like in the index.
+            let ops = match record.op {
+                Op::Batch {ops} => ops,
+                op => vec![op],
+            };
+            let share = record.len / ops.len().max(1) as u64;
+            for op in ops {
+                match op {
+                    Op::Set {key, expires_at, ..} => live.insert(key, (share, expires_at)),
+                    Op::Remove {key} => live.remove(&key),
+                    Op::Batch {..} => None,
+                };
+            }
+        }
+    }
+
+    let now = now_millis();
+    for (share, expires_at) in live.into_values() {
+        if expires_at.is_none_or(|expires_at| expires_at > now) {
+            stats.keys += 1;
+            stats.live_bytes += share;
+        }
+    }
+    stats.dead_bytes = record_bytes - stats.live_bytes;
+    Ok(stats)
+}
+
+// Tells the kind and generation of a data file from its name.
+fn identify(path: &Path) -> Result<(FileKind, u64)> {
+    let kind = match path.extension().and_then(OsStr::to_str) {
+        Some(SEGMENT_EXTENSION) => Some(FileKind::Segment),
+        Some(CHECKPOINT_EXTENSION) => Some(FileKind::Checkpoint),
+        _ => None,
+    };
+    let generation = path.file_stem().and_then(OsStr::to_str).and_then(|stem| stem.parse().ok());
+    kind.zip(generation)
+        .ok_or_else(|| KvsError::UnknownFormat(format!("{} is not a segment or checkpoint", path.display())))
+}
+
+// What reading a data file found out about it besides its records.
+struct Headers {
+    // Whether the file header, and a checkpoint's own header, could be read.
+    intact: bool,
+    // The ID of the key the records are encrypted with, if they are and it is known.
+    key_id: Option<u32>,
+}
+
+// Reads every record of a data file of the given kind and generation.
+fn inspect(path: &Path, kind: FileKind, generation: u64, keyring: &Keyring) -> Result<(FileReport, Headers)> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut report = FileReport {
+        path: path.to_owned(),
+        kind,
+        generation,
+        len,
+        data_start: 0,
+        records: Vec::new(),
+        damage: Vec::new(),
+    };
+
+    let (mut headers, mut offset) = match read_file_header(&file, path)? {
+        Some((header, offset)) => (Headers {intact: true, key_id: header.key_id}, offset),
+        None if len < PREFIX_LEN => {
+            if len > 0 {
+                report.damage.push(Damage {offset: 0, len, reason: "incomplete file header".to_owned()});
+            }
+            return Ok((report, Headers {intact: false, key_id: None}));
+        }
+        None => {
+            // The records after a damaged header are still read, with whichever key decrypts them.
+            let end = skip_damaged(&file, PREFIX_LEN, len)?;
+            report.damage.push(Damage {offset: 0, len: end, reason: "damaged file header".to_owned()});
+            (Headers {intact: false, key_id: None}, end)
+        }
+    };
+    if kind == FileKind::Checkpoint && offset < len {
+        let header = read_frame_at(&file, offset)?.filter(|record| {
+            decode_record(record, None).is_some_and(|payload| bincode::deserialize::<CheckpointHeader>(&payload).is_ok())
+        });
+        match header {
+            Some(record) => offset += record.len() as u64,
+            None => {
+                let end = skip_damaged(&file, offset, len)?;
+                report.damage.push(Damage {offset, len: end - offset, reason: "damaged checkpoint header".to_owned()});
+                headers.intact = false;
+                offset = end;
+            }
+        }
+    }
+    let mut cipher = headers.key_id.map(|key_id| keyring.cipher(key_id)).transpose()?;
+    // Without a header the key is unknown until a record decodes.
+    let mut key_known = headers.intact;
+    report.data_start = offset;
+
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(offset))?;
+    while offset < len {
+        let remaining = len - offset;
+        if remaining < HEADER_LEN {
+            report.damage.push(Damage {offset, len: remaining, reason: "incomplete record header".to_owned()});
+            break;
+        }
+        let mut record = vec![0; HEADER_LEN as usize];
+        reader.read_exact(&mut record)?;
+        let record_len = HEADER_LEN + u32::from_le_bytes(record[4..8].try_into().unwrap()) as u64;
+        if record_len > remaining {
+            report.damage.push(Damage {offset, len: remaining, reason: "record runs past the end of the file".to_owned()});
+            break;
+        }
+        record.resize(record_len as usize, 0);
+        reader.read_exact(&mut record[HEADER_LEN as usize..])?;
+
+        let mut parsed = parse_record(&record, cipher);
+        if !key_known && parsed == Err(UNDECODABLE) {
+            let found = keyring
+                .ciphers()
+                .find_map(|cipher| parse_record(&record, Some(cipher)).ok().map(|op| (cipher, op)));
+            if let Some((found, op)) = found {
+                cipher = Some(found);
+                headers.key_id = Some(found.key_id());
+                parsed = Ok(op);
+            }
+        }
+        match parsed {
+            Ok(op) => {
+                key_known = true;
+                let stamp = read_stamp(&record);
+                report.records.push(Record {
+                    offset,
+                    len: record_len,
+                    seq: stamp.map(|stamp| stamp.seq),
+                    timestamp: stamp.map(|stamp| stamp.timestamp),
+                    op,
+                });
+            }
+            Err(reason) => report.damage.push(Damage {offset, len: record_len, reason: reason.to_owned()}),
+        }
+        offset += record_len;
+    }
+    Ok((report, headers))
+}
+
+// Returns where the record after a damaged one at `start` begins: where the damaged record's
+// length says, if an intact record or the end of the file is there, or else at the next offset
+// an intact record starts at.
+fn skip_damaged(file: &File, start: u64, len: u64) -> Result<u64> {
+    let mut rest = vec![0; (len - start) as usize];
+    read_exact_at(file, &mut rest, start)?;
+    let intact_at = |at: usize| {
+        if at == rest.len() {
+            return true;
+        }
+        if at + HEADER_LEN as usize > rest.len() {
+            return false;
+        }
+        let end = at + HEADER_LEN as usize + u32::from_le_bytes(rest[at + 4..at + 8].try_into().unwrap()) as usize;
+        end <= rest.len()
+            && u32::from_le_bytes(rest[at..at + 4].try_into().unwrap()) == crc32fast::hash(&rest[at + 4..end])
+    };
+
+    if rest.len() >= HEADER_LEN as usize {
+        let claimed = HEADER_LEN as usize + u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        if claimed <= rest.len() && intact_at(claimed) {
+            return Ok(start + claimed as u64);
+        }
+    }
+    let next = (1..rest.len()).find(|&at| intact_at(at)).unwrap_or(rest.len());
+    Ok(start + next as u64)
+}
+
+// Why a record that passed its checksum could not be read.
+const UNDECODABLE: &str = "cannot be decrypted or decompressed";
+
+// Decodes a framed record, or says why it cannot be.
+fn parse_record(record: &[u8], cipher: Option<&RecordCipher>) -> std::result::Result<Op, &'static str> {
+    if u32::from_le_bytes(record[..4].try_into().unwrap()) != crc32fast::hash(&record[4..]) {
+        return Err("checksum mismatch");
+    }
+    if record[8] & HEADER_FLAG != 0 {
+        return Err("unexpected header record");
+    }
+    let payload = decode_record(record, cipher).ok_or(UNDECODABLE)?;
+    let cmd: Command = bincode::deserialize(&payload).map_err(|_| "not a valid command")?;
+    Ok(cmd.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_inspect_and_repair() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db");
+        {
+            let store = KvStore::open(&db_path).unwrap();
+            for i in 0..10 {
+                store.set(format!("key{}", i % 5), format!("value{}", i)).unwrap();
+            }
+            store.remove("key4".to_owned()).unwrap();
+        }
+
+        let options = KvStoreOptions::new();
+        let stats = log_stats(&db_path, &options).unwrap();
+        assert_eq!((stats.files, stats.records, stats.keys, stats.damaged_records), (1, 11, 4, 0));
+        assert!(stats.live_bytes > 0 && stats.dead_bytes > stats.live_bytes);
+
+        // Flip a bit in the third record.
+        let segment = segment_path(&db_path, 1);
+        let report = inspect_file(&segment, &options).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.records[0].op, Op::Set {key: b"key0".to_vec(), value: b"value0".to_vec(), expires_at: None});
+        assert_eq!(report.records[0].seq, Some(1));
+        let damaged = report.records[2].offset;
+        let mut contents = fs::read(&segment).unwrap();
+        contents[damaged as usize + HEADER_LEN as usize + 20] ^= 0x01;
+        fs::write(&segment, contents).unwrap();
+
+        let report = inspect_file(&segment, &options).unwrap();
+        assert_eq!(report.records.len(), 10);
+        assert_eq!(report.damage.len(), 1);
+        assert_eq!(report.damage[0].offset, damaged);
+        assert!(matches!(KvStore::open(&db_path), Err(KvsError::Corruption {..})));
+
+        let truncated = repair_file(&segment, &temp_dir.path().join("truncated"), RepairMode::Truncate, &options).unwrap();
+        assert_eq!((truncated.records.len(), truncated.damage.len()), (2, 0));
+        let skipped = temp_dir.path().join("skipped");
+        let repaired = repair_file(&segment, &skipped, RepairMode::Skip, &options).unwrap();
+        assert_eq!((repaired.records.len(), repaired.damage.len()), (10, 0));
+
+        // The repaired segment takes the place of the damaged one.
+        fs::rename(&skipped, &segment).unwrap();
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value7".to_owned()));
+        assert_eq!(store.get("key4".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair_damaged_header() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db");
+        let key_file = temp_dir.path().join("keys");
+        fs::write(&key_file, format!("1 {}\n2 {}\n", "01".repeat(32), "02".repeat(32))).unwrap();
+        let options = KvStoreOptions::new().key_file(&key_file);
+        {
+            let store = options.clone().open(&db_path).unwrap();
+            for i in 0..10 {
+                store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            }
+        }
+
+        // Flip a bit in the file header record.
+        let segment = segment_path(&db_path, 1);
+        let intact = inspect_file(&segment, &options).unwrap();
+        let mut contents = fs::read(&segment).unwrap();
+        contents[(PREFIX_LEN + HEADER_LEN) as usize + 2] ^= 0x01;
+        fs::write(&segment, contents).unwrap();
+
+        let report = inspect_file(&segment, &options).unwrap();
+        assert_eq!(report.damage.len(), 1);
+        assert_eq!(report.damage[0].offset, 0);
+        assert_eq!(report.damage[0].len, intact.data_start);
+        assert_eq!(report.records, intact.records);
+
+        let repaired = temp_dir.path().join("repaired");
+        let report = repair_file(&segment, &repaired, RepairMode::Truncate, &options).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.records.len(), 10);
+        assert!(read_file_header(&File::open(&repaired).unwrap(), &repaired).unwrap().is_some());
+
+        // The repaired segment takes the place of the damaged one.
+        fs::rename(&repaired, &segment).unwrap();
+        let store = options.open(&db_path).unwrap();
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+        }
+    }
+}
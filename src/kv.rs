@@ -1,216 +1,4081 @@
-use crate::{KvsError, Result};
+use crate::util::{DirLock, now_millis, read_exact_at, sync_dir};
+use crate::batch::BatchOp;
+use crate::compression::{CODEC_MASK, Compressor, decompress};
+use crate::crypto::{ENCRYPTED_FLAG, Keyring, RecordCipher};
+use crate::engine::{is_empty_range, prefix_range};
+use crate::stats::{Operation, OperationCounters, StoreStats};
+use crate::transaction::KeyRange;
+use crate::{Compression, CompressionStats, Condition, KvStoreOptions, KvsEngine, KvsError, RecoveryTarget, Result, ScanOptions, SyncPolicy, Transaction, WriteBatch};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Deref, DerefMut, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
 
+// Extension of the segment files that make up the log.
+pub(crate) const SEGMENT_EXTENSION: &str = "log";
+// Extension of the temporary file a compacted segment is written to before it is renamed into place.
+const COMPACTION_EXTENSION: &str = "compact";
+// Extension of the hint file written next to each sealed segment.
+const HINT_EXTENSION: &str = "hint";
+// Extension of checkpoint files, which hold every key as of some point in the log.
+pub(crate) const CHECKPOINT_EXTENSION: &str = "checkpoint";
+// Extension of the temporary file a checkpoint is written to before it is renamed into place.
+const CHECKPOINT_TMP_EXTENSION: &str = "checkpoint-tmp";
+// Extension added to the name of a file while an upgrade rewrites it.
+const UPGRADE_EXTENSION: &str = "upgrade";
+// Name of the manifest listing the files of a backup and their checksums.
+const BACKUP_MANIFEST: &str = "BACKUP";
+// Name of the directory a backup is copied into before it is restored.
+const RESTORE_TMP_DIR: &str = "restore-tmp";
+// Name of the directory holding a complete copy of a backup that is being restored.
+const RESTORE_DIR: &str = "restore";
+// Size of the header in front of every record: a CRC32 checksum, the payload length and a flags byte.
+pub(crate) const HEADER_LEN: u64 = 9;
+// The bit of a record's flags byte that marks the header record at the start of a file.
+pub(crate) const HEADER_FLAG: u8 = 0b1000;
+// The bit of a record's flags byte that is set if its payload starts with its stamp.
+const STAMPED_FLAG: u8 = 0b1_0000;
+// Size of the stamp of a record: its sequence number and timestamp.
+const STAMP_LEN: usize = 16;
+// The bytes every segment and checkpoint starts with, followed by its format version.
+const FORMAT_MAGIC: [u8; 4] = *b"RKVS";
+// The version of the on-disk format this build writes. Files from before there was a version,
+// which start right away with their first record, are version 1. Version 2 did not stamp records.
+const FORMAT_VERSION: u32 = 3;
+// Size of the magic number and format version at the start of a file.
+pub(crate) const PREFIX_LEN: u64 = 8;
+// The name of the engine recorded in the header of every file.
+const ENGINE_NAME: &str = "kvs";
 
 // Represents the commands that can be written to the log.
 // This allows us to rebuild the state of the KvStore by replaying the log.
+// Keys and values are raw bytes. Bincode encodes a `Vec<u8>` exactly like the `String`
+// fields older versions used, so their logs still load.
 #[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Set {key : String, value : String},
-    Remove {key: String}
+pub(crate) enum Command {
+    Set {key : Vec<u8>, value : Vec<u8>},
+    Remove {key: Vec<u8>},
+    // Sets a key that expires at the given time, in milliseconds since the UNIX epoch.
+    SetWithExpiry {key: Vec<u8>, value: Vec<u8>, expires_at: u64},
+    // A group of `Set` and `Remove` commands written as one record, so they are replayed
+    // all together or not at all.
+    Batch {commands: Vec<Command>},
+}
+
+impl Command {
+    // Returns when the key written by this command expires, if it does.
+    fn expires_at(&self) -> Option<u64> {
+        match *self {
+            Command::SetWithExpiry {expires_at, ..} => Some(expires_at),
+            _ => None,
+        }
+    }
+}
+
+// What one record does to the index, as listed in the hint file of its segment. Loading a
+// segment's hints rebuilds its part of the index without reading any values.
+#[derive(Debug, Serialize, Deserialize)]
+struct HintRecord {
+    offset: u64,
+    // The sequence number the record is stamped with.
+    seq: u64,
+    // The length of the framed record in bytes, including its header.
+    len: u64,
+    // The keys the record sets or removes, in order. A batch has one entry per command.
+    ops: Vec<HintOp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HintOp {
+    key: Vec<u8>,
+    expires_at: Option<u64>,
+    // Set if the key is removed rather than set.
+    tombstone: bool,
+}
+
+impl HintRecord {
+    fn new(cmd: &Command, offset: u64, len: u64, seq: u64) -> HintRecord {
+        let op = |cmd: &Command| match cmd {
+            Command::Set {key, ..} | Command::SetWithExpiry {key, ..} => {
+                Some(HintOp {key: key.clone(), expires_at: cmd.expires_at(), tombstone: false})
+            }
+            Command::Remove {key} => Some(HintOp {key: key.clone(), expires_at: None, tombstone: true}),
+            // Batches are never nested.
+            Command::Batch {..} => None,
+        };
+        let ops = match cmd {
+            Command::Batch {commands} => commands.iter().filter_map(op).collect(),
+            cmd => op(cmd).into_iter().collect(),
+        };
+        HintRecord {offset, seq, len, ops}
+    }
+}
+
+// When a record was written: the sequence number of the write and the time it was made at,
+// in milliseconds since the UNIX epoch. Stored in front of the record's payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Stamp {
+    pub(crate) seq: u64,
+    pub(crate) timestamp: u64,
+}
+
+impl Stamp {
+    // Returns whether a write with this stamp was made at or before `target`.
+    fn reaches(&self, target: RecoveryTarget) -> bool {
+        match target {
+            RecoveryTarget::Seq(seq) => self.seq <= seq,
+            RecoveryTarget::Timestamp(time) => {
+                let millis = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+                self.timestamp <= millis
+            }
+        }
+    }
+}
+
+// The header record at the start of every segment and checkpoint, after the magic number and
+// format version. It is never encrypted, so a file can be identified without its key.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct FileHeader {
+    // The name of the engine that wrote the file.
+    engine: String,
+    // When the file was created, in milliseconds since the UNIX epoch.
+    created_at: u64,
+    options: CreationOptions,
+    // The ID of the key the file's records, and a segment's hint file, are encrypted with.
+    pub(crate) key_id: Option<u32>,
+    // Set for files that hold the state of the store as of some write rather than the writes
+    // leading up to it: compacted segments, checkpoints and upgraded files. The store cannot be
+    // recovered to a point before that write from them.
+    pub(crate) base: Option<Stamp>,
+}
+
+// The header record of files in format version 2.
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyFileHeader {
+    engine: String,
+    created_at: u64,
+    options: CreationOptions,
+    key_id: Option<u32>,
+}
+
+// The options of the store that created a file. They are kept for reference only: records
+// name their own codec, and nothing else depends on them to read the file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct CreationOptions {
+    segment_size: u64,
+    compression: Compression,
+    compression_min_size: u64,
+}
+
+impl CreationOptions {
+    pub(crate) fn new(options: &KvStoreOptions) -> CreationOptions {
+        CreationOptions {
+            segment_size: options.segment_size,
+            compression: options.compression,
+            compression_min_size: options.compression_min_size as u64,
+        }
+    }
+}
+
+impl FileHeader {
+    pub(crate) fn new(options: CreationOptions, key_id: Option<u32>) -> FileHeader {
+        FileHeader::for_engine(ENGINE_NAME, options, key_id)
+    }
+
+    // Returns the header of a file written by the given engine, which shares this file format.
+    pub(crate) fn for_engine(engine: &str, options: CreationOptions, key_id: Option<u32>) -> FileHeader {
+        FileHeader {engine: engine.to_owned(), created_at: now_millis(), options, key_id, base: None}
+    }
+
+    // Returns the start of a new file: the magic number, the format version and the header record.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(PREFIX_LEN as usize);
+        buf.extend_from_slice(&FORMAT_MAGIC);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend(frame_record(&bincode::serialize(self)?, HEADER_FLAG)?);
+        Ok(buf)
+    }
+}
+
+// The header record at the start of an encrypted segment in format version 1, which is only
+// read to upgrade it. Segments written in the clear had none.
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacySegmentHeader {
+    key_id: Option<u32>,
+}
+
+// The first record of a checkpoint file. The records after it set every key in the index as of
+// the start of segment `generation`, one key each, so the segments before it are not needed.
+// The checkpoint takes the place of segment `generation`, which is never written.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CheckpointHeader {
+    pub(crate) generation: u64,
+    // The number of records following the header, used to tell a complete checkpoint.
+    pub(crate) keys: u64,
+    // When the checkpoint was written, in milliseconds since the UNIX epoch.
+    pub(crate) created_at: u64,
+    // The ID of the key the records after the header are encrypted with, if they are.
+    pub(crate) key_id: Option<u32>,
+}
+
+// The manifest of a backup, which is written once every file it lists is in place.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupManifest {
+    // When the backup was taken, in milliseconds since the UNIX epoch.
+    created_at: u64,
+    files: Vec<BackupFile>,
+}
+
+// A segment or checkpoint in a backup.
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupFile {
+    name: String,
+    len: u64,
+    crc: u32,
+}
+
+// A key and the location of its record, as loaded into the index.
+type IndexEntry = (Vec<u8>, LogPointer);
+
+// The location of a key's most recent `Set` record in the log, and when the key expires.
+// The length covers the whole record, including its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LogPointer {
+    // The generation of the segment holding the record.
+    generation: u64,
+    // The byte offset of the record within the segment.
+    offset: u64,
+    // The length of the framed record in bytes.
+    len: u64,
+    // The number of bytes of the record this key accounts for, which become stale once the
+    // key is overwritten. This is the whole record, unless it is a batch shared by several keys.
+    share: u64,
+    // When the key expires, in milliseconds since the UNIX epoch. Copied from the record.
+    expires_at: Option<u64>,
+    // The sequence number of the write, which orders it among all writes to the store.
+    seq: u64,
+}
+
+impl LogPointer {
+    // Returns whether the key has not expired at the given time.
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+// A read-only handle to a segment or checkpoint, along with the cipher its records are
+// encrypted with, as named by its header.
+pub(crate) struct LogFile {
+    file: File,
+    cipher: Option<RecordCipher>,
+}
+
+// The segment that new commands are appended to.
+pub(crate) struct ActiveSegment {
+    // The generation number of the segment, which is also its file name.
+    pub(crate) generation: u64,
+    writer: BufWriter<File>,
+    // A second handle to the segment file, which lets it be synced without holding the writer lock.
+    pub(crate) file: Arc<File>,
+    // The current length of the segment file in bytes.
+    pub(crate) len: u64,
+    // The hints for every record in the segment, if a hint file is to be written once it is sealed.
+    hints: Option<Vec<HintRecord>>,
+    // Compresses the records appended to the segment.
+    compressor: Arc<Compressor>,
+    // Holds the key the records appended to the segment are encrypted with, if any.
+    keyring: Arc<Keyring>,
+    // The engine and options recorded in the header of each new segment, or `None` until
+    // `start_with_header` is called.
+    header: Option<(&'static str, CreationOptions)>,
+}
+
+impl ActiveSegment {
+    // Opens the segment with the given generation for appending, creating it if necessary.
+    pub(crate) fn open(dir: &Path, generation: u64) -> Result<ActiveSegment> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, generation))?;
+        let len = file.metadata()?.len();
+
+        Ok(ActiveSegment {
+            generation,
+            file: Arc::new(file.try_clone()?),
+            writer: BufWriter::new(file),
+            len,
+            hints: None,
+            compressor: Arc::default(),
+            keyring: Arc::default(),
+            header: None,
+        })
+    }
+
+    // Makes this segment and every one it rolls over to start with a file header naming
+    // `engine`, and writes the header now if the segment is still empty.
+    pub(crate) fn start_with_header(&mut self, engine: &'static str, options: CreationOptions) -> Result<()> {
+        self.header = Some((engine, options));
+        self.start()
+    }
+
+    // Writes the header of a new, empty segment, naming the key its records are encrypted with.
+    fn start(&mut self) -> Result<()> {
+        let Some((engine, options)) = self.header else {
+            return Ok(());
+        };
+        if self.len > 0 {
+            return Ok(());
+        }
+        let key_id = self.keyring.current().map(RecordCipher::key_id);
+        let header = FileHeader::for_engine(engine, options, key_id).encode()?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+        self.len = header.len() as u64;
+        Ok(())
+    }
+
+    // Frames a command as a record at the end of the segment and flushes it to the OS.
+    // Returns the location of the new record, tagged with the write's sequence number.
+    pub(crate) fn append(&mut self, cmd: &Command, seq: u64) -> Result<LogPointer> {
+        let stamp = Stamp {seq, timestamp: now_millis()};
+        let record = encode_record(cmd, stamp, &self.compressor, self.keyring.current())?;
+        self.writer.write_all(&record)?;
+        self.writer.flush()?;
+
+        let pointer = LogPointer {
+            generation: self.generation,
+            offset: self.len,
+            len: record.len() as u64,
+            share: record.len() as u64,
+            expires_at: cmd.expires_at(),
+            seq,
+        };
+        if let Some(hints) = &mut self.hints {
+            hints.push(HintRecord::new(cmd, pointer.offset, pointer.len, seq));
+        }
+        self.len += pointer.len;
+        Ok(pointer)
+    }
+
+    // Seals the current segment and continues with a new, empty segment of the given generation.
+    // If the segment keeps hints, they are written to its hint file, and the new segment keeps
+    // hints as well. The new segment gets the same kind of header, and its records are
+    // compressed and encrypted the same way.
+    pub(crate) fn roll(&mut self, dir: &Path, generation: u64) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        seal_segment(&segment_path(dir, self.generation))?;
+
+        let hints = self.hints.take();
+        if let Some(hints) = &hints
+            && let Err(e) = write_hints(dir, self.generation, hints, self.keyring.current())
+        {
+            // The segment is replayed in full on the next start instead.
+            warn!("Failed to write the hint file of segment {}: {}", self.generation, e);
+        }
+
+        let (compressor, keyring) = (Arc::clone(&self.compressor), Arc::clone(&self.keyring));
+        let header = self.header;
+        *self = ActiveSegment::open(dir, generation)?;
+        self.hints = hints.map(|_| Vec::new());
+        self.compressor = compressor;
+        self.keyring = keyring;
+        self.header = header;
+        self.start()
+    }
+}
+
+// Makes appended records durable according to the configured `SyncPolicy`.
+pub(crate) struct Syncer {
+    policy: SyncPolicy,
+    state: Mutex<SyncState>,
+    // Signalled whenever a group commit finishes.
+    synced: Condvar,
+}
+
+struct SyncState {
+    // The active segment, which holds every write that has not been synced yet. `None` for a
+    // read-only store, which never writes.
+    file: Option<Arc<File>>,
+    // The number of writes appended to the log so far.
+    written: u64,
+    // The number of writes known to be on disk.
+    synced: u64,
+    // Set while one writer is syncing on behalf of a group of writers.
+    syncing: bool,
+}
+
+impl Syncer {
+    pub(crate) fn new(policy: SyncPolicy, file: Option<Arc<File>>) -> Syncer {
+        Syncer {
+            policy,
+            state: Mutex::new(SyncState {
+                file,
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    // Registers a write that was just appended to the active segment and returns a ticket to
+    // pass to `wait`. Must be called while the writer lock is held, so tickets follow log order.
+    // Policies that sync on the writer's own behalf do so here.
+    pub(crate) fn appended(&self) -> Result<u64> {
+        let mut state = self.state.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        state.written += 1;
+
+        let sync = match self.policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => state.written - state.synced >= n,
+            SyncPolicy::GroupCommit {..} | SyncPolicy::Never => false,
+        };
+        if sync {
+            if let Some(file) = &state.file {
+                file.sync_data()?;
+            }
+            state.synced = state.written;
+        }
+        Ok(state.written)
+    }
+
+    // Blocks until the write with the given ticket is on disk. Only group commits wait here.
+    //
+    // The first writer to arrive becomes the leader: it waits `max_delay` for others to
+    // append their records, then syncs once for all of them. Writers arriving while a sync is
+    // in progress wait for it, and the next sync if their write was not covered.
+    pub(crate) fn wait(&self, ticket: u64) -> Result<()> {
+        let SyncPolicy::GroupCommit {max_delay} = self.policy else {
+            return Ok(());
+        };
+
+        let mut state = self.state.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        while state.synced < ticket {
+            if state.syncing {
+                state = self.synced.wait(state).map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            if !max_delay.is_zero() {
+                thread::sleep(max_delay);
+            }
+
+            let (file, target) = {
+                let state = self.state.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+                (state.file.clone(), state.written)
+            };
+            let result = file.map_or(Ok(()), |file| file.sync_data());
+
+            state = self.state.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+        Ok(())
+    }
+
+    // Switches to a newly started segment. Every earlier write is on disk, since the previous
+    // segment was synced when it was sealed.
+    pub(crate) fn segment_rolled(&self, file: Arc<File>) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        state.file = Some(file);
+        state.synced = state.written;
+        Ok(())
+    }
 }
 
 /// A simple, persistent, thread-safe key-value store.
 ///
-/// It stores key-value pairs in memory for fast lookups and appends every
-/// write operation to a log file on disk to ensure durability. The log is replayed
-/// on startup to restore the in-memory state.
+/// Every write operation is appended to a log on disk to ensure durability. Only an
+/// index is kept in memory, mapping each key to the location of its latest value in
+/// the log, so datasets larger than memory can be stored. Values are read from disk
+/// with positioned reads. The log is replayed on startup to rebuild the index.
+///
+/// The log lives in a directory of numbered segment files (`1.log`, `2.log`, ...).
+/// Writes go to the newest segment until it reaches the configured size, at which
+/// point it is sealed as read-only and a new segment is started. Each segment starts with
+/// a header naming its on-disk format version, which is checked when the store is opened;
+/// data in an older format is brought up to date with [`KvStore::upgrade`].
+/// Every record is stamped with the sequence number and time of its write, so the store can be
+/// opened as it was at an earlier point with [`KvStore::open_at`].
+///
+/// Overwritten and removed entries leave stale records behind in the log. Once they
+/// exceed the configured compaction threshold, the sealed segments are rewritten in
+/// the background into a single segment containing only the live entries.
+///
+/// Alternatively, the store can periodically write a checkpoint holding every key, after which
+/// the segments it covers are deleted. On open, the newest checkpoint is loaded and only the
+/// log written after it is replayed.
 ///
 /// Cloning is a cheap, lightweight operation as it only increments an atomic reference count.
 #[derive(Clone)]
 pub struct KvStore {
-    // The in-memory cache of key-value pairs for fast reads.
-    map: Arc<RwLock<HashMap<String, String>>>,
-    // The writer for the on-disk write-ahead log (WAL).
+    // The directory holding the segment files.
+    dir: Arc<PathBuf>,
+    // The in-memory index mapping each key to the location of its value in the log, in key order.
+    map: Arc<RwLock<BTreeMap<Vec<u8>, LogPointer>>>,
+    // Read-only handles to the segment files, opened on first use.
+    readers: Arc<RwLock<HashMap<u64, Arc<LogFile>>>>,
+    // The active segment of the on-disk write-ahead log (WAL), or `None` if the store was opened read-only.
     // A Mutex is used to ensure that writes to the log are sequential.
-    writer: Arc<Mutex<BufWriter<File>>>,
+    writer: Arc<Mutex<Option<ActiveSegment>>>,
+    // Syncs written records to disk according to the configured policy.
+    syncer: Arc<Syncer>,
+    // The number of bytes in the log taken up by records that have been overwritten or removed.
+    uncompacted: Arc<AtomicU64>,
+    // Set while a background compaction is scheduled, so that only one is spawned at a time.
+    compaction_scheduled: Arc<AtomicBool>,
+    // Held for the duration of a compaction to prevent two from running concurrently.
+    compaction_lock: Arc<Mutex<()>>,
+    // Set while a background checkpoint is scheduled, so that only one is spawned at a time.
+    checkpoint_scheduled: Arc<AtomicBool>,
+    // When the last checkpoint was written or the store was opened, in milliseconds since the UNIX epoch.
+    last_checkpoint: Arc<AtomicU64>,
+    // When the last compaction finished, in milliseconds since the UNIX epoch, or 0 if none has
+    // since the store was opened.
+    last_compaction: Arc<AtomicU64>,
+    // How often each operation was called and how long it took.
+    operations: Arc<OperationCounters>,
+    // The sequence number of the last write applied to the index. Only advanced under the writer lock.
+    seq: Arc<AtomicU64>,
+    // Open snapshots and the superseded versions they can still see.
+    history: Arc<Mutex<History>>,
+    // Compresses new records, shared with the active segment.
+    compressor: Arc<Compressor>,
+    // The keys files are encrypted with, shared with the active segment.
+    keyring: Arc<Keyring>,
+    // The lock on the directory, held for as long as any handle to a writable store is open.
+    _dir_lock: Option<Arc<DirLock>>,
+    options: Arc<KvStoreOptions>,
+}
+
+// The in-memory state of a store as loaded from its directory.
+struct LoadedState {
+    map: BTreeMap<Vec<u8>, LogPointer>,
+    uncompacted: u64,
+    // The sequence number of the last record loaded.
+    seq: u64,
+    // The segment to append to, or `None` if the store is opened read-only.
+    writer: Option<ActiveSegment>,
+    // The generations of every segment and checkpoint the index may point into.
+    generations: Vec<u64>,
+}
+
+// How far the log has been replayed while loading a store.
+struct Replay {
+    // The highest sequence number applied so far.
+    seq: u64,
+    // The last write to replay, if not all of them.
+    target: Option<RecoveryTarget>,
+    // Set once a record past the target has been read. The records after it are skipped too,
+    // even if their clock went backwards.
+    done: bool,
+}
+
+impl Replay {
+    // Returns the sequence number to apply a record with the given stamp with, or `None` if
+    // the record lies past the target. Records without a stamp follow the one before them.
+    fn apply(&mut self, stamp: Option<Stamp>) -> Option<u64> {
+        let past = stamp.zip(self.target).is_some_and(|(stamp, target)| !stamp.reaches(target));
+        self.done |= past;
+        if self.done {
+            return None;
+        }
+        self.seq = stamp.map_or(self.seq + 1, |stamp| stamp.seq.max(self.seq));
+        Some(self.seq)
+    }
+
+    // Fails if the log before a file holding the state of the store as of `base` is needed to
+    // reach the target.
+    fn check_base(&self, base: Option<Stamp>) -> Result<()> {
+        match (base, self.target) {
+            (Some(base), Some(target)) if !base.reaches(target) => Err(KvsError::Unrecoverable(format!(
+                "the log was compacted or checkpointed after write {}",
+                base.seq
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The writer lock of a store that was not opened read-only, giving access to its active segment.
+struct WriterGuard<'a>(MutexGuard<'a, Option<ActiveSegment>>);
+
+impl Deref for WriterGuard<'_> {
+    type Target = ActiveSegment;
+
+    fn deref(&self) -> &ActiveSegment {
+        self.0.as_ref().expect("only writable stores hand out writer guards")
+    }
+}
+
+impl DerefMut for WriterGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ActiveSegment {
+        self.0.as_mut().expect("only writable stores hand out writer guards")
+    }
 }
 
-impl KvStore {
-    /// Opens a `KvStore` and loads its data from the given path.
-    /// If the log file doesn't exist, it will be created.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
+// Superseded versions of keys that open snapshots can still see.
+#[derive(Default)]
+struct History {
+    // The sequence numbers of open snapshots, with the number of handles open at each.
+    snapshots: BTreeMap<u64, usize>,
+    // Overwritten or removed versions by key, oldest first.
+    versions: BTreeMap<Vec<u8>, Vec<Version>>,
+}
+
+// A superseded version of a key. It is visible to snapshots taken at or after `pointer.seq`
+// and before `until`, the sequence number of the write that replaced it.
+struct Version {
+    pointer: LogPointer,
+    until: u64,
+    // The segment holding the record, kept open so the version stays readable after compaction.
+    file: Arc<LogFile>,
+}
+
+impl History {
+    // Returns whether an open snapshot falls within the given range of sequence numbers.
+    fn is_visible(&self, from: u64, until: u64) -> bool {
+        self.snapshots.range(from..until).next().is_some()
+    }
+}
+
+impl KvStore {
+    /// Opens a `KvStore` and loads its data from the given directory.
+    /// If the directory doesn't exist, it will be created.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens the `KvStore` in the given directory read-only, alongside any process writing to it.
+    ///
+    /// See [`KvStoreOptions::read_only`].
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default().read_only(true))
+    }
+
+    /// Opens the `KvStore` in the given directory read-only, as it was at `target`.
+    ///
+    /// See [`KvStoreOptions::recovery_target`]. To continue writing from there, copy the store
+    /// into a new directory with [`KvStore::copy_to`].
+    pub fn open_at(path: impl Into<PathBuf>, target: RecoveryTarget) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::default().recovery_target(target))
+    }
+
+    /// Opens a `KvStore` in the given directory using the provided options.
+    ///
+    /// Unless the store is opened read-only, the directory is locked for as long as the store
+    /// is open. Errors with [`KvsError::AlreadyLocked`] if another process has it open already.
+    pub fn open_with_options(path: impl Into<PathBuf>, mut options: KvStoreOptions) -> Result<KvStore> {
+        let dir = path.into();
+        // Appending to a log that was only partly replayed would lose the rest of it.
+        if options.recovery_target.is_some() {
+            options.read_only = true;
+        }
+        // A read-only store leaves every file as it finds it, since a writer may be using them.
+        let read_only = options.read_only;
+        let dir_lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(&dir)?;
+            Some(Arc::new(DirLock::acquire(&dir)?))
+        };
+
+        // A restore that was interrupted is finished before anything is loaded.
+        if !read_only {
+            finish_restore(&dir)?;
+        }
+        let keyring = Arc::new(Keyring::from_options(&options)?);
+        let compressor = Arc::new(Compressor::new(options.compression, options.compression_min_size));
+        let LoadedState {map, uncompacted, seq, writer, generations} = Self::load_dir(&dir, &options, &keyring, &compressor)?;
+
+        let store = KvStore{
+            dir: Arc::new(dir),
+            map: Arc::new(RwLock::new(map)),
+            readers: Arc::new(RwLock::new(HashMap::new())),
+            syncer: Arc::new(Syncer::new(options.sync_policy, writer.as_ref().map(|writer| Arc::clone(&writer.file)))),
+            writer: Arc::new(Mutex::new(writer)),
+            uncompacted: Arc::new(AtomicU64::new(uncompacted)),
+            compaction_scheduled: Arc::new(AtomicBool::new(false)),
+            compaction_lock: Arc::new(Mutex::new(())),
+            checkpoint_scheduled: Arc::new(AtomicBool::new(false)),
+            last_checkpoint: Arc::new(AtomicU64::new(now_millis())),
+            last_compaction: Arc::new(AtomicU64::new(0)),
+            operations: Arc::new(OperationCounters::default()),
+            seq: Arc::new(AtomicU64::new(seq)),
+            history: Arc::new(Mutex::new(History::default())),
+            compressor,
+            keyring,
+            _dir_lock: dir_lock,
+            options: Arc::new(options),
+        };
+
+        if read_only {
+            // Open every file the index points into right away. The writer deletes files once
+            // they are compacted or checkpointed, but files that are already open stay readable.
+            for generation in generations {
+                store.segment_file(generation)?;
+            }
+        }
+        store.spawn_sweeper();
+        store.spawn_stats_logger();
+        Ok(store)
+    }
+
+    // Loads the index of the store in the given directory, up to the recovery target if one is
+    // set, and opens the segment to append to unless the store is opened read-only. Leftovers
+    // of anything interrupted by a crash are cleaned up along the way.
+    fn load_dir(
+        dir: &Path,
+        options: &KvStoreOptions,
+        keyring: &Arc<Keyring>,
+        compressor: &Arc<Compressor>,
+    ) -> Result<LoadedState> {
+        let read_only = options.read_only;
+        if !read_only {
+            // A leftover compaction file means a previous process stopped before it could rename
+            // the compacted segment into place. The segments it was built from are still complete,
+            // so the copy is discarded, as is an unfinished checkpoint or upgraded file. Hint
+            // files whose segment was deleted go as well.
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension() == Some(OsStr::new(COMPACTION_EXTENSION))
+                    || path.extension() == Some(OsStr::new(CHECKPOINT_TMP_EXTENSION))
+                    || path.extension() == Some(OsStr::new(UPGRADE_EXTENSION))
+                {
+                    warn!("Discarding unfinished file {}", path.display());
+                    fs::remove_file(&path)?;
+                } else if path.extension() == Some(OsStr::new(HINT_EXTENSION))
+                    && !path.with_extension(SEGMENT_EXTENSION).exists()
+                {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        let mut replay = Replay {seq: 0, target: options.recovery_target, done: false};
+
+        // Start from the newest checkpoint that is intact, if there is one. A crash right after
+        // it was written may have left behind the files it covers, which are removed now.
+        let mut covered = 0;
+        for generation in sorted_files(dir, CHECKPOINT_EXTENSION)?.into_iter().rev() {
+            match read_checkpoint(dir, generation, keyring) {
+                Ok((header, entries)) => {
+                    replay.check_base(header.base)?;
+                    for (key, pointer) in entries {
+                        replay.seq = replay.seq.max(pointer.seq);
+                        map.insert(key, pointer);
+                    }
+                    covered = generation;
+                    break;
+                }
+                Err(e) => warn!("Ignoring damaged checkpoint {}: {}", generation, e),
+            }
+        }
+        if !read_only {
+            remove_covered(dir, covered)?;
+        }
+
+        // Restore the rest of the in-memory state, oldest segment first. Sealed segments are
+        // loaded from their hint files, so only the active segment has to be replayed record by record.
+        let generations: Vec<u64> = sorted_generations(dir)?.into_iter().filter(|&generation| generation > covered).collect();
+        let mut uncompacted = 0;
+        let mut active_hints = Vec::new();
+        let mut active_cipher = None;
+        for &generation in &generations {
+            let path = segment_path(dir, generation);
+            let file = File::open(&path)?;
+            let metadata = file.metadata()?;
+            let (file_len, sealed) = (metadata.len(), metadata.permissions().readonly());
+            // Fails if the segment is in another format, was encrypted under a key that was not
+            // supplied, or discarded the history needed to reach the recovery target.
+            let (key_id, data_start) = match read_file_header(&file, &path)? {
+                Some((header, len)) => {
+                    replay.check_base(header.base)?;
+                    (header.key_id, len)
+                }
+                None => (None, 0),
+            };
+            let cipher = key_id.map(|key_id| keyring.cipher(key_id)).transpose()?;
+            // Hints carry no timestamps, so they cannot tell where to stop replaying.
+            if sealed
+                && replay.target.is_none()
+                && let Some(hints) = read_hints(dir, generation, data_start, file_len, cipher)
+            {
+                uncompacted += Self::load_hints(generation, hints, &mut map, &mut replay.seq)?;
+                continue;
+            }
+
+            // A segment without a complete header is what a crash right after it was started
+            // leaves behind, and holds no records.
+            let (stale, valid_len, hints) = match data_start {
+                0 => (0, 0, Vec::new()),
+                _ => Self::load(generation, BufReader::new(file), data_start, file_len, cipher, &mut map, &mut replay)?,
+            };
+            uncompacted += stale;
+
+            if valid_len < file_len {
+                // An incomplete record at the end of the newest segment is what a crash in the
+                // middle of a write leaves behind. It was never acknowledged, so it is dropped.
+                // Sealed segments were synced before being sealed, so a bad tail there is corruption.
+                if Some(&generation) != generations.last() || sealed {
+                    return Err(KvsError::Corruption {segment: generation, offset: valid_len});
+                }
+                // A writer may still be in the middle of appending it.
+                if read_only {
+                    continue;
+                }
+                warn!(
+                    "Truncating {} bytes of incomplete records at the end of segment {}",
+                    file_len - valid_len,
+                    generation
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+
+            if sealed && !read_only {
+                // The segment was sealed before hint files existed, or its hint file was lost.
+                write_hints(dir, generation, &hints, cipher)?;
+            } else {
+                active_hints = hints;
+                active_cipher = cipher;
+            }
+        }
+
+        let writer = if read_only {
+            None
+        } else {
+            // Keep appending to the newest segment unless it has been sealed or is already full. A
+            // segment encrypted differently than new records are to be is sealed as well, since all
+            // records in a segment are encrypted with the key named in its header.
+            let current_key = keyring.current().map(RecordCipher::key_id);
+            let mut writer = match generations.last() {
+                Some(&generation) => {
+                    let path = segment_path(dir, generation);
+                    let metadata = fs::metadata(&path)?;
+                    let rekeyed = metadata.len() > 0 && active_cipher.map(RecordCipher::key_id) != current_key;
+                    if metadata.permissions().readonly() {
+                        ActiveSegment::open(dir, generation + 1)?
+                    } else if metadata.len() >= options.segment_size || rekeyed {
+                        seal_segment(&path)?;
+                        write_hints(dir, generation, &mem::take(&mut active_hints), active_cipher)?;
+                        ActiveSegment::open(dir, generation + 1)?
+                    } else {
+                        ActiveSegment::open(dir, generation)?
+                    }
+                }
+                None => ActiveSegment::open(dir, covered + 1)?,
+            };
+            writer.hints = Some(active_hints);
+            writer.compressor = Arc::clone(compressor);
+            writer.keyring = Arc::clone(keyring);
+            writer.start_with_header(ENGINE_NAME, CreationOptions::new(options))?;
+            Some(writer)
+        };
+
+        let mut generations = generations;
+        generations.extend((covered > 0).then_some(covered));
+        Ok(LoadedState {map, uncompacted, seq: replay.seq, writer, generations})
+    }
+
+    /// Rewrites every segment and checkpoint in the given directory that is in an older on-disk
+    /// format in the current one. Files already in the current format are left alone, so an
+    /// upgrade that was interrupted can simply be run again.
+    ///
+    /// Records are copied as they are apart from being stamped with a sequence number, so no
+    /// encryption keys are needed. The headers of files from before there was a file header
+    /// record `options` as the options they were created with. The store cannot be recovered
+    /// to a point before the upgrade.
+    pub fn upgrade(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<()> {
+        let dir = path.into();
+        let _dir_lock = DirLock::acquire(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new(UPGRADE_EXTENSION)) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        // Segments and checkpoints never share a generation, so together they sort in log order.
+        let segments = sorted_generations(&dir)?.into_iter().map(|generation| (generation, segment_path(&dir, generation)));
+        let checkpoints = sorted_files(&dir, CHECKPOINT_EXTENSION)?
+            .into_iter()
+            .map(|generation| (generation, checkpoint_path(&dir, generation)));
+        let mut files: Vec<_> = segments.chain(checkpoints).collect();
+        files.sort_unstable_by_key(|&(generation, _)| generation);
+
+        // Records in older formats are stamped with sequence numbers in log order, carrying on
+        // from the files an interrupted upgrade already rewrote.
+        let mut seq = 0;
+        let mut upgraded = 0;
+        for (generation, path) in files {
+            let version = match read_file_header(&File::open(&path)?, &path) {
+                Err(KvsError::UpgradeRequired(version)) => version,
+                result => {
+                    if let Some((header, _)) = result?
+                        && let Some(base) = header.base
+                    {
+                        seq = seq.max(base.seq);
+                    }
+                    continue;
+                }
+            };
+            // The records move, so the segment's hints no longer match. They are written again
+            // when the store is next opened.
+            remove_hints(&dir, generation)?;
+            upgrade_file(&path, generation, version, CreationOptions::new(options), &mut seq)?;
+            upgraded += 1;
+        }
+        sync_dir(&dir)?;
+
+        info!("Upgraded {} files in {} to format version {}", upgraded, dir.display(), FORMAT_VERSION);
+        Ok(())
+    }
+
+    /// Copies the store into the given directory, which must be empty or not exist yet, while
+    /// reads and writes continue.
+    ///
+    /// The backup holds every write made before the call. The active segment is sealed first,
+    /// and sealed segments and checkpoints are hard-linked into the backup where the file system
+    /// allows it, since they are never modified. A manifest with the checksum of every file is
+    /// written last, so an interrupted backup is never mistaken for a complete one.
+    pub fn backup_to(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let backup_dir = dir.as_ref();
+        // Compactions and checkpoints delete files, so neither may run until all are linked.
+        let _guard = self.compaction_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        let generation = {
+            let mut writer = self.lock_writer()?;
+            let generation = writer.generation + 1;
+            self.roll(&mut writer, generation)?;
+            generation
+        };
+
+        fs::create_dir_all(backup_dir)?;
+        if fs::read_dir(backup_dir)?.next().is_some() {
+            let message = format!("{} is not empty", backup_dir.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+        }
+        // Hint files are left out. They are written again when the backup is restored.
+        let segments = sorted_generations(&self.dir)?
+            .into_iter()
+            .filter(|&old| old < generation)
+            .map(|old| segment_path(&self.dir, old));
+        let checkpoints = sorted_files(&self.dir, CHECKPOINT_EXTENSION)?
+            .into_iter()
+            .map(|old| checkpoint_path(&self.dir, old));
+        let mut files = Vec::new();
+        for path in segments.chain(checkpoints) {
+            let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default().to_owned();
+            let target = backup_dir.join(&name);
+            link_or_copy(&path, &target)?;
+            let (len, crc) = checksum_file(&target)?;
+            files.push(BackupFile {name, len, crc});
+        }
+
+        let manifest = BackupManifest {created_at: now_millis(), files};
+        let mut file = File::create(backup_dir.join(BACKUP_MANIFEST))?;
+        file.write_all(&frame_record(&bincode::serialize(&manifest)?, 0)?)?;
+        file.sync_all()?;
+        sync_dir(backup_dir)?;
+
+        info!("Backed up {} files to {}", manifest.files.len(), backup_dir.display());
+        Ok(())
+    }
+
+    /// Writes every live key of the store into a new store in the given directory, which must
+    /// be empty or not exist yet, and returns the new store. It is opened with the same
+    /// options, except that it is writable.
+    ///
+    /// Together with [`KvStore::open_at`], this recovers a store as it was at some earlier
+    /// point into a new directory.
+    pub fn copy_to(&self, dir: impl Into<PathBuf>) -> Result<KvStore> {
+        let dir = dir.into();
+        if dir.exists() && fs::read_dir(&dir)?.next().is_some() {
+            let message = format!("{} is not empty", dir.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+        }
+        // Keep compactions from removing the files the index points into while they are read.
+        let _guard = self.compaction_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        let entries: Vec<_> = {
+            let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            map.iter().map(|(key, &pointer)| (key.clone(), pointer)).collect()
+        };
+
+        let mut options = (*self.options).clone();
+        options.read_only = false;
+        options.recovery_target = None;
+        // Nothing in the copy is needed until all of it is, so it is synced once at the end.
+        let copy = KvStore::open_with_options(&dir, options.clone().sync_policy(SyncPolicy::Never))?;
+        let now = now_millis();
+        for (key, pointer) in entries {
+            if pointer.is_live(now) {
+                let file = self.segment_file(pointer.generation)?;
+                let value = read_value(&file, pointer, &key)?;
+                copy.put(key, value, pointer.expires_at)?;
+            }
+        }
+        copy.flush()?;
+        drop(copy);
+
+        KvStore::open_with_options(dir, options)
+    }
+
+    /// Replaces the contents of the store with a backup made by [`KvStore::backup_to`].
+    ///
+    /// Every file of the backup is checked against its checksum before anything is changed,
+    /// and errors with [`KvsError::InvalidBackup`] if one does not match. The backup is then
+    /// copied into the data directory and swapped in. If the process stops in the middle, the
+    /// swap is finished when the store is next opened. Open snapshots keep seeing the data they
+    /// were taken of.
+    pub fn restore_from(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let backup_dir = dir.as_ref();
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let _guard = self.compaction_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        let manifest = verify_backup(backup_dir, &self.keyring)?;
+
+        // Copy the backup next to the files it replaces, from where it can be moved into place.
+        // Once the copy is complete it is renamed, which marks it as ready to be installed.
+        let staging = self.dir.join(RESTORE_TMP_DIR);
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir(&staging)?;
+        for file in &manifest.files {
+            link_or_copy(&backup_dir.join(&file.name), &staging.join(&file.name))?;
+        }
+        link_or_copy(&backup_dir.join(BACKUP_MANIFEST), &staging.join(BACKUP_MANIFEST))?;
+        sync_dir(&staging)?;
+        fs::rename(&staging, self.dir.join(RESTORE_DIR))?;
+        sync_dir(&self.dir)?;
+
+        let mut writer = self.lock_writer()?;
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let seq = self.seq.load(Ordering::SeqCst);
+        for (key, pointer) in mem::take(&mut *map) {
+            self.retire(key, pointer, seq + 1)?;
+        }
+
+        finish_restore(&self.dir)?;
+        let mut loaded = Self::load_dir(&self.dir, &self.options, &self.keyring, &self.compressor)?;
+        // Keys loaded from the backup are ordered after every open snapshot, which keep the
+        // versions they can see.
+        for pointer in loaded.map.values_mut() {
+            pointer.seq = pointer.seq.max(seq + 1);
+        }
+        *map = loaded.map;
+        let mut readers = self.readers.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        readers.clear();
+        if let Some(active) = loaded.writer {
+            self.syncer.segment_rolled(Arc::clone(&active.file))?;
+            *writer = active;
+        }
+        self.uncompacted.store(loaded.uncompacted, Ordering::SeqCst);
+        self.seq.store(loaded.seq.max(seq + 1), Ordering::SeqCst);
+
+        info!("Restored {} files from {}", manifest.files.len(), backup_dir.display());
+        Ok(())
+    }
+
+    // Applies all records from one segment file, which start at `data_start`, to the in-memory
+    // index, until `replay` reaches its target.
+    //
+    // Returns the number of stale bytes found in the segment, the length of the segment up to
+    // the end of the last complete record, as read by `read_segment`, and the hints for the
+    // records up to there.
+    fn load(
+        generation: u64,
+        mut reader: BufReader<File>,
+        data_start: u64,
+        file_len: u64,
+        cipher: Option<&RecordCipher>,
+        map: &mut BTreeMap<Vec<u8>, LogPointer>,
+        replay: &mut Replay,
+    ) -> Result<(u64, u64, Vec<HintRecord>)> {
+        let mut uncompacted = 0;
+        let mut hints = Vec::new();
+
+        reader.seek(SeekFrom::Start(data_start))?;
+        let valid_len = read_segment(generation, reader, data_start, file_len, cipher, |cmd, stamp, offset, len| {
+            let Some(seq) = replay.apply(stamp) else {
+                return Ok(());
+            };
+            hints.push(HintRecord::new(&cmd, offset, len, seq));
+            let pointer = LogPointer {generation, offset, len, share: len, expires_at: cmd.expires_at(), seq};
+
+            // Keys that expired while the store was closed are loaded anyway. They are hidden
+            // from reads and dropped by the next sweep.
+            match cmd {
+                Command::Set {key, ..} | Command::SetWithExpiry {key, ..} => {
+                    if let Some(old) = map.insert(key, pointer) {
+                        uncompacted += old.share;
+                    }
+                }
+                Command::Remove {key} => {
+                    // The record that set the key is stale now, as is the removal record itself.
+                    if let Some(old) = map.remove(&key) {
+                        uncompacted += old.share;
+                    }
+                    uncompacted += pointer.len;
+                }
+                Command::Batch {commands} => uncompacted += apply_batch(map, pointer, &commands, |_, _| {}),
+            }
+            Ok(())
+        })?;
+        Ok((uncompacted, valid_len, hints))
+    }
+
+    // Applies the records of a sealed segment to the in-memory index from its hints, with the
+    // same result as replaying the records themselves. Returns the number of stale bytes.
+    fn load_hints(
+        generation: u64,
+        hints: Vec<HintRecord>,
+        map: &mut BTreeMap<Vec<u8>, LogPointer>,
+        seq: &mut u64,
+    ) -> Result<u64> {
+        let mut uncompacted = 0;
+
+        for hint in hints {
+            *seq = (*seq).max(hint.seq);
+            // Each key of the record accounts for an equal share of it, like in `apply_batch`.
+            // For a record of a single command, that is the whole record.
+            let share = hint.len / hint.ops.len().max(1) as u64;
+            uncompacted += hint.len - share * hint.ops.len() as u64;
+            let pointer = LogPointer {generation, offset: hint.offset, len: hint.len, share, expires_at: None, seq: hint.seq};
+
+            for op in hint.ops {
+                let old = if op.tombstone {
+                    uncompacted += share;
+                    map.remove(&op.key)
+                } else {
+                    map.insert(op.key, LogPointer {expires_at: op.expires_at, ..pointer})
+                };
+                if let Some(old) = old {
+                    uncompacted += old.share;
+                }
+            }
+        }
+        Ok(uncompacted)
+    }
+
+    /// Sets a key-value pair.
+    ///
+    /// This is a convenience wrapper around [`KvStore::set_bytes`] for UTF-8 keys and values.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets a key to an arbitrary byte value.
+    ///
+    /// This operation is persisted to the on-disk log before updating the in-memory map.
+    /// It returns once the record is as durable as the configured [`SyncPolicy`] requires.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.timed(Operation::Set, || self.put(key, value, None))
+    }
+
+    /// Sets a key-value pair that expires once `ttl` has passed.
+    ///
+    /// Expired keys are hidden from reads immediately, and their space is reclaimed by a
+    /// background sweep and the next compaction.
+    pub fn set_with_ttl(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>, ttl: Duration) -> Result<()> {
+        self.timed(Operation::Set, || self.put(key.into(), value.into(), Some(expiry_after(ttl))))
+    }
+
+    /// Makes an existing key expire once `ttl` has passed, replacing any previous expiry.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist.
+    pub fn expire(&self, key: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        self.timed(Operation::Expire, || self.set_expiry(key.as_ref(), Some(expiry_after(ttl))))
+    }
+
+    /// Removes the expiry of a key, so it lives until it is removed.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist.
+    pub fn persist(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.timed(Operation::Expire, || self.set_expiry(key.as_ref(), None))
+    }
+
+    /// Returns how long a key has left to live, or `None` if it does not expire.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist.
+    pub fn ttl(&self, key: impl AsRef<[u8]>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        match map.get(key.as_ref()) {
+            Some(pointer) if pointer.is_live(now) => {
+                Ok(pointer.expires_at.map(|expires_at| Duration::from_millis(expires_at - now)))
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    // Writes a key's value and expiry to the log, then waits until it is durable.
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let ticket = {
+            let mut writer = self.lock_writer()?;
+            self.append_value(&mut writer, key, value, expires_at)?
+        };
+
+        // Waiting for a group commit happens outside the writer lock, so other writers can join it.
+        self.syncer.wait(ticket)?;
+        self.maybe_schedule_work();
+        Ok(())
+    }
+
+    // Rewrites the current value of a key with a new expiry. The value is written again so
+    // that the latest record of a key always holds both, which is all compaction keeps.
+    fn set_expiry(&self, key: &[u8], expires_at: Option<u64>) -> Result<()> {
+        let ticket = {
+            let mut writer = self.lock_writer()?;
+            let (pointer, file) = self.locate(key)?.ok_or(KvsError::KeyNotFound)?;
+            if pointer.expires_at == expires_at {
+                return Ok(());
+            }
+
+            let value = read_value(&file, pointer, key)?;
+            self.append_value(&mut writer, key.to_vec(), value, expires_at)?
+        };
+
+        self.syncer.wait(ticket)?;
+        self.maybe_schedule_work();
+        Ok(())
+    }
+
+    // Appends a record setting a key and points the index at it. Returns the ticket to wait on
+    // before the write may be acknowledged.
+    fn append_value(
+        &self,
+        writer: &mut ActiveSegment,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        // Serialize the command and flush it to the log before touching the index.
+        // This implements the write-ahead log (WAL) pattern for durability.
+        let cmd = match expires_at {
+            Some(expires_at) => Command::SetWithExpiry {key: key.clone(), value, expires_at},
+            None => Command::Set {key: key.clone(), value},
+        };
+        let pointer = writer.append(&cmd, self.next_seq())?;
+        let ticket = self.syncer.appended()?;
+
+        // The map is updated before the writer lock is released so that the map always
+        // reflects exactly the records in the log. Compaction relies on this to take a
+        // consistent snapshot.
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        if let Some(old) = map.insert(key.clone(), pointer) {
+            self.uncompacted.fetch_add(old.share, Ordering::SeqCst);
+            self.retire(key, old, pointer.seq)?;
+        }
+        drop(map);
+
+        self.maybe_roll(writer)?;
+        Ok(ticket)
+    }
+
+    /// Gets the value associated with a key.
+    ///
+    /// Errors with [`KvsError::InvalidUtf8`] if the stored value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Gets the bytes associated with a key.
+    ///
+    /// Returns `None` if the key is not found. The location of the value is looked up
+    /// in the in-memory index and the value is then read from its segment on disk.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Only a read lock on the index is needed, so reads run concurrently.
+        self.timed(Operation::Get, || match self.locate(key)? {
+            Some((pointer, file)) => Ok(Some(read_value(&file, pointer, key)?)),
+            None => Ok(None),
+        })
+    }
+
+    /// Returns every key-value pair whose key falls within `range`, in ascending key order.
+    ///
+    /// Errors with [`KvsError::InvalidUtf8`] if a key or value in range is not valid UTF-8.
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        KvsEngine::scan(self, range)
+    }
+
+    /// Returns the key-value pairs whose key falls within `range`, in byte order, limited and
+    /// ordered as set by `options`.
+    pub fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_iter(range, options)?.collect()
+    }
+
+    /// Returns an iterator over the key-value pairs whose key falls within `range`.
+    ///
+    /// The matching keys are picked from the index up front, but values are only read from the
+    /// log as the iterator advances, so a large scan does not hold every value in memory.
+    pub fn scan_iter(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<ScanIter> {
+        self.timed(Operation::Scan, || {
+            if is_empty_range(&range) {
+                return Ok(ScanIter {entries: Vec::new().into_iter()});
+            }
+
+            let now = now_millis();
+            let limit = options.limit.unwrap_or(usize::MAX);
+            let live = |(_, pointer): &(&Vec<u8>, &LogPointer)| pointer.is_live(now);
+
+            let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            let selected: Vec<_> = if options.reverse {
+                map.range(range).rev().filter(live).take(limit).collect()
+            } else {
+                map.range(range).filter(live).take(limit).collect()
+            };
+            // Segment files are resolved under the lock, so compaction cannot remove them before they are read.
+            let entries = selected
+                .into_iter()
+                .map(|(key, &pointer)| Ok((key.clone(), pointer, self.segment_file(pointer.generation)?)))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(ScanIter {entries: entries.into_iter()})
+        })
+    }
+
+    /// Forces every record written so far to disk, regardless of the configured [`SyncPolicy`].
+    pub fn flush(&self) -> Result<()> {
+        let writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        if let Some(writer) = &*writer {
+            writer.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Returns how much compression has shrunk the records written since the store was opened.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats()
+    }
+
+    /// Returns the number of keys, how much of the log they take up, and how often each
+    /// operation was called and how long it took since the store was opened.
+    pub fn stats(&self) -> Result<StoreStats> {
+        store_stats(&self.dir, &self.map, &self.last_compaction, &self.operations, &self.compressor)
+    }
+
+    // Runs an operation and records how long it took.
+    fn timed<T>(&self, operation: Operation, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started = Instant::now();
+        let result = f();
+        self.operations.record(operation, started, &result);
+        result
+    }
+
+    /// Removes a key-value pair.
+    ///
+    /// This is a convenience wrapper around [`KvStore::remove_bytes`].
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Removes a key-value pair.
+    ///
+    /// Errors if the key does not exist. This operation is persisted to the log.
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.timed(Operation::Remove, || {
+            let ticket = {
+                let mut writer = self.lock_writer()?;
+
+                // Enforce that the key must exist for a remove operation to be valid.
+                // Checking under the writer lock keeps a missing key from leaving a record in the log.
+                if self.locate(key)?.is_none() {
+                    return Err(KvsError::KeyNotFound);
+                }
+                self.append_removal(&mut writer, key)?
+            };
+
+            self.syncer.wait(ticket)?;
+            self.maybe_schedule_work();
+            Ok(())
+        })
+    }
+
+    /// Writes `new` to a key, or removes the key if `new` is `None`, but only if the key's
+    /// current value satisfies `condition`. The check and the write happen atomically.
+    ///
+    /// Errors with [`KvsError::ConditionFailed`], without writing anything, if it does not.
+    pub fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()> {
+        self.timed(Operation::WriteIf, || {
+            let ticket = {
+                let mut writer = self.lock_writer()?;
+
+                // Holding the writer lock keeps the key from changing between the check and the write.
+                let current = self.locate(&key)?;
+                let exists = current.is_some();
+                let satisfied = match (condition, current) {
+                    (Condition::Equals(expected), Some((pointer, file))) => read_value(&file, pointer, &key)? == expected,
+                    (Condition::Equals(_), None) => false,
+                    (Condition::Present, _) => exists,
+                    (Condition::Absent, _) => !exists,
+                };
+                if !satisfied {
+                    return Err(KvsError::ConditionFailed);
+                }
+
+                match new {
+                    Some(value) => self.append_value(&mut writer, key, value, None)?,
+                    None if exists => self.append_removal(&mut writer, &key)?,
+                    None => return Ok(()),
+                }
+            };
+
+            self.syncer.wait(ticket)?;
+            self.maybe_schedule_work();
+            Ok(())
+        })
+    }
+
+    /// Returns a read-only view of the store as it is now.
+    ///
+    /// Reads and scans through the snapshot all see the same state, no matter what is written
+    /// to the store afterwards. Versions of keys overwritten or removed after the snapshot was
+    /// taken are kept in memory until every snapshot that can see them has been dropped.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // Holding the writer lock makes sure every write up to the sequence number is in the index.
+        let _writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        let seq = self.seq.load(Ordering::SeqCst);
+        let mut history = self.history.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        *history.snapshots.entry(seq).or_default() += 1;
+
+        Ok(Snapshot {store: self.clone(), seq})
+    }
+
+    // Takes the writer lock, which every write holds while it appends to the log. Fails if the
+    // store was opened read-only.
+    fn lock_writer(&self) -> Result<WriterGuard<'_>> {
+        let writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        if writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(WriterGuard(writer))
+    }
+
+    // Assigns the sequence number of a new write. Must be called with the writer lock held.
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Keeps a version of a key that was replaced at sequence number `until` if an open
+    // snapshot can still see it. Must be called with the map lock held.
+    fn retire(&self, key: Vec<u8>, old: LogPointer, until: u64) -> Result<()> {
+        let mut history = self.history.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        if !history.is_visible(old.seq, until) {
+            return Ok(());
+        }
+        let file = self.segment_file(old.generation)?;
+        history.versions.entry(key).or_default().push(Version {pointer: old, until, file});
+        Ok(())
+    }
+
+    // Returns the location of a key that has not expired, along with the segment holding it.
+    fn locate(&self, key: &[u8]) -> Result<Option<(LogPointer, Arc<LogFile>)>> {
+        // The segment file is resolved while the lock is held, so compaction cannot remove it in between.
+        let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        match map.get(key) {
+            Some(&pointer) if pointer.is_live(now_millis()) => Ok(Some((pointer, self.segment_file(pointer.generation)?))),
+            _ => Ok(None),
+        }
+    }
+
+    // Appends a record removing a key and drops the key from the index. Returns the ticket to
+    // wait on before the removal may be acknowledged.
+    fn append_removal(&self, writer: &mut ActiveSegment, key: &[u8]) -> Result<u64> {
+        // Similar to `set`, log the removal command first for durability.
+        let pointer = writer.append(&Command::Remove {key: key.to_vec()}, self.next_seq())?;
+        let ticket = self.syncer.appended()?;
+
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        if let Some(old) = map.remove(key) {
+            self.uncompacted.fetch_add(old.share + pointer.len, Ordering::SeqCst);
+            self.retire(key.to_vec(), old, pointer.seq)?;
+        }
+        drop(map);
+
+        self.maybe_roll(writer)?;
+        Ok(ticket)
+    }
+
+    /// Applies every write in `batch` atomically.
+    ///
+    /// The batch is written to the log as a single record, so after a crash it is replayed
+    /// either completely or not at all. Errors with [`KvsError::KeyNotFound`], without writing
+    /// anything, if the batch removes a key that does not exist.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.timed(Operation::Batch, || {
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            let ticket = {
+                let mut writer = self.lock_writer()?;
+                self.append_batch(&mut writer, batch)?
+            };
+
+            self.syncer.wait(ticket)?;
+            self.maybe_schedule_work();
+            Ok(())
+        })
+    }
+
+    /// Starts a transaction that reads from a snapshot of the store and buffers its writes
+    /// until it is committed.
+    pub fn begin(&self) -> Result<Transaction> {
+        Ok(Transaction::new(self.clone(), self.snapshot()?))
+    }
+
+    // Applies the writes of a transaction that read `reads` and scanned `ranges` through a
+    // snapshot at `seq`. Fails with a conflict, writing nothing, if any of those keys was
+    // written after the snapshot was taken.
+    //
+    // Reads are validated and writes applied under the writer lock, so the transaction takes
+    // effect as if it ran entirely at the moment it commits, which makes it serializable.
+    pub(crate) fn commit_transaction(
+        &self,
+        seq: u64,
+        reads: &BTreeSet<Vec<u8>>,
+        ranges: &[KeyRange],
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let ticket = {
+            let mut writer = self.lock_writer()?;
+            {
+                let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+                let history = self.history.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+
+                // A key was written after the snapshot if the index holds a newer version, or if
+                // the version the snapshot sees has been replaced since. The latter also covers
+                // keys that were removed.
+                let written_since = |key: &[u8]| {
+                    map.get(key).is_some_and(|pointer| pointer.seq > seq)
+                        || history.versions.get(key).is_some_and(|versions| {
+                            versions.iter().any(|version| version.pointer.seq <= seq && seq < version.until)
+                        })
+                };
+                let range_written_since = |range: &KeyRange| {
+                    let bounds = (range.0.as_ref(), range.1.as_ref());
+                    !is_empty_range(range)
+                        && (map.range::<Vec<u8>, _>(bounds).any(|(_, pointer)| pointer.seq > seq)
+                            || history.versions.range::<Vec<u8>, _>(bounds).any(|(key, _)| written_since(key)))
+                };
+
+                if reads.iter().any(|key| written_since(key)) || ranges.iter().any(range_written_since) {
+                    return Err(KvsError::Conflict);
+                }
+            }
+
+            if batch.is_empty() {
+                return Ok(());
+            }
+            // The transaction only removes keys its snapshot holds, so one that is missing now
+            // expired or was swept since. Retrying is then right, as a new snapshot no longer has it.
+            self.append_batch(&mut writer, batch).map_err(|e| match e {
+                KvsError::KeyNotFound => KvsError::Conflict,
+                e => e,
+            })?
+        };
+
+        self.syncer.wait(ticket)?;
+        self.maybe_schedule_work();
+        Ok(())
+    }
+
+    // Appends a batch as a single record and applies it to the index. Returns the ticket to
+    // wait on before the batch may be acknowledged.
+    fn append_batch(&self, writer: &mut ActiveSegment, batch: WriteBatch) -> Result<u64> {
+        {
+            let now = now_millis();
+            let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            batch.check_removes(|key| map.get(key).is_some_and(|pointer| pointer.is_live(now)))?;
+        }
+
+        let commands = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set {key, value} => Command::Set {key, value},
+                BatchOp::Remove {key} => Command::Remove {key},
+            })
+            .collect();
+        let cmd = Command::Batch {commands};
+        // Every write in the batch shares one sequence number, so snapshots see all or none of them.
+        let pointer = writer.append(&cmd, self.next_seq())?;
+        let ticket = self.syncer.appended()?;
+
+        // All keys of the batch become visible at once, as the map is updated under a single lock.
+        let Command::Batch {commands} = cmd else {
+            unreachable!("the command was built as a batch");
+        };
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let mut superseded = Vec::new();
+        let stale = apply_batch(&mut map, pointer, &commands, |key, old| superseded.push((key.to_vec(), old)));
+        self.uncompacted.fetch_add(stale, Ordering::SeqCst);
+        for (key, old) in superseded {
+            self.retire(key, old, pointer.seq)?;
+        }
+        drop(map);
+
+        self.maybe_roll(writer)?;
+        Ok(ticket)
+    }
+
+    /// Rewrites all sealed segments into a single segment that only contains the latest
+    /// record of each live key.
+    ///
+    /// The active segment is sealed first, so reads and writes continue against a fresh
+    /// segment while the compacted one is written. Once it is safely on disk, the segments
+    /// it replaces are deleted.
+    pub fn compact(&self) -> Result<()> {
+        let _guard = self.compaction_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+
+        // Take a snapshot of the live entries. Because the map matches the log exactly while the
+        // writer lock is held, the snapshot covers precisely the segments sealed here.
+        let (snapshot, compaction_generation, snapshot_uncompacted, base) = {
+            let mut writer = self.lock_writer()?;
+            // Reserve the next generation for the compacted segment and move writes past it.
+            let compaction_generation = writer.generation + 1;
+            self.roll(&mut writer, compaction_generation + 1)?;
+            let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            let base = Stamp {seq: self.seq.load(Ordering::SeqCst), timestamp: now_millis()};
+            (map.clone(), compaction_generation, self.uncompacted.load(Ordering::SeqCst), base)
+        };
+
+        // Copy the latest record of every live key into the compacted segment. A batch record
+        // shared by several live keys is copied once, with only the writes of those keys.
+        // Records keep their order from the log, so a batch that also sets a key overwritten
+        // later is replayed before the overwrite.
+        let mut snapshot: Vec<_> = snapshot.into_iter().collect();
+        snapshot.sort_unstable_by_key(|(_, pointer)| (pointer.generation, pointer.offset));
+
+        // The compacted segment is encrypted with the current key. Records are copied as they
+        // are if they already were, and re-encrypted otherwise. Rewritten records were counted
+        // in the compression stats when they were first written, so they are compressed apart.
+        let cipher = self.keyring.current();
+        let compressor = Compressor::new(self.options.compression, self.options.compression_min_size);
+        let compaction_path = segment_path(&self.dir, compaction_generation).with_extension(COMPACTION_EXTENSION);
+        let mut compacted = BufWriter::new(File::create(&compaction_path)?);
+        let creation_options = CreationOptions::new(&self.options);
+        // Overwritten and removed versions are left behind, so the segment only holds the
+        // state of the store as of the snapshot.
+        let header = FileHeader {base: Some(base), ..FileHeader::new(creation_options, cipher.map(RecordCipher::key_id))};
+        let header = header.encode()?;
+        compacted.write_all(&header)?;
+
+        let mut relocated = HashMap::with_capacity(snapshot.len());
+        let mut hints = Vec::new();
+        let mut offset = header.len() as u64;
+        for keys in snapshot.chunk_by(|(_, a), (_, b)| (a.generation, a.offset) == (b.generation, b.offset)) {
+            let pointer = keys[0].1;
+            let file = self.segment_file(pointer.generation)?;
+            let mut record = vec![0; pointer.len as usize];
+            read_exact_at(&file.file, &mut record, pointer.offset)?;
+            let payload = decode_record(&record, file.cipher.as_ref()).ok_or(KvsError::Corruption {
+                segment: pointer.generation,
+                offset: pointer.offset,
+            })?;
+            let mut cmd = bincode::deserialize(&payload)?;
+            let stamp = read_stamp(&record).unwrap_or(Stamp {seq: pointer.seq, timestamp: 0});
+            let mut rewrite = file.cipher.as_ref().map(RecordCipher::key_id) != cipher.map(RecordCipher::key_id);
+            if let Command::Batch {commands} = &mut cmd {
+                // The other writes of the batch were overwritten, removed or swept since, and
+                // copying them would bring their keys back once the store is reopened.
+                let written = commands.len();
+                *commands = live_batch_commands(mem::take(commands), keys);
+                rewrite |= commands.len() != written;
+            }
+            if rewrite {
+                record = encode_record(&cmd, stamp, &compressor, cipher)?;
+            }
+            compacted.write_all(&record)?;
+
+            // Each key of a batch accounts for an equal share of it, like in `apply_batch`.
+            let len = record.len() as u64;
+            let share = match &cmd {
+                Command::Batch {commands} => len / commands.len().max(1) as u64,
+                _ => len,
+            };
+            hints.push(HintRecord::new(&cmd, offset, len, stamp.seq));
+            for (key, pointer) in keys {
+                let new_pointer = LogPointer {generation: compaction_generation, offset, len, share, ..*pointer};
+                relocated.insert(key.clone(), (*pointer, new_pointer));
+            }
+            offset += len;
+        }
+        let compacted = compacted.into_inner().map_err(|e| e.into_error())?;
+        compacted.sync_all()?;
+
+        // The compacted segment only becomes visible once it is complete. A crash before the
+        // rename leaves the old segments untouched; a crash after it, while the old segments
+        // are being removed, only leaves records that the compacted segment supersedes.
+        let segment = segment_path(&self.dir, compaction_generation);
+        fs::rename(&compaction_path, &segment)?;
+        seal_segment(&segment)?;
+        sync_dir(&self.dir)?;
+        if let Err(e) = write_hints(&self.dir, compaction_generation, &hints, cipher) {
+            warn!("Failed to write the hint file of segment {}: {}", compaction_generation, e);
+        }
+
+        {
+            // Point the index at the compacted segment. Keys that were overwritten or removed
+            // in the meantime are left alone, but the stale record they leave behind in the
+            // compacted segment replaces the one already counted as stale, which a re-encrypted
+            // record may differ in size from.
+            let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            for (key, (old_pointer, new_pointer)) in relocated {
+                match map.get_mut(&key) {
+                    Some(pointer) if *pointer == old_pointer => *pointer = new_pointer,
+                    _ => {
+                        self.uncompacted.fetch_add(new_pointer.share, Ordering::SeqCst);
+                        self.uncompacted.fetch_sub(old_pointer.share, Ordering::SeqCst);
+                    }
+                }
+            }
+
+            // Nothing refers to the old segments anymore. Readers that resolved a file before
+            // the index was updated keep their own handle to it.
+            let mut readers = self.readers.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            readers.retain(|&generation, _| generation >= compaction_generation);
+        }
+
+        remove_covered(&self.dir, compaction_generation)?;
+
+        // Every stale record known at snapshot time lived in one of the removed segments.
+        self.uncompacted.fetch_sub(snapshot_uncompacted, Ordering::SeqCst);
+        self.last_compaction.store(now_millis(), Ordering::SeqCst);
+        info!("Compacted log into segment {}, reclaimed {} bytes", compaction_generation, snapshot_uncompacted);
+
+        Ok(())
+    }
+
+    /// Writes every key in the index to a checkpoint file, then deletes the segments it covers.
+    ///
+    /// The active segment is sealed first, so reads and writes continue against a fresh segment
+    /// while the checkpoint is written. Unlike compaction, which copies the latest records as
+    /// they are, a checkpoint holds one record per key, whether it was written alone or in a batch.
+    pub fn checkpoint(&self) -> Result<()> {
+        // Checkpoints remove segments just like compactions, so the two never run at once.
+        let _guard = self.compaction_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+
+        let (snapshot, checkpoint_generation, snapshot_uncompacted, base) = {
+            let mut writer = self.lock_writer()?;
+            // Reserve the next generation for the checkpoint and move writes past it.
+            let checkpoint_generation = writer.generation + 1;
+            self.roll(&mut writer, checkpoint_generation + 1)?;
+            let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            let base = Stamp {seq: self.seq.load(Ordering::SeqCst), timestamp: now_millis()};
+            (map.clone(), checkpoint_generation, self.uncompacted.load(Ordering::SeqCst), base)
+        };
+
+        let created_at = base.timestamp;
+        let cipher = self.keyring.current();
+        // Like in a compaction, copied records do not count towards the compression stats again.
+        let compressor = Compressor::new(self.options.compression, self.options.compression_min_size);
+        let path = checkpoint_path(&self.dir, checkpoint_generation);
+        let tmp_path = path.with_extension(CHECKPOINT_TMP_EXTENSION);
+        let mut checkpoint = BufWriter::new(File::create(&tmp_path)?);
+        let key_id = cipher.map(RecordCipher::key_id);
+        let header = FileHeader {base: Some(base), ..FileHeader::new(CreationOptions::new(&self.options), key_id)};
+        let mut header = header.encode()?;
+        let checkpoint_header = CheckpointHeader {
+            generation: checkpoint_generation,
+            keys: snapshot.len() as u64,
+            created_at,
+            key_id,
+        };
+        header.extend(frame_record(&bincode::serialize(&checkpoint_header)?, 0)?);
+        checkpoint.write_all(&header)?;
+
+        // Expired keys are written too, so every pointer in the index can be moved to the checkpoint.
+        let mut relocated = Vec::with_capacity(snapshot.len());
+        let mut offset = header.len() as u64;
+        for (key, pointer) in snapshot {
+            let file = self.segment_file(pointer.generation)?;
+            let value = read_value(&file, pointer, &key)?;
+            let cmd = match pointer.expires_at {
+                Some(expires_at) => Command::SetWithExpiry {key: key.clone(), value, expires_at},
+                None => Command::Set {key: key.clone(), value},
+            };
+            // The index does not keep when a key was written, so the checkpoint's time stands in.
+            let stamp = Stamp {seq: pointer.seq, timestamp: created_at};
+            let record = encode_record(&cmd, stamp, &compressor, cipher)?;
+            checkpoint.write_all(&record)?;
+
+            let len = record.len() as u64;
+            relocated.push((key, pointer, LogPointer {generation: checkpoint_generation, offset, len, share: len, ..pointer}));
+            offset += len;
+        }
+        let checkpoint = checkpoint.into_inner().map_err(|e| e.into_error())?;
+        checkpoint.sync_all()?;
+
+        // Like a compacted segment, the checkpoint only becomes visible once it is complete.
+        fs::rename(&tmp_path, &path)?;
+        seal_segment(&path)?;
+        sync_dir(&self.dir)?;
+
+        {
+            let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            for (key, old_pointer, new_pointer) in relocated {
+                match map.get_mut(&key) {
+                    Some(pointer) if *pointer == old_pointer => *pointer = new_pointer,
+                    // The key was overwritten or removed in the meantime, which counted its old
+                    // record as stale. Its record in the checkpoint is the stale one now.
+                    _ => {
+                        self.uncompacted.fetch_add(new_pointer.len, Ordering::SeqCst);
+                        self.uncompacted.fetch_sub(old_pointer.share, Ordering::SeqCst);
+                    }
+                }
+            }
+
+            let mut readers = self.readers.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            readers.retain(|&generation, _| generation >= checkpoint_generation);
+        }
+
+        remove_covered(&self.dir, checkpoint_generation)?;
+
+        self.uncompacted.fetch_sub(snapshot_uncompacted, Ordering::SeqCst);
+        self.last_checkpoint.store(created_at, Ordering::SeqCst);
+        info!("Wrote checkpoint {} of {} bytes", checkpoint_generation, offset);
+
+        Ok(())
+    }
+
+    // Returns a read-only handle to the segment with the given generation, opening it if needed.
+    fn segment_file(&self, generation: u64) -> Result<Arc<LogFile>> {
+        {
+            let readers = self.readers.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            if let Some(file) = readers.get(&generation) {
+                return Ok(Arc::clone(file));
+            }
+        }
+
+        let mut readers = self.readers.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let file = match readers.get(&generation) {
+            Some(file) => Arc::clone(file),
+            None => {
+                // The generation of a checkpoint is never used by a segment, and keys loaded from
+                // it point into the checkpoint itself.
+                let path = segment_path(&self.dir, generation);
+                let (file, key_id) = match File::open(&path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        let path = checkpoint_path(&self.dir, generation);
+                        let file = File::open(&path)?;
+                        let (_, header, _) = read_checkpoint_header(&file, &path, generation)?;
+                        (file, header.key_id)
+                    }
+                    file => {
+                        let file = file?;
+                        let key_id = read_file_header(&file, &path)?.and_then(|(header, _)| header.key_id);
+                        (file, key_id)
+                    }
+                };
+                let cipher = key_id.map(|key_id| self.keyring.cipher(key_id).cloned()).transpose()?;
+                let file = Arc::new(LogFile {file, cipher});
+                readers.insert(generation, Arc::clone(&file));
+                file
+            }
+        };
+        Ok(file)
+    }
+
+    // Seals the active segment and starts a new one if it has reached the size limit.
+    fn maybe_roll(&self, writer: &mut ActiveSegment) -> Result<()> {
+        if writer.len >= self.options.segment_size {
+            let next_generation = writer.generation + 1;
+            self.roll(writer, next_generation)?;
+        }
+        Ok(())
+    }
+
+    // Seals the active segment and continues with a new segment of the given generation.
+    fn roll(&self, writer: &mut ActiveSegment, generation: u64) -> Result<()> {
+        writer.roll(&self.dir, generation)?;
+        self.syncer.segment_rolled(Arc::clone(&writer.file))
+    }
+
+    // Starts a thread that periodically drops expired keys from the index for as long as the
+    // store is open. It only holds weak references while idle, so it does not keep the store alive.
+    fn spawn_sweeper(&self) {
+        let map = Arc::downgrade(&self.map);
+        let writer = Arc::downgrade(&self.writer);
+        let uncompacted = Arc::downgrade(&self.uncompacted);
+        let interval = self.options.sweep_interval;
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let (Some(map), Some(writer), Some(uncompacted)) = (map.upgrade(), writer.upgrade(), uncompacted.upgrade()) else {
+                return;
+            };
+            if let Err(e) = sweep_expired(&map, &writer, &uncompacted) {
+                error!("Sweeping expired keys failed: {}", e);
+            }
+        });
+    }
+
+    // Starts a thread that logs the statistics of the store every configured interval, if one
+    // is set. Like the sweeper, it does not keep the store alive.
+    fn spawn_stats_logger(&self) {
+        let Some(interval) = self.options.stats_interval else {
+            return;
+        };
+        let dir = Arc::downgrade(&self.dir);
+        let map = Arc::downgrade(&self.map);
+        let last_compaction = Arc::downgrade(&self.last_compaction);
+        let operations = Arc::downgrade(&self.operations);
+        let compressor = Arc::downgrade(&self.compressor);
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let (Some(dir), Some(map), Some(last_compaction), Some(operations), Some(compressor)) =
+                (dir.upgrade(), map.upgrade(), last_compaction.upgrade(), operations.upgrade(), compressor.upgrade())
+            else {
+                return;
+            };
+            match store_stats(&dir, &map, &last_compaction, &operations, &compressor) {
+                Ok(stats) => {
+                    info!(
+                        "{} keys, {} of {} bytes live in {} segments, compression ratio {:.2}",
+                        stats.keys,
+                        stats.live_bytes,
+                        stats.total_bytes,
+                        stats.segments,
+                        stats.compression.ratio()
+                    );
+                    for operation in stats.operations.iter().filter(|operation| operation.count > 0) {
+                        info!("{}", operation);
+                    }
+                }
+                Err(e) => error!("Collecting statistics failed: {}", e),
+            }
+        });
+    }
+
+    // Starts any background work that is due after a write.
+    fn maybe_schedule_work(&self) {
+        self.maybe_compact();
+        self.maybe_checkpoint();
+    }
+
+    // Spawns a background compaction if the stale bytes exceed the configured threshold.
+    fn maybe_compact(&self) {
+        if self.uncompacted.load(Ordering::SeqCst) <= self.options.compaction_threshold {
+            return;
+        }
+        if self.compaction_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let store = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = store.compact() {
+                error!("Log compaction failed: {}", e);
+            }
+            store.compaction_scheduled.store(false, Ordering::SeqCst);
+            // Writes made while compacting may have pushed the log past the threshold again.
+            store.maybe_compact();
+        });
+    }
+
+    // Spawns a background checkpoint if checkpoints are enabled and the configured interval
+    // has passed since the last one.
+    fn maybe_checkpoint(&self) {
+        let Some(interval) = self.options.checkpoint_interval else {
+            return;
+        };
+        let elapsed = now_millis().saturating_sub(self.last_checkpoint.load(Ordering::SeqCst));
+        if u128::from(elapsed) < interval.as_millis() {
+            return;
+        }
+        if self.checkpoint_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let store = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = store.checkpoint() {
+                error!("Checkpoint failed: {}", e);
+                // Wait a full interval before trying again.
+                store.last_checkpoint.store(now_millis(), Ordering::SeqCst);
+            }
+            store.checkpoint_scheduled.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// A read-only, point-in-time view of a [`KvStore`], created by [`KvStore::snapshot`].
+///
+/// The view is fixed at the sequence number of the last write made before the snapshot was
+/// taken. Keys that expire are still hidden once their time has passed.
+pub struct Snapshot {
+    store: KvStore,
+    seq: u64,
+}
+
+impl Snapshot {
+    /// Returns the sequence number of the last write visible through the snapshot.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value a key had when the snapshot was taken.
+    ///
+    /// Errors with [`KvsError::InvalidUtf8`] if the value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.as_bytes())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Gets the bytes a key had when the snapshot was taken.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let located = {
+            let map = self.store.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            let history = self.store.history.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            self.locate(&map, &history, key)?
+        };
+
+        match located {
+            Some((pointer, file)) => Ok(Some(read_value(&file, pointer, key)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the key-value pairs whose key fell within `range` when the snapshot was taken,
+    /// limited and ordered as set by `options`.
+    pub fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let located = {
+            let map = self.store.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            let history = self.store.history.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+
+            // A key visible to the snapshot is either still in the index or has an older version.
+            let bounds = (range.start_bound(), range.end_bound());
+            let keys: BTreeSet<&Vec<u8>> = map
+                .range::<Vec<u8>, _>(bounds)
+                .map(|(key, _)| key)
+                .chain(history.versions.range::<Vec<u8>, _>(bounds).map(|(key, _)| key))
+                .collect();
+            let keys: Box<dyn Iterator<Item = &&Vec<u8>>> = if options.reverse {
+                Box::new(keys.iter().rev())
+            } else {
+                Box::new(keys.iter())
+            };
+
+            let mut located = Vec::new();
+            for key in keys {
+                if located.len() >= options.limit.unwrap_or(usize::MAX) {
+                    break;
+                }
+                if let Some((pointer, file)) = self.locate(&map, &history, key)? {
+                    located.push(((*key).clone(), pointer, file));
+                }
+            }
+            located
+        };
+
+        located
+            .into_iter()
+            .map(|(key, pointer, file)| {
+                let value = read_value(&file, pointer, &key)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Returns the key-value pairs whose key started with `prefix` when the snapshot was taken.
+    pub fn scan_prefix(&self, prefix: &[u8], options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes(prefix_range(prefix), options)
+    }
+
+    // Finds the version of a key visible to the snapshot, if it has one that has not expired.
+    fn locate(
+        &self,
+        map: &BTreeMap<Vec<u8>, LogPointer>,
+        history: &History,
+        key: &[u8],
+    ) -> Result<Option<(LogPointer, Arc<LogFile>)>> {
+        let located = match map.get(key) {
+            Some(&pointer) if pointer.seq <= self.seq => Some((pointer, self.store.segment_file(pointer.generation)?)),
+            _ => history.versions.get(key).and_then(|versions| {
+                versions
+                    .iter()
+                    .find(|version| version.pointer.seq <= self.seq && self.seq < version.until)
+                    .map(|version| (version.pointer, Arc::clone(&version.file)))
+            }),
+        };
+        Ok(located.filter(|(pointer, _)| pointer.is_live(now_millis())))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let Ok(mut history) = self.store.history.lock() else {
+            return;
+        };
+        if let Some(count) = history.snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                history.snapshots.remove(&self.seq);
+            }
+        }
+
+        // Forget the versions no remaining snapshot can see.
+        let History {snapshots, versions} = &mut *history;
+        versions.retain(|_, versions| {
+            versions.retain(|version| snapshots.range(version.pointer.seq..version.until).next().is_some());
+            !versions.is_empty()
+        });
+    }
+}
+
+/// An iterator over the results of a scan, created by [`KvStore::scan_iter`].
+///
+/// Each value is read from the log when the iterator reaches it. A scan sees the keys that
+/// existed when it started, even if they are overwritten or removed while it runs.
+pub struct ScanIter {
+    entries: std::vec::IntoIter<(Vec<u8>, LogPointer, Arc<LogFile>)>,
+}
+
+impl Iterator for ScanIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, pointer, file) = self.entries.next()?;
+        Some(read_value(&file, pointer, &key).map(|value| (key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        KvStore::set_bytes(self, key, value)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        KvStore::get_bytes(self, key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        KvStore::remove_bytes(self, key)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        KvStore::scan_bytes(self, range, options)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        KvStore::set_with_ttl(self, key, value, ttl)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        KvStore::write(self, batch)
+    }
+
+    fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()> {
+        KvStore::write_if(self, key, condition, new)
+    }
+
+    fn begin(&self) -> Result<Transaction> {
+        KvStore::begin(self)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        KvStore::snapshot(self)
+    }
+
+    fn ttl(&self, key: &[u8]) -> Result<Option<Duration>> {
+        KvStore::ttl(self, key)
+    }
+
+    fn backup_to(&self, dir: &Path) -> Result<()> {
+        self.backup_to(dir)
+    }
+
+    fn restore_from(&self, dir: &Path) -> Result<()> {
+        self.restore_from(dir)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvStore::flush(self)
+    }
+}
+
+// Removes expired keys from the index and counts their records as stale. No removal record
+// is written: the latest record of each swept key is its expiring `Set`, so replaying the
+// log finds the key expired again.
+fn sweep_expired(
+    map: &RwLock<BTreeMap<Vec<u8>, LogPointer>>,
+    writer: &Mutex<Option<ActiveSegment>>,
+    uncompacted: &AtomicU64,
+) -> Result<()> {
+    let now = now_millis();
+    let expired: Vec<Vec<u8>> = {
+        let map = map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        map.iter()
+            .filter(|(_, pointer)| !pointer.is_live(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    };
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    // The index is only changed under the writer lock, like every other update.
+    let _writer = writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+    let mut map = map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+    let mut swept = 0;
+    for key in &expired {
+        // The key may have been set again since the list was built.
+        if let Some(pointer) = map.get(key)
+            && !pointer.is_live(now)
+        {
+            uncompacted.fetch_add(pointer.share, Ordering::SeqCst);
+            map.remove(key);
+            swept += 1;
+        }
+    }
+    debug!("Swept {} expired keys", swept);
+    Ok(())
+}
+
+// Returns the last write of each of the given keys in the commands of a batch, in batch order.
+fn live_batch_commands(commands: Vec<Command>, keys: &[(Vec<u8>, LogPointer)]) -> Vec<Command> {
+    let mut wanted: HashSet<&[u8]> = keys.iter().map(|(key, _)| key.as_slice()).collect();
+    let mut live: Vec<_> = commands
+        .into_iter()
+        .rev()
+        .filter(|cmd| match cmd {
+            Command::Set {key, ..} | Command::SetWithExpiry {key, ..} => wanted.remove(key.as_slice()),
+            _ => false,
+        })
+        .collect();
+    live.reverse();
+    live
+}
+
+// Points the index at the keys set by a batch record and drops the keys it removes. Each
+// command accounts for an equal share of the record. Every pointer the batch replaces is
+// passed to `superseded`. Returns the number of bytes made stale.
+fn apply_batch(
+    map: &mut BTreeMap<Vec<u8>, LogPointer>,
+    pointer: LogPointer,
+    commands: &[Command],
+    mut superseded: impl FnMut(&[u8], LogPointer),
+) -> u64 {
+    let share = pointer.len / commands.len().max(1) as u64;
+    // Whatever is left over from dividing the record evenly belongs to no key.
+    let mut stale = pointer.len - share * commands.len() as u64;
+
+    for cmd in commands {
+        match cmd {
+            Command::Set {key, ..} | Command::SetWithExpiry {key, ..} => {
+                let pointer = LogPointer {share, expires_at: cmd.expires_at(), ..pointer};
+                if let Some(old) = map.insert(key.clone(), pointer) {
+                    stale += old.share;
+                    superseded(key, old);
+                }
+            }
+            Command::Remove {key} => {
+                if let Some(old) = map.remove(key) {
+                    stale += old.share;
+                    superseded(key, old);
+                }
+                stale += share;
+            }
+            // Batches are never nested.
+            Command::Batch {..} => stale += share,
+        }
+    }
+    stale
+}
+
+// Collects the statistics of the store in the given directory, with the given index.
+fn store_stats(
+    dir: &Path,
+    map: &RwLock<BTreeMap<Vec<u8>, LogPointer>>,
+    last_compaction: &AtomicU64,
+    operations: &OperationCounters,
+    compressor: &Compressor,
+) -> Result<StoreStats> {
+    let now = now_millis();
+    let (keys, live_bytes) = {
+        let map = map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        map.values()
+            .filter(|pointer| pointer.is_live(now))
+            .fold((0, 0), |(keys, bytes), pointer| (keys + 1, bytes + pointer.share))
+    };
+
+    let segments = sorted_generations(dir)?;
+    let checkpoints = sorted_files(dir, CHECKPOINT_EXTENSION)?;
+    let paths = segments.iter().map(|&generation| segment_path(dir, generation));
+    let mut total_bytes = 0;
+    for path in paths.chain(checkpoints.iter().map(|&generation| checkpoint_path(dir, generation))) {
+        match fs::metadata(path) {
+            Ok(metadata) => total_bytes += metadata.len(),
+            // Compaction may have removed the file since the directory was listed.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let last_compaction = match last_compaction.load(Ordering::SeqCst) {
+        0 => None,
+        millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+    };
+    Ok(StoreStats {
+        keys,
+        total_bytes,
+        live_bytes,
+        segments: segments.len() as u64,
+        last_compaction,
+        compression: compressor.stats(),
+        operations: operations.stats(),
+        filters: None,
+    })
+}
+
+// Returns the expiry time, in milliseconds since the UNIX epoch, of a key that lives for `ttl`.
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+// Serializes a command, compresses it if the compressor chooses to, and frames it as a log
+// record with the given stamp, encrypted with `cipher` if one is given.
+//
+// The header holds a CRC32 of everything after it, the payload length and a flags byte,
+// all little-endian. The low bits of the flags byte name the codec the payload is compressed
+// with, the next one is set if it is encrypted, the one after marks a segment header, and the
+// last one a record whose payload starts with its stamp.
+fn encode_record(cmd: &Command, stamp: Stamp, compressor: &Compressor, cipher: Option<&RecordCipher>) -> Result<Vec<u8>> {
+    let (flags, payload) = compressor.compress(bincode::serialize(cmd)?)?;
+    stamp_record(&frame_payload(payload, flags, cipher)?, stamp)
+}
+
+// Puts a stamp in front of the payload of a framed record that has none. The stamp is not
+// encrypted, so records can be stamped without their key.
+fn stamp_record(record: &[u8], stamp: Stamp) -> Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(STAMP_LEN + record.len() - HEADER_LEN as usize);
+    payload.extend_from_slice(&stamp.seq.to_le_bytes());
+    payload.extend_from_slice(&stamp.timestamp.to_le_bytes());
+    payload.extend_from_slice(&record[HEADER_LEN as usize..]);
+    frame_record(&payload, record[8] | STAMPED_FLAG)
+}
+
+// Returns the stamp of a framed record that passed its checksum, if it has one.
+pub(crate) fn read_stamp(record: &[u8]) -> Option<Stamp> {
+    if record[8] & STAMPED_FLAG == 0 {
+        return None;
+    }
+    let field = |at: usize| u64::from_le_bytes(record[at..at + 8].try_into().unwrap());
+    Some(Stamp {seq: field(HEADER_LEN as usize), timestamp: field(HEADER_LEN as usize + 8)})
+}
+
+// Frames a serialized payload as a log record with the given flags, encrypting it first if a
+// cipher is given.
+fn frame_payload(payload: Vec<u8>, flags: u8, cipher: Option<&RecordCipher>) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => frame_record(&cipher.encrypt(flags | ENCRYPTED_FLAG, &payload)?, flags | ENCRYPTED_FLAG),
+        None => frame_record(&payload, flags),
+    }
+}
+
+// Frames an already serialized payload as a log record with the given flags.
+pub(crate) fn frame_record(payload: &[u8], flags: u8) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| KvsError::Internal(format!("Record of {} bytes is too large", payload.len())))?;
+
+    let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.push(flags);
+    record.extend_from_slice(payload);
+
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+// Verifies a framed record and returns its decrypted and decompressed payload, or `None` if it
+// is damaged, or encrypted with a key other than `cipher`.
+pub(crate) fn decode_record<'a>(record: &'a [u8], cipher: Option<&RecordCipher>) -> Option<Cow<'a, [u8]>> {
+    if (record.len() as u64) < HEADER_LEN {
+        return None;
+    }
+    let crc = u32::from_le_bytes(record[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(record[4..8].try_into().unwrap()) as u64;
+    let flags = record[8];
+    let known_flags = CODEC_MASK | ENCRYPTED_FLAG | HEADER_FLAG | STAMPED_FLAG;
+    if crc != crc32fast::hash(&record[4..]) || HEADER_LEN + len != record.len() as u64 || flags & !known_flags != 0 {
+        return None;
+    }
+
+    // The stamp was added after the rest of the record was encoded.
+    let mut payload = &record[HEADER_LEN as usize..];
+    if flags & STAMPED_FLAG != 0 {
+        payload = payload.get(STAMP_LEN..)?;
+    }
+    let flags = flags & !STAMPED_FLAG;
+    if flags & ENCRYPTED_FLAG == 0 {
+        return decompress(flags, payload);
+    }
+    let decrypted = cipher?.decrypt(flags, payload)?;
+    decompress(flags, &decrypted).map(|payload| Cow::Owned(payload.into_owned()))
+}
+
+// Reads the framed record at the given offset of a file, or returns `None` if the file ends
+// before the record does.
+pub(crate) fn read_frame_at(file: &File, offset: u64) -> Result<Option<Vec<u8>>> {
+    let file_len = file.metadata()?.len();
+    if offset + HEADER_LEN > file_len {
+        return Ok(None);
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    read_exact_at(file, &mut header, offset)?;
+    let len = HEADER_LEN + u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+    if offset + len > file_len {
+        return Ok(None);
+    }
+
+    let mut record = vec![0; len as usize];
+    read_exact_at(file, &mut record, offset)?;
+    Ok(Some(record))
+}
+
+// Reads the magic number, format version and header record at the start of a segment or
+// checkpoint. Returns the header and the offset the file's records start at, or `None` if the
+// file is empty or was cut short while its header was being written.
+//
+// Errors with `KvsError::UpgradeRequired` if the file is in an older format, and with
+// `KvsError::UnknownFormat` if it is in none this version knows.
+pub(crate) fn read_file_header(file: &File, path: &Path) -> Result<Option<(FileHeader, u64)>> {
+    read_engine_file_header(file, path, ENGINE_NAME)
+}
+
+// Reads the file header like `read_file_header`, for a file written by the given engine.
+pub(crate) fn read_engine_file_header(file: &File, path: &Path, engine: &str) -> Result<Option<(FileHeader, u64)>> {
+    let file_len = file.metadata()?.len();
+    let mut prefix = vec![0; file_len.min(PREFIX_LEN) as usize];
+    read_exact_at(file, &mut prefix, 0)?;
+
+    if !FORMAT_MAGIC.starts_with(&prefix[..prefix.len().min(FORMAT_MAGIC.len())]) {
+        // Files from before there was a format version start with an intact record instead.
+        if read_frame_at(file, 0)?.is_some_and(|record| decode_record(&record, None).is_some()) {
+            return Err(KvsError::UpgradeRequired(1));
+        }
+        return Err(KvsError::UnknownFormat(format!("{} is not a data file", path.display())));
+    }
+    if file_len < PREFIX_LEN {
+        return Ok(None);
+    }
+    let version = u32::from_le_bytes(prefix[4..].try_into().unwrap());
+    if version < FORMAT_VERSION {
+        return Err(KvsError::UpgradeRequired(version));
+    }
+    if version != FORMAT_VERSION {
+        return Err(KvsError::UnknownFormat(format!("{} is in format version {}", path.display(), version)));
+    }
+
+    let Some(record) = read_frame_at(file, PREFIX_LEN)? else {
+        return Ok(None);
+    };
+    let Some(payload) = decode_record(&record, None) else {
+        return Ok(None);
+    };
+    let header: FileHeader = bincode::deserialize(&payload)?;
+    if header.engine != engine {
+        return Err(KvsError::EngineMismatch {requested: engine.to_owned(), existing: header.engine});
+    }
+    Ok(Some((header, PREFIX_LEN + record.len() as u64)))
+}
+
+// Rewrites a segment or checkpoint in an older format version in the current format: with
+// a file header in front, and every record stamped with the next sequence number after `seq`.
+// The file is replaced only once the new one is on disk.
+fn upgrade_file(path: &Path, generation: u64, version: u32, options: CreationOptions, seq: &mut u64) -> Result<()> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let corruption = |offset| KvsError::Corruption {segment: generation, offset};
+
+    // When the file was last modified is the closest there is to when it was created, and to
+    // when its records were written.
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_millis() as u64);
+    let mut header = FileHeader::new(options, None);
+    header.created_at = modified.unwrap_or(header.created_at);
+
+    // Files in format version 2 have a file header already, whose fields are kept.
+    let mut offset = 0;
+    if version >= 2 {
+        let record = read_frame_at(&file, PREFIX_LEN)?.ok_or_else(|| corruption(PREFIX_LEN))?;
+        let payload = decode_record(&record, None).ok_or_else(|| corruption(PREFIX_LEN))?;
+        let legacy: LegacyFileHeader = bincode::deserialize(&payload)?;
+        header = FileHeader {
+            engine: legacy.engine,
+            created_at: legacy.created_at,
+            options: legacy.options,
+            key_id: legacy.key_id,
+            base: None,
+        };
+        offset = PREFIX_LEN + record.len() as u64;
+    }
+
+    // The header of a checkpoint is kept as it is. In format version 1, it named the key the
+    // checkpoint is encrypted with, as did a header record that encrypted segments started with.
+    let mut checkpoint_header = None;
+    if path.extension() == Some(OsStr::new(CHECKPOINT_EXTENSION)) {
+        let record = read_frame_at(&file, offset)?.ok_or_else(|| corruption(offset))?;
+        let payload = decode_record(&record, None).ok_or_else(|| corruption(offset))?;
+        let legacy: CheckpointHeader = bincode::deserialize(&payload)?;
+        header.key_id = header.key_id.or(legacy.key_id);
+        offset += record.len() as u64;
+        checkpoint_header = Some(record);
+    } else if version == 1
+        && let Some(record) = read_frame_at(&file, 0)?
+        && record[8] & HEADER_FLAG != 0
+    {
+        let payload = decode_record(&record, None).ok_or_else(|| corruption(0))?;
+        let legacy: LegacySegmentHeader = bincode::deserialize(&payload)?;
+        header.key_id = legacy.key_id;
+        offset = record.len() as u64;
+    }
+
+    // Find the records first, since the file header names the last one. Their checksums are
+    // verified without decrypting them, so no keys are needed. A torn write at the end of the
+    // active segment is dropped, like when the store is opened.
+    let file_len = metadata.len();
+    let mut records = Vec::new();
+    while let Some(record) = read_frame_at(&file, offset)? {
+        let len = record.len() as u64;
+        if u32::from_le_bytes(record[..4].try_into().unwrap()) != crc32fast::hash(&record[4..]) {
+            if offset + len == file_len && !metadata.permissions().readonly() {
+                break;
+            }
+            return Err(corruption(offset));
+        }
+        records.push(offset);
+        offset += len;
+    }
+
+    // What was overwritten before the upgrade is unknown, so the file only holds the state of
+    // the store as of its last record.
+    let timestamp = modified.unwrap_or(header.created_at);
+    let first_seq = *seq;
+    *seq += records.len() as u64;
+    header.base = Some(Stamp {seq: *seq, timestamp});
+
+    // Segments and checkpoints never share a generation, so the temporary names cannot clash.
+    let tmp_path = path.with_extension(UPGRADE_EXTENSION);
+    let mut upgraded = BufWriter::new(File::create(&tmp_path)?);
+    upgraded.write_all(&header.encode()?)?;
+    if let Some(record) = checkpoint_header {
+        upgraded.write_all(&record)?;
+    }
+    for (seq, offset) in (first_seq + 1..).zip(records) {
+        let record = read_frame_at(&file, offset)?.ok_or_else(|| corruption(offset))?;
+        upgraded.write_all(&stamp_record(&record, Stamp {seq, timestamp})?)?;
+    }
+    let upgraded = upgraded.into_inner().map_err(|e| e.into_error())?;
+    upgraded.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+    if metadata.permissions().readonly() {
+        seal_segment(path)?;
+    }
+    Ok(())
+}
+
+// Reads, verifies and deserializes the record at the given location.
+fn read_record(file: &LogFile, pointer: LogPointer) -> Result<Command> {
+    let mut buf = vec![0; pointer.len as usize];
+    read_exact_at(&file.file, &mut buf, pointer.offset)?;
+    let payload = decode_record(&buf, file.cipher.as_ref()).ok_or(KvsError::Corruption {
+        segment: pointer.generation,
+        offset: pointer.offset,
+    })?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+// Reads the value of `key` from the record at the given location, which either sets the
+// key or is a batch that does.
+fn read_value(file: &LogFile, pointer: LogPointer, key: &[u8]) -> Result<Vec<u8>> {
+    let cmd = read_record(file, pointer)?;
+    let value = match cmd {
+        Command::Set {value, ..} | Command::SetWithExpiry {value, ..} => Some(value),
+        Command::Batch {commands} => commands.into_iter().rev().find_map(|cmd| match cmd {
+            Command::Set {key: k, value} if k == key => Some(value),
+            _ => None,
+        }),
+        Command::Remove {..} => None,
+    };
+    value.ok_or_else(|| KvsError::Internal(format!("Expected a record setting the key at {:?}", pointer)))
+}
+
+// Reads the records of one segment in log order, starting at offset `start`, where `reader` is
+// positioned. Each command is passed to `apply`, along with the stamp, offset and length of its
+// record. Records are decrypted with `cipher`, if one is given.
+//
+// Returns the length of the segment up to the end of the last complete record. A record that
+// fails its checksum is treated as a torn write if it is the last one in the file, and as
+// corruption otherwise.
+pub(crate) fn read_segment(
+    generation: u64,
+    mut reader: impl Read,
+    start: u64,
+    file_len: u64,
+    cipher: Option<&RecordCipher>,
+    mut apply: impl FnMut(Command, Option<Stamp>, u64, u64) -> Result<()>,
+) -> Result<u64> {
+    let mut offset = start;
+    while file_len - offset >= HEADER_LEN {
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let len = HEADER_LEN + u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+        if offset + len > file_len {
+            break;
+        }
+
+        let mut record = vec![0; len as usize];
+        record[..HEADER_LEN as usize].copy_from_slice(&header);
+        reader.read_exact(&mut record[HEADER_LEN as usize..])?;
+        let Some(payload) = decode_record(&record, cipher) else {
+            if offset + len == file_len {
+                break;
+            }
+            return Err(KvsError::Corruption {segment: generation, offset});
+        };
+
+        // Only the first record of a file can be its header, which is read before the others.
+        if header[8] & HEADER_FLAG != 0 {
+            return Err(KvsError::Corruption {segment: generation, offset});
+        }
+        apply(bincode::deserialize(&payload)?, read_stamp(&record), offset, len)?;
+        offset += len;
+    }
+    Ok(offset)
+}
+
+// Reads the hint file of a sealed segment whose records span from `data_start` to
+// `segment_len`. Returns `None` if there is none, or if it is damaged or does not match the segment.
+fn read_hints(
+    dir: &Path,
+    generation: u64,
+    data_start: u64,
+    segment_len: u64,
+    cipher: Option<&RecordCipher>,
+) -> Option<Vec<HintRecord>> {
+    let path = segment_path(dir, generation).with_extension(HINT_EXTENSION);
+    let buf = fs::read(&path).ok()?;
+    let hints: Option<Vec<HintRecord>> =
+        decode_record(&buf, cipher).and_then(|payload| bincode::deserialize(&payload).ok());
+    let end = hints.as_ref().map(|hints| hints.last().map_or(data_start, |hint| hint.offset + hint.len));
+    if end != Some(segment_len) {
+        warn!("Ignoring damaged hint file {}", path.display());
+        return None;
+    }
+    hints
+}
+
+// Writes the hint file of a sealed segment, encrypted with the same key as the segment. It is
+// not synced: a hint file lost or torn in a crash fails its checksum, and the segment is
+// replayed instead.
+fn write_hints(dir: &Path, generation: u64, hints: &[HintRecord], cipher: Option<&RecordCipher>) -> Result<()> {
+    let path = segment_path(dir, generation).with_extension(HINT_EXTENSION);
+    fs::write(path, frame_payload(bincode::serialize(hints)?, 0, cipher)?)?;
+    Ok(())
+}
+
+// Removes the hint file of a segment, if it has one.
+fn remove_hints(dir: &Path, generation: u64) -> Result<()> {
+    match fs::remove_file(segment_path(dir, generation).with_extension(HINT_EXTENSION)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Reads the file header of a checkpoint and the checkpoint header that follows it, and returns
+// both along with the offset the checkpoint's records start at.
+pub(crate) fn read_checkpoint_header(file: &File, path: &Path, generation: u64) -> Result<(FileHeader, CheckpointHeader, u64)> {
+    let corruption = || KvsError::Corruption {segment: generation, offset: 0};
+    let (file_header, start) = read_file_header(file, path)?.ok_or_else(corruption)?;
+    let record = read_frame_at(file, start)?.ok_or_else(corruption)?;
+    let header: CheckpointHeader = bincode::deserialize(&decode_record(&record, None).ok_or_else(corruption)?)?;
+    if header.generation != generation {
+        return Err(corruption());
+    }
+    Ok((file_header, header, start + record.len() as u64))
+}
+
+// Reads the checkpoint with the given generation and returns the location of every key it
+// sets, along with its file header. Errors if the checkpoint is damaged or incomplete, or was
+// encrypted with a key that is not in the keyring.
+fn read_checkpoint(dir: &Path, generation: u64, keyring: &Keyring) -> Result<(FileHeader, Vec<IndexEntry>)> {
+    let path = checkpoint_path(dir, generation);
+    let file = File::open(&path)?;
+    let file_len = file.metadata()?.len();
+    let (file_header, header, header_len) = read_checkpoint_header(&file, &path, generation)?;
+    let cipher = header.key_id.map(|key_id| keyring.cipher(key_id)).transpose()?;
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(header_len))?;
+
+    let corruption = |offset| KvsError::Corruption {segment: generation, offset};
+    let mut entries = Vec::with_capacity(header.keys as usize);
+    let valid_len = read_segment(generation, reader, header_len, file_len, cipher, |cmd, stamp, offset, len| {
+        let expires_at = cmd.expires_at();
+        let seq = stamp.map_or(0, |stamp| stamp.seq);
+        match cmd {
+            Command::Set {key, ..} | Command::SetWithExpiry {key, ..} => {
+                entries.push((key, LogPointer {generation, offset, len, share: len, expires_at, seq}));
+                Ok(())
+            }
+            _ => Err(corruption(offset)),
+        }
+    })?;
+    if valid_len != file_len || entries.len() as u64 != header.keys {
+        return Err(corruption(valid_len));
+    }
+    Ok((file_header, entries))
+}
+
+// Hard-links a file to a new path, or copies it if the file system does not allow the link.
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Returns the length and CRC32 checksum of a file.
+fn checksum_file(path: &Path) -> Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok((len, hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+}
+
+// Reads the manifest of the backup in the given directory.
+fn read_manifest(dir: &Path) -> Result<BackupManifest> {
+    let buf = match fs::read(dir.join(BACKUP_MANIFEST)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(KvsError::InvalidBackup(format!("{} has no manifest", dir.display())));
+        }
+        buf => buf?,
+    };
+    decode_record(&buf, None)
+        .and_then(|payload| bincode::deserialize(&payload).ok())
+        .ok_or_else(|| KvsError::InvalidBackup(format!("the manifest in {} is damaged", dir.display())))
+}
+
+// Reads the manifest of a backup and checks that every file it lists is a segment or
+// checkpoint that matches its checksum and can be decrypted with the keys in `keyring`.
+fn verify_backup(dir: &Path, keyring: &Keyring) -> Result<BackupManifest> {
+    let manifest = read_manifest(dir)?;
+    for file in &manifest.files {
+        // The names end up in the data directory, so they must not point anywhere else.
+        let known = file.name.split_once('.').is_some_and(|(stem, extension)| {
+            stem.parse::<u64>().is_ok() && [SEGMENT_EXTENSION, CHECKPOINT_EXTENSION].contains(&extension)
+        });
+        if !known {
+            return Err(KvsError::InvalidBackup(format!("unexpected file {:?}", file.name)));
+        }
+
+        let path = dir.join(&file.name);
+        let checksum = match checksum_file(&path) {
+            Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(KvsError::InvalidBackup(format!("{} is missing", file.name)));
+            }
+            checksum => checksum?,
+        };
+        if checksum != (file.len, file.crc) {
+            return Err(KvsError::InvalidBackup(format!("{} does not match its checksum", file.name)));
+        }
+        if let Some((header, _)) = read_file_header(&File::open(&path)?, &path)?
+            && let Some(key_id) = header.key_id
+        {
+            keyring.cipher(key_id)?;
+        }
+    }
+    Ok(manifest)
+}
+
+// Moves the files of a backup staged for restoring into the data directory, replacing every
+// segment, hint file and checkpoint there. Does nothing unless a complete copy of the backup
+// is staged. Safe to run again if it was interrupted.
+fn finish_restore(dir: &Path) -> Result<()> {
+    let staging = dir.join(RESTORE_TMP_DIR);
+    if staging.exists() {
+        warn!("Discarding incomplete restore {}", staging.display());
+        fs::remove_dir_all(&staging)?;
+    }
+    let staged = dir.join(RESTORE_DIR);
+    if !staged.exists() {
+        return Ok(());
+    }
+
+    // Files moved in before an interruption are listed in the manifest, so they are kept.
+    let manifest = read_manifest(&staged)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        let restored = manifest.files.iter().any(|file| file.name == name);
+        if path.extension() == Some(OsStr::new(HINT_EXTENSION))
+            || (path.extension() == Some(OsStr::new(SEGMENT_EXTENSION)) && !restored)
+            || (path.extension() == Some(OsStr::new(CHECKPOINT_EXTENSION)) && !restored)
+        {
+            fs::remove_file(&path)?;
+        }
+    }
+    for file in &manifest.files {
+        let path = staged.join(&file.name);
+        if path.exists() {
+            fs::rename(&path, dir.join(&file.name))?;
+        }
+    }
+    sync_dir(dir)?;
+    fs::remove_dir_all(&staged)?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+// Removes every segment, hint file and checkpoint older than the given generation. Called once
+// a compacted segment or checkpoint of that generation holding all their live keys is on disk.
+fn remove_covered(dir: &Path, generation: u64) -> Result<()> {
+    for old in sorted_generations(dir)? {
+        if old < generation {
+            fs::remove_file(segment_path(dir, old))?;
+            remove_hints(dir, old)?;
+        }
+    }
+    for old in sorted_files(dir, CHECKPOINT_EXTENSION)? {
+        if old < generation {
+            fs::remove_file(checkpoint_path(dir, old))?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the path of the segment file with the given generation.
+pub(crate) fn segment_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.{}", generation, SEGMENT_EXTENSION))
+}
+
+// Returns the path of the checkpoint with the given generation.
+pub(crate) fn checkpoint_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}.{}", generation, CHECKPOINT_EXTENSION))
+}
+
+// Returns the generations of all segment files in the directory, oldest first.
+pub(crate) fn sorted_generations(dir: &Path) -> Result<Vec<u64>> {
+    sorted_files(dir, SEGMENT_EXTENSION)
+}
+
+// Returns the generations of all files in the directory with the given extension, oldest first.
+pub(crate) fn sorted_files(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(OsStr::new(extension)) {
+            continue;
+        }
+        if let Some(generation) = path.file_stem().and_then(OsStr::to_str).and_then(|s| s.parse().ok()) {
+            generations.push(generation);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+// Marks a segment file as read-only. Sealed segments are never written to again.
+pub(crate) fn seal_segment(path: &Path) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Compression, EncryptionKey};
+    use crate::engine::prefix_range;
+    use tempfile::TempDir;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    // Returns the combined size of all segment files in the directory. Segments removed by
+    // a concurrent compaction while they are being listed are skipped.
+    fn log_size(dir: &Path) -> u64 {
+        sorted_generations(dir)
+            .unwrap()
+            .into_iter()
+            .filter_map(|generation| fs::metadata(segment_path(dir, generation)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_crud() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path().join("db.kvs")).expect("unable to open store");
+
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+
+        store.remove("key1".to_owned()).unwrap();
+        assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("kvs.db");
+
+        {
+            // 1. Open a store
+            let store = KvStore::open(&db_path).unwrap();
+            // 2. Set key 'foo' to 'bar'
+            store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+        }
+
+        // 4. Open a new store at the same path.
+        let new_store = KvStore::open(&db_path).unwrap();
+        // 5. Assert 'foo' is still 'bar'.
+        assert_eq!(new_store.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+    }
+
+    #[test]
+    fn test_cuncurrent_writes() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path().join("db.kvs")).unwrap();
+
+        let mut handles = vec![];
+
+        for i in 0..10 {
+            let store_clone = store.clone();
+            let handle = thread::spawn(move || {
+                store_clone.set(format!("key{}", i), format!("value{}", i)).unwrap();
+            });
+
+            handles.push(handle);
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // The directory stays locked until the store is closed.
+        drop(store);
+        let store_reloaded = KvStore::open(temp_dir.path().join("db.kvs")).unwrap();
+
+        for i in 0..10 {
+            assert_eq!(
+                store_reloaded.get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    #[test]
+    fn test_reopen_appends_to_log() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+
+        KvStore::open(&db_path).unwrap().set("a".to_owned(), "1".to_owned()).unwrap();
+        KvStore::open(&db_path).unwrap().set("b".to_owned(), "2".to_owned()).unwrap();
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(sorted_generations(&db_path).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_segments_roll_at_size_limit() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let options = KvStoreOptions::new().segment_size(1024).compaction_threshold(u64::MAX);
+
+        {
+            let store = options.open(&db_path).unwrap();
+            for i in 0..100 {
+                store.set(format!("key{}", i), "x".repeat(50)).unwrap();
+            }
+        }
+
+        let generations = sorted_generations(&db_path).unwrap();
+        assert!(generations.len() > 1);
+        for &generation in &generations[..generations.len() - 1] {
+            let metadata = fs::metadata(segment_path(&db_path, generation)).unwrap();
+            assert!(metadata.permissions().readonly());
+            assert!(metadata.len() >= 1024);
+        }
+
+        let store = options.open(&db_path).unwrap();
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i)).unwrap(), Some("x".repeat(50)));
+        }
+    }
+
+    #[test]
+    fn test_hint_files_restore_the_index() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let options = KvStoreOptions::new().segment_size(1024).compaction_threshold(u64::MAX);
+
+        let (entries, uncompacted) = {
+            let store = options.open(&db_path).unwrap();
+            for i in 0..100 {
+                store.set(format!("key{}", i % 40), format!("value{}", i)).unwrap();
+            }
+            store.remove("key3".to_owned()).unwrap();
+            store.set_with_ttl("expiring", "soon", Duration::from_secs(3600)).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.set("key5", "batched").remove("key6").set("key7", "batched");
+            store.write(batch).unwrap();
+            for i in 0..20 {
+                store.set(format!("late{}", i), "x".repeat(50)).unwrap();
+            }
+            (store.scan(..).unwrap(), store.uncompacted.load(Ordering::SeqCst))
+        };
+
+        // Every sealed segment has a hint file, and loading them gives the same index as a replay.
+        let generations = sorted_generations(&db_path).unwrap();
+        assert!(generations.len() > 2);
+        for &generation in &generations[..generations.len() - 1] {
+            assert!(segment_path(&db_path, generation).with_extension(HINT_EXTENSION).exists());
+        }
+        let check = || {
+            let store = options.open(&db_path).unwrap();
+            assert_eq!(store.scan(..).unwrap(), entries);
+            assert_eq!(store.uncompacted.load(Ordering::SeqCst), uncompacted);
+            assert!(store.ttl("expiring").unwrap().is_some());
+        };
+        check();
+
+        // A damaged hint file is ignored, and its segment replayed instead.
+        let hint_path = segment_path(&db_path, generations[0]).with_extension(HINT_EXTENSION);
+        let mut contents = fs::read(&hint_path).unwrap();
+        contents[HEADER_LEN as usize] ^= 0x01;
+        fs::write(&hint_path, contents).unwrap();
+        check();
+        let segment_len = fs::metadata(segment_path(&db_path, generations[0])).unwrap().len();
+        assert!(read_hints(&db_path, generations[0], 0, segment_len, None).is_some());
+    }
+
+    #[test]
+    fn test_compaction_keeps_live_entries() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let store = KvStoreOptions::new()
+            .segment_size(4096)
+            .compaction_threshold(u64::MAX)
+            .open(&db_path)
+            .unwrap();
+
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 10), format!("value{}", i)).unwrap();
+        }
+        store.remove("key0".to_owned()).unwrap();
+        let before = log_size(&db_path);
+
+        store.compact().unwrap();
+        assert!(log_size(&db_path) < before / 10);
+
+        // The store keeps working after the segments have been replaced.
+        store.set("key1".to_owned(), "after".to_owned()).unwrap();
+        drop(store);
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("after".to_owned()));
+        assert_eq!(store.get("key9".to_owned()).unwrap(), Some("value999".to_owned()));
+    }
+
+    #[test]
+    fn test_stats() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .segment_size(4096)
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+            .unwrap();
+
+        for i in 0..200 {
+            store.set(format!("key{}", i % 10), format!("value{}", i)).unwrap();
+        }
+        store.remove("key0".to_owned()).unwrap();
+        assert!(store.remove("key0".to_owned()).is_err());
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value191".to_owned()));
+        store.scan("key1".to_owned().."key3".to_owned()).unwrap();
+        store.expire("key1", Duration::from_secs(60)).unwrap();
+        store.persist("key1").unwrap();
+        assert!(store.write_if(b"key2".to_vec(), Condition::Absent, Some(b"other".to_vec())).is_err());
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.keys, 9);
+        assert_eq!(stats.total_bytes, log_size(temp_dir.path()));
+        assert!(stats.segments > 1);
+        assert!(stats.live_bytes > 0 && stats.dead_bytes() > stats.live_bytes);
+        assert_eq!(stats.last_compaction, None);
+        assert_eq!(stats.compression, store.compression_stats());
+        assert_eq!(stats.compression.records, 203);
+
+        let sets = stats.operation(Operation::Set).unwrap();
+        assert_eq!((sets.count, sets.errors, sets.latency.count()), (200, 0, 200));
+        assert!(sets.latency.percentile(0.5).unwrap() <= sets.latency.percentile(0.99).unwrap());
+        let removes = stats.operation(Operation::Remove).unwrap();
+        assert_eq!((removes.count, removes.errors), (2, 1));
+        assert_eq!(stats.operation(Operation::Get).unwrap().count, 1);
+        assert_eq!(stats.operation(Operation::Scan).unwrap().count, 1);
+        assert_eq!(stats.operation(Operation::Expire).unwrap().count, 2);
+        let conditional = stats.operation(Operation::WriteIf).unwrap();
+        assert_eq!((conditional.count, conditional.errors), (1, 1));
+        assert_eq!(stats.operation(Operation::Batch).unwrap().latency.percentile(0.5), None);
+
+        store.compact().unwrap();
+        let compacted = store.stats().unwrap();
+        assert_eq!((compacted.keys, compacted.segments), (9, 2));
+        assert!(compacted.last_compaction.is_some());
+        assert!(compacted.dead_bytes() < stats.dead_bytes());
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let store = KvStoreOptions::new().compaction_threshold(4096).open(&db_path).unwrap();
+
+        let value = "x".repeat(100);
+        for _ in 0..10_000 {
+            store.set("key".to_owned(), value.clone()).unwrap();
+        }
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&path)?;
+        // Wait for the scheduled compactions to catch up with the writes.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while log_size(&db_path) >= 64 * 1024 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
 
-        // Clone the file handle for a separate writer. This allows us to read and write
-        // to the same file concurrently (reading for startup, writing for operations).
-        let writer = BufWriter::new(file.try_clone()?);
+        assert!(log_size(&db_path) < 64 * 1024);
+        assert_eq!(store.get("key".to_owned()).unwrap(), Some(value));
+    }
 
-        let map = Arc::new(RwLock::new(HashMap::new()));
-        
-        let reader = BufReader::new(File::open(&path)?);
-        
-        // Replay the write-ahead log to restore the in-memory state.
-        Self::load(reader, &map)?;
+    #[test]
+    fn test_writes_during_compaction() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let store = KvStoreOptions::new()
+            .segment_size(4096)
+            .compaction_threshold(u64::MAX)
+            .open(&db_path)
+            .unwrap();
 
-        Ok(KvStore{
-            map,
-            writer: Arc::new(Mutex::new(writer)),
-        })
+        let mut handles = vec![];
+        for t in 0..4 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..500 {
+                    store.set(format!("key{}-{}", t, i % 50), format!("value{}", i)).unwrap();
+                }
+            }));
+        }
+        for _ in 0..10 {
+            store.compact().unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        drop(store);
+
+        let store = KvStore::open(&db_path).unwrap();
+        for t in 0..4 {
+            for i in 450..500 {
+                assert_eq!(
+                    store.get(format!("key{}-{}", t, i % 50)).unwrap(),
+                    Some(format!("value{}", i))
+                );
+            }
+        }
     }
 
-    // Rebuilds the in-memory map by reading and applying all commands from the log file.
-    fn load(mut reader: BufReader<File>, map: &Arc<RwLock<HashMap<String, String>>>) -> Result<()> {
-        // A write lock is held during the entire load process to prevent any other access.
-        let mut map_guard = map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+    #[test]
+    fn test_reads_during_compaction() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+            .unwrap();
 
-        loop {
-            
-            let cmd: std::result::Result<Command, _> = bincode::deserialize_from(&mut reader);
+        for i in 0..200 {
+            store.set(format!("key{}", i % 20), format!("value{}", i)).unwrap();
+        }
 
-            match cmd {
-                Ok(Command::Set {key, value}) => {
-                    map_guard.insert(key, value);
-                }
-                Ok(Command::Remove {key}) => {
-                    map_guard.remove(&key);
-                }
-                Err(e) => {
-                    if let bincode::ErrorKind::Io(ref io_err) = *e {
-                        // `UnexpectedEof` is a normal condition, indicating the end of the log file.
-                        // Any other I/O error during deserialization is a corruption issue.
-                        if io_err.kind() == io::ErrorKind::UnexpectedEof {
-                            break;
-                        }
+        let mut handles = vec![];
+        for _ in 0..4 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..50 {
+                    for i in 180..200 {
+                        assert_eq!(store.get(format!("key{}", i % 20)).unwrap(), Some(format!("value{}", i)));
                     }
-                    return Err(KvsError::from(e));
                 }
-            } 
+            }));
+        }
+        for _ in 0..10 {
+            store.compact().unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
         }
-        Ok(())
     }
 
-    /// Sets a key-value pair.
-    ///
-    /// This operation is persisted to the on-disk log before updating the in-memory map.
-    pub fn set(&self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {key: key.clone(), value: value.clone()};
-        
-        {
-            // Lock the writer, serialize the command, and flush to disk.
-            // This implements the write-ahead log (WAL) pattern for durability.
-            // The lock is released immediately after the write.
-            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
-            bincode::serialize_into(&mut *writer, &cmd)?;
-            writer.flush()?;
+    #[test]
+    fn test_sync_policies() {
+        let policies = [
+            SyncPolicy::Always,
+            SyncPolicy::GroupCommit {max_delay: Duration::from_millis(1)},
+            SyncPolicy::EveryN(3),
+            SyncPolicy::Never,
+        ];
+
+        for policy in policies {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = KvStoreOptions::new().sync_policy(policy).segment_size(256);
+
+            {
+                let store = options.open(temp_dir.path()).unwrap();
+                for i in 0..20 {
+                    store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                store.remove("key0".to_owned()).unwrap();
+            }
+
+            let store = options.open(temp_dir.path()).unwrap();
+            assert_eq!(store.get("key0".to_owned()).unwrap(), None);
+            for i in 1..20 {
+                assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+            }
         }
+    }
 
-        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
-        map.insert(key, value);
+    #[test]
+    fn test_group_commit_shares_syncs() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let max_delay = Duration::from_millis(20);
+        let store = KvStoreOptions::new()
+            .sync_policy(SyncPolicy::GroupCommit {max_delay})
+            .open(temp_dir.path())
+            .unwrap();
 
-        Ok(())
+        let start = Instant::now();
+        let mut handles = vec![];
+        for t in 0..16 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..5 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}", i)).unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Syncing each of the 80 writes separately would take at least 80 delays.
+        assert!(start.elapsed() < max_delay * 40);
+        assert_eq!(store.get("key15-4".to_owned()).unwrap(), Some("value4".to_owned()));
     }
 
-    /// Gets the value associated with a key.
-    ///
-    /// Returns `None` if the key is not found. Reads are served from the in-memory
-    /// map for high performance.
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        // Acquire a read lock, which allows for concurrent reads.
-        let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
-        Ok(map.get(&key).cloned())
+    #[test]
+    fn test_string_logs_still_load() {
+        // The command type earlier versions wrote, before keys and values became bytes.
+        #[derive(Serialize)]
+        enum LegacyCommand {
+            Set {key: String, value: String},
+            Remove {key: String},
+        }
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        fs::create_dir(&db_path).unwrap();
+
+        let mut log = Vec::new();
+        for cmd in [
+            LegacyCommand::Set {key: "key1".to_owned(), value: "value1".to_owned()},
+            LegacyCommand::Set {key: "key2".to_owned(), value: "value2".to_owned()},
+            LegacyCommand::Remove {key: "key1".to_owned()},
+        ] {
+            log.extend(frame_record(&bincode::serialize(&cmd).unwrap(), 0).unwrap());
+        }
+        fs::write(segment_path(&db_path, 1), log).unwrap();
+
+        // Logs from before the format had a version have to be upgraded before they are opened.
+        assert!(matches!(KvStore::open(&db_path), Err(KvsError::UpgradeRequired(1))));
+        KvStore::upgrade(&db_path, &KvStoreOptions::new()).unwrap();
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+        assert_eq!(store.get_bytes(b"key2").unwrap(), Some(b"value2".to_vec()));
+        drop(store);
+
+        // A format this version does not know is refused.
+        let mut contents = fs::read(segment_path(&db_path, 1)).unwrap();
+        contents[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(segment_path(&db_path, 1), contents).unwrap();
+        assert!(matches!(KvStore::open(&db_path), Err(KvsError::UnknownFormat(_))));
     }
 
-    /// Removes a key-value pair.
-    ///
-    /// Errors if the key does not exist. This operation is persisted to the log.
-    pub fn remove(&self, key: String) -> Result<()> {
-        let cmd = Command::Remove {key: key.clone()};
+    #[test]
+    fn test_file_format_versions() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-        {
-            // Similar to `set`, log the removal command first for durability.
-            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
-            bincode::serialize_into(&mut *writer, &cmd)?;
-            writer.flush()?;
+        // A file with a magic number of its own, or from a newer version, is refused.
+        for (name, prefix) in [("magic", b"NOPE".to_vec()), ("future", [&FORMAT_MAGIC[..], &(FORMAT_VERSION + 1).to_le_bytes()].concat())] {
+            let db_path = temp_dir.path().join(name);
+            fs::create_dir(&db_path).unwrap();
+            fs::write(segment_path(&db_path, 1), [prefix, vec![0; 32]].concat()).unwrap();
+            assert!(matches!(KvStore::open(&db_path), Err(KvsError::UnknownFormat(_))));
+            assert!(matches!(KvStore::upgrade(&db_path, &KvStoreOptions::new()), Err(KvsError::UnknownFormat(_))));
         }
 
-        // Enforce that the key must exist for a remove operation to be valid.
-        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
-        if map.remove(&key).is_none() {
-            return Err(KvsError::KeyNotFound);
+        // Segments from before files had a header start right with their first record.
+        let db_path = temp_dir.path().join("legacy");
+        fs::create_dir(&db_path).unwrap();
+        let legacy_segment = |commands: Vec<Command>| -> Vec<u8> {
+            commands
+                .iter()
+                .flat_map(|cmd| frame_record(&bincode::serialize(cmd).unwrap(), 0).unwrap())
+                .collect()
+        };
+        let set = |key: &str, value: &str| Command::Set {key: key.as_bytes().to_vec(), value: value.as_bytes().to_vec()};
+        let expires_at = now_millis() + 3_600_000;
+        fs::write(
+            segment_path(&db_path, 1),
+            legacy_segment(vec![
+                set("key1", "value1"),
+                set("key2", "value2"),
+                Command::SetWithExpiry {key: b"key3".to_vec(), value: b"value3".to_vec(), expires_at},
+            ]),
+        )
+        .unwrap();
+        seal_segment(&segment_path(&db_path, 1)).unwrap();
+        fs::write(
+            segment_path(&db_path, 2),
+            legacy_segment(vec![
+                Command::Batch {commands: vec![set("key4", "value4"), Command::Remove {key: b"key1".to_vec()}]},
+                set("key2", "newer"),
+            ]),
+        )
+        .unwrap();
+        assert!(matches!(KvStore::open(&db_path), Err(KvsError::UpgradeRequired(1))));
+
+        // The upgrade keeps every key, and running it again changes nothing.
+        KvStore::upgrade(&db_path, &KvStoreOptions::new()).unwrap();
+        KvStore::upgrade(&db_path, &KvStoreOptions::new()).unwrap();
+        for generation in [1, 2] {
+            let path = segment_path(&db_path, generation);
+            assert!(read_file_header(&File::open(&path).unwrap(), &path).unwrap().is_some());
         }
-        
-        Ok(())
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(
+            store.scan(..).unwrap(),
+            vec![
+                ("key2".to_owned(), "newer".to_owned()),
+                ("key3".to_owned(), "value3".to_owned()),
+                ("key4".to_owned(), "value4".to_owned()),
+            ]
+        );
+        assert!(store.ttl("key3").unwrap().is_some());
+        assert_eq!(store.snapshot().unwrap().seq(), 5);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
-    use std::thread;
+    #[test]
+    fn test_mixed_codecs_replay() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let json = |i: usize| format!(r#"{{"id": {}, "name": "user {}", "tags": ["a", "b", "c"], "active": true}}"#, i, i).repeat(10);
+
+        // Each codec writes its own share of the keys, and a few short values stay uncompressed.
+        let codecs = [Compression::Lz4, Compression::Zstd, Compression::None];
+        for (n, &codec) in codecs.iter().enumerate() {
+            let store = KvStoreOptions::new().compression(codec).open(&db_path).unwrap();
+            for i in n * 100..(n + 1) * 100 {
+                store.set(format!("key{}", i), json(i)).unwrap();
+            }
+            store.set(format!("short{}", n), "x".to_owned()).unwrap();
+
+            let stats = store.compression_stats();
+            assert_eq!(stats.records, 101);
+            if codec == Compression::None {
+                assert_eq!(stats.compressed, 0);
+                assert_eq!(stats.ratio(), 1.0);
+            } else {
+                assert_eq!(stats.compressed, 100);
+                assert!(stats.ratio() > 5.0, "{:?} only reached a ratio of {}", codec, stats.ratio());
+            }
+        }
+
+        let store = KvStore::open(&db_path).unwrap();
+        store.compact().unwrap();
+        for i in 0..300 {
+            assert_eq!(store.get(format!("key{}", i)).unwrap(), Some(json(i)));
+        }
+        assert_eq!(store.get("short1".to_owned()).unwrap(), Some("x".to_owned()));
+    }
 
     #[test]
-    fn test_crud() {
+    fn test_encryption_and_key_rotation() {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let store = KvStore::open(temp_dir.path().join("db.kvs")).expect("unable to open store");
+        let db_path = temp_dir.path().join("db.kvs");
+        let (key1, key2) = (EncryptionKey::new(1, [1; 32]), EncryptionKey::new(2, [2; 32]));
+        let options = KvStoreOptions::new().segment_size(1024).compaction_threshold(u64::MAX);
+        // No file in the directory may contain a key or value in the clear.
+        let assert_no_plaintext = || {
+            for entry in fs::read_dir(&db_path).unwrap() {
+                let contents = fs::read(entry.unwrap().path()).unwrap();
+                assert!(!contents.windows(6).any(|window| window == b"secret"));
+            }
+        };
 
-        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
-        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        let entries = {
+            let store = options.clone().encryption_key(key1.clone()).open(&db_path).unwrap();
+            for i in 0..100 {
+                store.set(format!("secret{}", i % 40), format!("secret value {}", i)).unwrap();
+            }
+            store.remove("secret3".to_owned()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.set("secret5", "secret batch").remove("secret6");
+            store.write(batch).unwrap();
+            store.scan(..).unwrap()
+        };
+        assert_no_plaintext();
+        assert!(matches!(KvStore::open(&db_path), Err(KvsError::MissingKey(1))));
 
-        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
-        assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+        // After a rotation, files written with the old key stay readable through the retired key,
+        // until compaction has re-encrypted everything with the new one.
+        let rotated = options.clone().encryption_key(key2.clone()).compression(Compression::Lz4);
+        let store = rotated.clone().retired_key(key1).open(&db_path).unwrap();
+        assert_eq!(store.scan(..).unwrap(), entries);
+        store.set("secret1".to_owned(), "secret rotated".to_owned()).unwrap();
+        store.compact().unwrap();
+        // Re-encrypting records does not count them as written again.
+        assert_eq!(store.compression_stats().records, 1);
+        let entries = store.scan(..).unwrap();
+        drop(store);
+        assert_no_plaintext();
 
+        for generation in sorted_generations(&db_path).unwrap() {
+            let path = segment_path(&db_path, generation);
+            let (header, _) = read_file_header(&File::open(&path).unwrap(), &path).unwrap().unwrap();
+            assert_eq!(header.key_id, Some(2));
+        }
+        let store = rotated.open(&db_path).unwrap();
+        assert_eq!(store.scan(..).unwrap(), entries);
+        drop(store);
+
+        // The same keys can come from a key file, whose last key is the current one.
+        let key_file = temp_dir.path().join("keys");
+        fs::write(&key_file, format!("# rotated keys\n1 {}\n2 {}\n", "01".repeat(32), "02".repeat(32))).unwrap();
+        let store = options.key_file(&key_file).open(&db_path).unwrap();
+        assert_eq!(store.scan(..).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_directory_lock_and_read_only_mode() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::AlreadyLocked {pid}) => assert_eq!(pid, Some(std::process::id())),
+            other => panic!("expected the directory to be locked, got {:?}", other.map(|_| ())),
+        }
+
+        // A read-only store sees the data as it was when opened, even once the writer has
+        // compacted away the segments holding it.
+        let reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+        store.set("key0".to_owned(), "changed".to_owned()).unwrap();
+        store.compact().unwrap();
+        assert_eq!(reader.get("key0".to_owned()).unwrap(), Some("value0".to_owned()));
+        assert_eq!(reader.scan(..).unwrap().len(), 10);
+        assert!(matches!(reader.set("key0".to_owned(), "again".to_owned()), Err(KvsError::ReadOnly)));
+        assert!(matches!(reader.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("key0".to_owned()).unwrap(), Some("changed".to_owned()));
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = temp_dir.path().join("backup");
+        let store = KvStore::open(temp_dir.path().join("db")).unwrap();
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        store.checkpoint().unwrap();
+        store.set("key0".to_owned(), "changed".to_owned()).unwrap();
+
+        // Writes keep going while the backup is taken, and only those made before it are in it.
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    store.set(format!("later{}", i), "value".to_owned()).unwrap();
+                }
+            })
+        };
+        store.backup_to(&backup_dir).unwrap();
+        writer.join().unwrap();
+        assert!(store.backup_to(&backup_dir).is_err());
+
+        // Restoring replaces everything in the store, while an open snapshot keeps its view.
+        let snapshot = store.snapshot().unwrap();
         store.remove("key1".to_owned()).unwrap();
-        assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+        store.restore_from(&backup_dir).unwrap();
+        assert_eq!(store.get("key0".to_owned()).unwrap(), Some("changed".to_owned()));
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert!((100..=200).contains(&store.scan(..).unwrap().len()));
+        assert_eq!(snapshot.get("later99".to_owned()).unwrap(), Some("value".to_owned()));
+        store.set("after".to_owned(), "restore".to_owned()).unwrap();
+        drop(snapshot);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path().join("db")).unwrap();
+        assert_eq!(store.get("key99".to_owned()).unwrap(), Some("value99".to_owned()));
+        assert_eq!(store.get("after".to_owned()).unwrap(), Some("restore".to_owned()));
+
+        // A damaged backup is rejected before the store is touched.
+        let segment = fs::read_dir(&backup_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some(OsStr::new(SEGMENT_EXTENSION)))
+            .unwrap();
+        let mut contents = fs::read(&segment).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        // The store may share the file through a hard link, so the damaged copy is a new file.
+        fs::remove_file(&segment).unwrap();
+        fs::write(&segment, contents).unwrap();
+        assert!(matches!(store.restore_from(&backup_dir), Err(KvsError::InvalidBackup(_))));
+        assert_eq!(store.get("after".to_owned()).unwrap(), Some("restore".to_owned()));
     }
 
     #[test]
-    fn test_persistence() {
+    fn test_point_in_time_recovery() {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let db_path = temp_dir.path().join("kvs.db");
+        let db_path = temp_dir.path().join("db");
+        let store = KvStoreOptions::new().segment_size(1024).open(&db_path).unwrap();
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let before = store.snapshot().unwrap().seq();
+        let time = SystemTime::now();
+        thread::sleep(Duration::from_millis(10));
+        for i in 0..50 {
+            store.remove(format!("key{}", i)).unwrap();
+        }
 
-        {
-            // 1. Open a store
-            let store = KvStore::open(&db_path).unwrap();
-            // 2. Set key 'foo' to 'bar'
-            store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+        // Sequence numbers and timestamps are part of the log, so they survive a restart.
+        drop(store);
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.scan(..).unwrap(), vec![]);
+        for target in [RecoveryTarget::Seq(before), RecoveryTarget::Timestamp(time)] {
+            let recovered = KvStore::open_at(&db_path, target).unwrap();
+            assert_eq!(recovered.scan(..).unwrap().len(), 50);
+            assert_eq!(recovered.get("key0".to_owned()).unwrap(), Some("value0".to_owned()));
+            assert!(matches!(recovered.set("key0".to_owned(), "again".to_owned()), Err(KvsError::ReadOnly)));
         }
 
-        // 4. Open a new store at the same path.
-        let new_store = KvStore::open(&db_path).unwrap();
-        // 5. Assert 'foo' is still 'bar'.
-        assert_eq!(new_store.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+        // A store recovered to an earlier point can be copied into a new directory and written to.
+        let recovered = KvStore::open_at(&db_path, RecoveryTarget::Seq(before - 10)).unwrap();
+        let copy = recovered.copy_to(temp_dir.path().join("copy")).unwrap();
+        assert_eq!(copy.scan(..).unwrap().len(), 40);
+        copy.set("key49".to_owned(), "value49".to_owned()).unwrap();
+        drop(copy);
+        let copy = KvStore::open(temp_dir.path().join("copy")).unwrap();
+        assert_eq!(copy.scan(..).unwrap().len(), 41);
+
+        // A compaction discards the history before it.
+        store.compact().unwrap();
+        assert!(matches!(KvStore::open_at(&db_path, RecoveryTarget::Seq(before)), Err(KvsError::Unrecoverable(_))));
     }
 
     #[test]
-    fn test_cuncurrent_writes() {
+    fn test_key_expiry() {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let store = KvStore::open(temp_dir.path().join("db.kvs")).unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
 
-        let mut handles = vec![];
+        store.set_with_ttl("session", "token", Duration::from_millis(200)).unwrap();
+        store.set("config".to_owned(), "on".to_owned()).unwrap();
+        assert_eq!(store.get("session".to_owned()).unwrap(), Some("token".to_owned()));
+        assert!(store.ttl("session").unwrap().unwrap() <= Duration::from_millis(200));
+        assert_eq!(store.ttl("config").unwrap(), None);
+
+        store.expire("config", Duration::from_secs(3600)).unwrap();
+        assert!(store.ttl("config").unwrap().unwrap() > Duration::from_secs(3500));
+        store.persist("config").unwrap();
+        assert_eq!(store.ttl("config").unwrap(), None);
+
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(store.get("session".to_owned()).unwrap(), None);
+        assert!(matches!(store.ttl("session"), Err(KvsError::KeyNotFound)));
+        assert!(matches!(store.expire("session", Duration::from_secs(1)), Err(KvsError::KeyNotFound)));
+        assert!(matches!(store.remove("session".to_owned()), Err(KvsError::KeyNotFound)));
+        assert_eq!(store.scan(..).unwrap(), vec![("config".to_owned(), "on".to_owned())]);
+
+        // Expiry times are part of the log, so they survive a restart.
+        store.expire("config", Duration::from_secs(3600)).unwrap();
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("session".to_owned()).unwrap(), None);
+        assert!(store.ttl("config").unwrap().is_some());
+        assert_eq!(store.get("config".to_owned()).unwrap(), Some("on".to_owned()));
+    }
+
+    #[test]
+    fn test_expired_keys_are_swept() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .sweep_interval(Duration::from_millis(20))
+            .open(temp_dir.path())
+            .unwrap();
 
         for i in 0..10 {
-            let store_clone = store.clone();
-            let handle = thread::spawn(move || {
-                store_clone.set(format!("key{}", i), format!("value{}", i)).unwrap();
-            });
+            store.set_with_ttl(format!("key{}", i), "value", Duration::from_millis(50)).unwrap();
+        }
+        store.set("kept".to_owned(), "value".to_owned()).unwrap();
+        assert_eq!(store.uncompacted.load(Ordering::SeqCst), 0);
 
-            handles.push(handle);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(store.map.read().unwrap().len(), 1);
+        assert!(store.uncompacted.load(Ordering::SeqCst) > 0);
+
+        // Compaction drops the swept records for good.
+        store.compact().unwrap();
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.map.read().unwrap().len(), 1);
+        assert_eq!(store.get("kept".to_owned()).unwrap(), Some("value".to_owned()));
+    }
+
+    #[test]
+    fn test_torn_write_is_truncated() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+
+        KvStore::open(&db_path).unwrap().set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let segment = segment_path(&db_path, 1);
+        let valid_len = fs::metadata(&segment).unwrap().len();
+
+        // A power cut in the middle of a write leaves part of a record behind.
+        let record = encode_record(&Command::Set {key: b"key2".to_vec(), value: b"value2".to_vec()}, Stamp {seq: 2, timestamp: now_millis()}, &Compressor::default(), None).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&record[..record.len() - 2]).unwrap();
+        drop(file);
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), valid_len);
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+
+        // A full-length record whose contents never reached the disk is dropped as well.
+        store.set("key3".to_owned(), "value3".to_owned()).unwrap();
+        drop(store);
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&record[..HEADER_LEN as usize]).unwrap();
+        file.write_all(&vec![0; record.len() - HEADER_LEN as usize]).unwrap();
+        drop(file);
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+        assert_eq!(store.get("key3".to_owned()).unwrap(), Some("value3".to_owned()));
+    }
+
+    #[test]
+    fn test_batch_is_replayed_all_or_nothing() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+
+        let store = KvStore::open(&db_path).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key2", "value2").set("key3", "value3").remove("key1");
+        store.write(batch).unwrap();
+
+        // Overwrite one key of the batch, then compact. The record is still shared by the others.
+        store.set("key3".to_owned(), "newer".to_owned()).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned()).unwrap(), Some("newer".to_owned()));
+
+        // A batch cut short by a crash is dropped as a whole.
+        let mut batch = WriteBatch::new();
+        batch.set("key4", "value4").set("key5", "value5");
+        store.write(batch).unwrap();
+        drop(store);
+        let newest = *sorted_generations(&db_path).unwrap().last().unwrap();
+        let segment = segment_path(&db_path, newest);
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+        drop(file);
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert_eq!(store.get("key4".to_owned()).unwrap(), None);
+        assert_eq!(store.get("key5".to_owned()).unwrap(), None);
+        assert_eq!(
+            store.scan(..).unwrap(),
+            vec![("key2".to_owned(), "value2".to_owned()), ("key3".to_owned(), "newer".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_compaction_drops_removed_batch_keys() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key1", "value1").set("key2", "value2");
+        store.write(batch).unwrap();
+
+        // The batch is still live through key1 when it is compacted.
+        store.remove("key2".to_owned()).unwrap();
+        store.compact().unwrap();
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_compaction_drops_expired_batch_keys() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .sweep_interval(Duration::from_millis(20))
+            .open(temp_dir.path())
+            .unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key1", "value1").set("key2", "value2");
+        store.write(batch).unwrap();
+
+        // Wait for key2 to expire and be swept from the index, which writes nothing to the log.
+        store.expire("key2", Duration::from_millis(20)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while store.map.read().unwrap().contains_key(b"key2".as_slice()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
         }
+        store.compact().unwrap();
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare_and_swap_is_atomic() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("counter".to_owned(), "0".to_owned()).unwrap();
 
+        // Every thread increments the counter with a read-modify-write loop. Lost updates would
+        // leave it short of the total.
+        let mut handles = vec![];
+        for _ in 0..8 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for _ in 0..25 {
+                    loop {
+                        let current = store.get_bytes(b"counter").unwrap().unwrap();
+                        let next = (String::from_utf8(current.clone()).unwrap().parse::<u32>().unwrap() + 1).to_string();
+                        match store.compare_and_swap(b"counter".to_vec(), Some(current), Some(next.into_bytes())) {
+                            Ok(()) => break,
+                            Err(KvsError::ConditionFailed) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                }
+            }));
+        }
         for handle in handles {
             handle.join().unwrap();
         }
 
-        let store_reloaded = KvStore::open(temp_dir.path().join("db.kvs")).unwrap();
+        assert_eq!(store.get("counter".to_owned()).unwrap(), Some("200".to_owned()));
+    }
 
+    #[test]
+    fn test_scan_iter_reads_lazily() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
         for i in 0..10 {
-            assert_eq!(
-                store_reloaded.get(format!("key{}", i)).unwrap(),
-                Some(format!("value{}", i))
-            );
+            store.set(format!("user:{}", i), format!("value{}", i)).unwrap();
+        }
+        store.set("other".to_owned(), "value".to_owned()).unwrap();
+
+        let options = ScanOptions {limit: Some(3), reverse: true};
+        let mut iter = store.scan_iter(prefix_range(b"user:"), options).unwrap();
+        assert_eq!(iter.size_hint(), (3, Some(3)));
+
+        // Writes made while iterating do not change what the scan returns.
+        store.set("user:8".to_owned(), "changed".to_owned()).unwrap();
+        store.remove("user:7".to_owned()).unwrap();
+        store.compact().unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), (b"user:9".to_vec(), b"value9".to_vec()));
+        assert_eq!(iter.next().unwrap().unwrap(), (b"user:8".to_vec(), b"value8".to_vec()));
+        assert_eq!(iter.next().unwrap().unwrap(), (b"user:7".to_vec(), b"value7".to_vec()));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_snapshot_sees_a_fixed_state() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        store.set("b".to_owned(), "1".to_owned()).unwrap();
+
+        let snapshot = store.snapshot().unwrap();
+        store.set("a".to_owned(), "2".to_owned()).unwrap();
+        store.remove("b".to_owned()).unwrap();
+        store.set("c".to_owned(), "2".to_owned()).unwrap();
+        let later = store.snapshot().unwrap();
+        store.set("a".to_owned(), "3".to_owned()).unwrap();
+
+        // Compaction removes the segments holding the old versions, but they stay readable.
+        store.compact().unwrap();
+        assert_eq!(snapshot.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(snapshot.get("b".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(snapshot.get("c".to_owned()).unwrap(), None);
+        assert_eq!(
+            snapshot.scan_bytes(.., ScanOptions::default()).unwrap(),
+            vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"1".to_vec())]
+        );
+        assert_eq!(
+            later.scan_bytes(.., ScanOptions {limit: Some(1), reverse: true}).unwrap(),
+            vec![(b"c".to_vec(), b"2".to_vec())]
+        );
+        assert_eq!(later.get("a".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+
+        // Old versions are only kept while a snapshot can see them.
+        drop(snapshot);
+        assert_eq!(store.history.lock().unwrap().versions.len(), 1);
+        drop(later);
+        assert!(store.history.lock().unwrap().versions.is_empty());
+        store.set("a".to_owned(), "4".to_owned()).unwrap();
+        assert!(store.history.lock().unwrap().versions.is_empty());
+    }
+
+    #[test]
+    fn test_transactions_conflict_on_concurrent_writes() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+
+        // A transaction sees its own writes, and they become visible on commit.
+        let mut txn = store.begin().unwrap();
+        txn.set("b", "1");
+        assert_eq!(txn.get("b").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        txn.remove("a").unwrap();
+        assert_eq!(txn.scan(.., ScanOptions::default()).unwrap(), vec![(b"b".to_vec(), b"1".to_vec())]);
+        txn.commit().unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), None);
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("1".to_owned()));
+
+        // A key that was read and then changed by someone else, even by removing it, conflicts.
+        let mut txn = store.begin().unwrap();
+        assert_eq!(txn.get("b").unwrap(), Some(b"1".to_vec()));
+        txn.set("c", "1");
+        store.remove("b".to_owned()).unwrap();
+        assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+
+        // So does a key inserted into a scanned range, but not one outside of it.
+        let mut txn = store.begin().unwrap();
+        assert!(txn.scan(prefix_range(b"user/"), ScanOptions::default()).unwrap().is_empty());
+        txn.set("count", "0");
+        store.set("other".to_owned(), "1".to_owned()).unwrap();
+        let mut phantom = store.begin().unwrap();
+        phantom.scan(prefix_range(b"user/"), ScanOptions::default()).unwrap();
+        phantom.set("count", "0");
+        store.set("user/1".to_owned(), "1".to_owned()).unwrap();
+        txn.abort();
+        assert!(matches!(phantom.commit(), Err(KvsError::Conflict)));
+        assert_eq!(store.get("count".to_owned()).unwrap(), None);
+
+        // A removed key that expires before the commit conflicts too, rather than going missing.
+        store.set_with_ttl(b"session".to_vec(), b"1".to_vec(), Duration::from_millis(50)).unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.remove("session").unwrap();
+        txn.set("count", "1");
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+        assert_eq!(store.get("count".to_owned()).unwrap(), None);
+        let mut retry = store.begin().unwrap();
+        assert!(matches!(retry.remove("session"), Err(KvsError::KeyNotFound)));
+    }
+
+    #[test]
+    fn test_corruption_is_detected() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+
+        {
+            let store = KvStore::open(&db_path).unwrap();
+            store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        }
+
+        // Flip a bit inside the value of the first record, which follows the file header.
+        let segment = segment_path(&db_path, 1);
+        let (_, data_start) = read_file_header(&File::open(&segment).unwrap(), &segment).unwrap().unwrap();
+        let mut contents = fs::read(&segment).unwrap();
+        let first_len = encode_record(&Command::Set {key: b"key1".to_vec(), value: b"value1".to_vec()}, Stamp {seq: 1, timestamp: now_millis()}, &Compressor::default(), None).unwrap().len();
+        contents[data_start as usize + first_len - 1] ^= 0x01;
+        fs::write(&segment, contents).unwrap();
+
+        match KvStore::open(&db_path) {
+            Err(KvsError::Corruption {segment, offset}) => assert_eq!((segment, offset), (1, data_start)),
+            other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_crash_during_compaction() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+
+        {
+            let store = KvStore::open(&db_path).unwrap();
+            for i in 0..100 {
+                store.set(format!("key{}", i % 10), format!("value{}", i)).unwrap();
+            }
+        }
+
+        // Crash while the compacted segment was being written: a truncated, partial file is left behind.
+        let partial = encode_record(&Command::Set {key: b"key0".to_vec(), value: b"stale".to_vec()}, Stamp {seq: 1, timestamp: now_millis()}, &Compressor::default(), None).unwrap();
+        let compaction_path = segment_path(&db_path, 2).with_extension(COMPACTION_EXTENSION);
+        fs::write(&compaction_path, &partial[..partial.len() - 3]).unwrap();
+
+        let store = KvStore::open(&db_path).unwrap();
+        assert!(!compaction_path.exists());
+        for i in 90..100 {
+            assert_eq!(store.get(format!("key{}", i % 10)).unwrap(), Some(format!("value{}", i)));
+        }
+        drop(store);
+
+        // Crash after the compacted segment was renamed into place, but before the segments
+        // it replaces were removed.
+        let sealed = sorted_generations(&db_path).unwrap();
+        let backups: Vec<_> = sealed
+            .iter()
+            .map(|&generation| (generation, fs::read(segment_path(&db_path, generation)).unwrap()))
+            .collect();
+        let store = KvStore::open(&db_path).unwrap();
+        store.set("key3".to_owned(), "newest".to_owned()).unwrap();
+        store.compact().unwrap();
+        drop(store);
+        for (generation, contents) in backups {
+            fs::write(segment_path(&db_path, generation), contents).unwrap();
+        }
+
+        let store = KvStore::open(&db_path).unwrap();
+        for i in 90..100 {
+            let expected = if i % 10 == 3 { "newest".to_owned() } else { format!("value{}", i) };
+            assert_eq!(store.get(format!("key{}", i % 10)).unwrap(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_replaces_the_log() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db_path = temp_dir.path().join("db.kvs");
+        let options = KvStoreOptions::new().segment_size(1024).compaction_threshold(u64::MAX);
+
+        let store = options.open(&db_path).unwrap();
+        for i in 0..100 {
+            store.set(format!("key{}", i % 40), format!("value{}", i)).unwrap();
+        }
+        store.remove("key3".to_owned()).unwrap();
+        store.set_with_ttl("expiring", "soon", Duration::from_secs(3600)).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("key5", "batched").remove("key6");
+        store.write(batch).unwrap();
+        store.checkpoint().unwrap();
+
+        // Only the checkpoint and the segments written after it are left.
+        let checkpoints = sorted_files(&db_path, CHECKPOINT_EXTENSION).unwrap();
+        assert_eq!(checkpoints.len(), 1);
+        assert!(sorted_generations(&db_path).unwrap().iter().all(|&generation| generation > checkpoints[0]));
+        assert_eq!(store.uncompacted.load(Ordering::SeqCst), 0);
+        assert_eq!(store.get("key5".to_owned()).unwrap(), Some("batched".to_owned()));
+
+        store.set("key1".to_owned(), "after".to_owned()).unwrap();
+        let entries = store.scan(..).unwrap();
+        drop(store);
+
+        // A damaged newer checkpoint is skipped in favor of the newest intact one.
+        fs::write(checkpoint_path(&db_path, checkpoints[0] + 100), b"damaged").unwrap();
+        let store = options.open(&db_path).unwrap();
+        assert_eq!(store.scan(..).unwrap(), entries);
+        assert!(store.ttl("expiring").unwrap().is_some());
+        drop(store);
+
+        // Checkpoints are also written in the background as the store is used.
+        let store = options.checkpoint_interval(Duration::ZERO).open(&db_path).unwrap();
+        store.set("key2".to_owned(), "background".to_owned()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while sorted_files(&db_path, CHECKPOINT_EXTENSION).unwrap()[0] == checkpoints[0] && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_ne!(sorted_files(&db_path, CHECKPOINT_EXTENSION).unwrap()[0], checkpoints[0]);
+        assert_eq!(store.get("key2".to_owned()).unwrap(), Some("background".to_owned()));
     }
 }
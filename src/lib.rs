@@ -1,7 +1,31 @@
+pub mod batch;
+pub mod bloom;
+pub mod btree;
+pub mod compression;
+pub mod crypto;
+pub mod engine;
 pub mod error;
+pub mod inspect;
 pub mod kv;
+pub mod lsm;
+pub mod memory;
 pub mod msg;
+pub mod options;
+pub mod stats;
+pub mod transaction;
+mod util;
 
+pub use batch::WriteBatch;
+pub use bloom::FilterStats;
+pub use btree::BTreeEngine;
+pub use compression::{Compression, CompressionStats};
+pub use crypto::EncryptionKey;
+pub use engine::{Condition, KvsEngine};
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
-pub use msg::{Request, Response};
\ No newline at end of file
+pub use kv::{KvStore, ScanIter, Snapshot};
+pub use lsm::LsmEngine;
+pub use memory::MemoryEngine;
+pub use msg::{Request, Response};
+pub use options::{KvStoreOptions, RecoveryTarget, ScanOptions, SyncPolicy};
+pub use stats::{LatencyHistogram, Operation, OperationStats, StoreStats};
+pub use transaction::Transaction;
@@ -0,0 +1,1378 @@
+use crate::batch::BatchOp;
+use crate::bloom::{BloomFilter, FilterCounters, FilterStats, hash_key};
+use crate::engine::is_empty_range;
+use crate::kv::{
+    ActiveSegment, Command, CreationOptions, Syncer, read_engine_file_header, read_segment, segment_path,
+    sorted_generations,
+};
+use crate::transaction::KeyRange;
+use crate::util::{DirLock, now_millis, read_exact_at, sync_dir};
+use crate::{
+    CompressionStats, Condition, KvStoreOptions, KvsEngine, KvsError, Result, ScanOptions, StoreStats, WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+// The name of the engine recorded in the header of every WAL segment.
+const ENGINE_NAME: &str = "lsm";
+// The version of the format of the manifest, SSTables and bloom filters this build writes.
+// WAL segments have the file header and format version of `KvStore` segments instead.
+const FORMAT_VERSION: u32 = 1;
+// The bytes the manifest starts with, followed by its format version.
+const MANIFEST_MAGIC: [u8; 4] = *b"RKVM";
+// Size of the magic number and format version at the start of the manifest.
+const MANIFEST_PREFIX_LEN: usize = 8;
+// Extension of the SSTable files.
+const TABLE_EXTENSION: &str = "sst";
+// Extension of the file holding the bloom filter of the SSTable with the same id.
+const FILTER_EXTENSION: &str = "filter";
+// Name of the file listing the SSTables that make up the store, level by level.
+const MANIFEST_FILE: &str = "MANIFEST";
+// Extension of the temporary file a new manifest is written to before it is renamed into place.
+const MANIFEST_TMP_EXTENSION: &str = "tmp";
+// Identifies an SSTable written by this engine. Stored at the very end of the file.
+const TABLE_MAGIC: &[u8; 8] = b"RKVTABLE";
+// Length of the footer at the end of every SSTable: the offset and length of its index, its
+// format version and the magic.
+const FOOTER_LEN: u64 = 28;
+// Bytes in front of each block: a CRC32 of the block and its length.
+const BLOCK_HEADER_LEN: usize = 8;
+// The size a data block is filled to before the next one is started. Blocks are the unit
+// SSTables are read in.
+const BLOCK_SIZE: usize = 4096;
+// The size at which compaction moves on to a new output SSTable.
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+// The number of SSTables in level 0 that triggers their compaction into level 1.
+const L0_COMPACTION_TRIGGER: usize = 4;
+// The total size of level 1 above which one of its SSTables is pushed down a level.
+const L1_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// How much larger every level below level 1 may grow than the one above it.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+// Estimated memory used by each memtable entry on top of its key and value.
+const ENTRY_OVERHEAD: u64 = 32;
+
+// A key with its value, or with `None` for a tombstone left by a removal.
+type Entry = (Vec<u8>, Option<Vec<u8>>);
+// A stream of entries from one memtable or level, in key order.
+type Source = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+// A sorted in-memory table holding the most recent writes. Its contents are also in the WAL.
+#[derive(Default)]
+struct Memtable {
+    // The generation of the newest WAL segment holding writes of this memtable. Once the
+    // memtable is flushed, this and every older segment can be deleted.
+    wal: u64,
+    entries: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // The approximate memory used by the entries.
+    size: u64,
+}
+
+impl Memtable {
+    fn new(wal: u64) -> Memtable {
+        Memtable {wal, ..Memtable::default()}
+    }
+
+    // Applies a command read from or written to the WAL.
+    fn apply(&mut self, cmd: Command) {
+        match cmd {
+            // The engine does not support expiry, so it never writes `SetWithExpiry`.
+            Command::Set {key, value} | Command::SetWithExpiry {key, value, ..} => self.insert(key, Some(value)),
+            Command::Remove {key} => self.insert(key, None),
+            Command::Batch {commands} => {
+                for cmd in commands {
+                    self.apply(cmd);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.size += key.len() as u64 + value.as_ref().map_or(0, |value| value.len() as u64) + ENTRY_OVERHEAD;
+        self.entries.insert(key, value);
+    }
+
+    // Returns a copy of the entries within `range`, in the given order.
+    fn source(&self, range: &KeyRange, reverse: bool) -> Source {
+        let bounds = (range.0.as_ref(), range.1.as_ref());
+        let mut entries: Vec<Entry> = self
+            .entries
+            .range::<Vec<u8>, _>(bounds)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        if reverse {
+            entries.reverse();
+        }
+        Box::new(entries.into_iter().map(Ok))
+    }
+}
+
+// The location of a data block within an SSTable, along with the last key it holds.
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    // The length of the block in bytes, including its header.
+    len: u64,
+}
+
+// The index stored at the end of every SSTable.
+#[derive(Debug, Serialize, Deserialize)]
+struct TableIndex {
+    first_key: Vec<u8>,
+    // The data blocks in key order. Tables always have at least one.
+    blocks: Vec<BlockHandle>,
+}
+
+// An immutable, sorted file of entries, as written by a memtable flush or a compaction.
+//
+// The file is a sequence of data blocks, each a bincode list of entries framed with a CRC32
+// and its length, followed by the framed index and a fixed-size footer pointing at it. The
+// bloom filter over its keys is kept in a separate file next to it.
+struct Table {
+    id: u64,
+    file: File,
+    index: TableIndex,
+    filter: BloomFilter,
+    // The size of the file in bytes.
+    size: u64,
+}
+
+impl Table {
+    // Opens an SSTable and reads its index and bloom filter. A filter that is missing or
+    // damaged is rebuilt with the given number of bits per key.
+    fn open(dir: &Path, id: u64, bits_per_key: usize) -> Result<Table> {
+        let file = File::open(table_path(dir, id))?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(KvsError::Corruption {segment: id, offset: 0});
+        }
+
+        let mut footer = [0; FOOTER_LEN as usize];
+        read_exact_at(&file, &mut footer, size - FOOTER_LEN)?;
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_len = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let version = u32::from_le_bytes(footer[16..20].try_into().unwrap());
+        if &footer[20..] != TABLE_MAGIC {
+            return Err(KvsError::Corruption {segment: id, offset: size - FOOTER_LEN});
+        }
+        if version != FORMAT_VERSION {
+            let path = table_path(dir, id);
+            return Err(KvsError::UnknownFormat(format!("{} is in format version {}", path.display(), version)));
+        }
+        if index_offset.checked_add(index_len) != Some(size - FOOTER_LEN) {
+            return Err(KvsError::Corruption {segment: id, offset: size - FOOTER_LEN});
+        }
+
+        let index: TableIndex = bincode::deserialize(&read_frame(&file, id, index_offset, index_len)?)?;
+        if index.blocks.is_empty() {
+            return Err(KvsError::Corruption {segment: id, offset: index_offset});
+        }
+
+        let mut table = Table {id, file, index, filter: BloomFilter::build(&[], 0), size};
+        match read_filter(dir, id) {
+            Ok(filter) => table.filter = filter,
+            Err(e) => {
+                warn!("Rebuilding the bloom filter of SSTable {}: {}", id, e);
+                let mut key_hashes = Vec::new();
+                for block in 0..table.index.blocks.len() {
+                    key_hashes.extend(table.read_block(block)?.iter().map(|(key, _)| hash_key(key)));
+                }
+                table.filter = BloomFilter::build(&key_hashes, bits_per_key);
+                write_filter(dir, id, &table.filter)?;
+            }
+        }
+        Ok(table)
+    }
+
+    fn first_key(&self) -> &[u8] {
+        &self.index.first_key
+    }
+
+    fn last_key(&self) -> &[u8] {
+        &self.index.blocks[self.index.blocks.len() - 1].last_key
+    }
+
+    // Returns whether any key within `range` could be in the table.
+    fn overlaps(&self, range: &KeyRange) -> bool {
+        let after_start = match &range.0 {
+            Bound::Included(start) => self.last_key() >= start.as_slice(),
+            Bound::Excluded(start) => self.last_key() > start.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &range.1 {
+            Bound::Included(end) => self.first_key() <= end.as_slice(),
+            Bound::Excluded(end) => self.first_key() < end.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index.blocks[block];
+        Ok(bincode::deserialize(&read_frame(&self.file, self.id, handle.offset, handle.len)?)?)
+    }
+
+    // Looks up a key. Returns `None` if the table holds no entry for it, and `Some(None)` if
+    // it holds a tombstone. The bloom filter is consulted before reading anything from disk,
+    // and the outcome recorded in `counters`.
+    fn get(&self, key: &[u8], counters: &FilterCounters) -> Result<Option<Option<Vec<u8>>>> {
+        if key < self.first_key() || key > self.last_key() {
+            return Ok(None);
+        }
+        if self.filter.is_enabled() {
+            let may_contain = self.filter.may_contain(key);
+            counters.checked(may_contain);
+            if !may_contain {
+                return Ok(None);
+            }
+        }
+
+        // The key can only be in the first block whose last key is not smaller.
+        let block = self.index.blocks.partition_point(|handle| handle.last_key.as_slice() < key);
+        let mut entries = self.read_block(block)?;
+        let value = entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|i| entries.swap_remove(i).1);
+        if value.is_none() && self.filter.is_enabled() {
+            counters.false_positive();
+        }
+        Ok(value)
+    }
+}
+
+// Writes a new SSTable one entry at a time, in key order.
+struct TableBuilder {
+    id: u64,
+    writer: BufWriter<File>,
+    // The number of bytes written so far.
+    offset: u64,
+    first_key: Option<Vec<u8>>,
+    // The entries of the block being filled, and their approximate size.
+    block: Vec<Entry>,
+    block_size: usize,
+    blocks: Vec<BlockHandle>,
+    // The hash of every key added, for the bloom filter.
+    key_hashes: Vec<u64>,
+    bits_per_key: usize,
+}
+
+impl TableBuilder {
+    fn create(dir: &Path, id: u64, bits_per_key: usize) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            id,
+            writer: BufWriter::new(File::create(table_path(dir, id))?),
+            offset: 0,
+            first_key: None,
+            block: Vec::new(),
+            block_size: 0,
+            blocks: Vec::new(),
+            key_hashes: Vec::new(),
+            bits_per_key,
+        })
+    }
+
+    fn add(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.clone());
+        }
+        self.key_hashes.push(hash_key(&key));
+        self.block_size += key.len() + value.as_ref().map_or(0, Vec::len);
+        self.block.push((key, value));
+        if self.block_size >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    // Returns the approximate size of the table so far.
+    fn size(&self) -> u64 {
+        self.offset + self.block_size as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let Some((last_key, _)) = self.block.last() else {
+            return Ok(());
+        };
+        let last_key = last_key.clone();
+
+        let frame = frame(&bincode::serialize(&self.block)?)?;
+        self.writer.write_all(&frame)?;
+        self.blocks.push(BlockHandle {last_key, offset: self.offset, len: frame.len() as u64});
+        self.offset += frame.len() as u64;
+        self.block.clear();
+        self.block_size = 0;
+        Ok(())
+    }
+
+    // Writes the index and footer, syncs the file, writes the bloom filter and opens the
+    // finished table. At least one entry must have been added.
+    fn finish(mut self, dir: &Path) -> Result<Table> {
+        self.finish_block()?;
+        let index = TableIndex {
+            first_key: self.first_key.take().expect("tables are never empty"),
+            blocks: mem::take(&mut self.blocks),
+        };
+
+        let frame = frame(&bincode::serialize(&index)?)?;
+        self.writer.write_all(&frame)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        self.writer.write_all(TABLE_MAGIC)?;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        write_filter(dir, self.id, &BloomFilter::build(&self.key_hashes, self.bits_per_key))?;
+        Table::open(dir, self.id, self.bits_per_key)
+    }
+}
+
+// Reads the entries of one SSTable within a range, a block at a time.
+struct TableIter {
+    table: Arc<Table>,
+    range: KeyRange,
+    reverse: bool,
+    // The blocks that may hold keys within the range and have not been read yet.
+    blocks: std::ops::Range<usize>,
+    // The unread entries of the current block.
+    entries: std::vec::IntoIter<Entry>,
+}
+
+impl TableIter {
+    fn new(table: Arc<Table>, range: KeyRange, reverse: bool) -> TableIter {
+        let blocks = &table.index.blocks;
+        let first = match &range.0 {
+            Bound::Included(start) => blocks.partition_point(|handle| handle.last_key < *start),
+            Bound::Excluded(start) => blocks.partition_point(|handle| handle.last_key <= *start),
+            Bound::Unbounded => 0,
+        };
+        // Keys up to the end of the range can still be in the first block that ends past it.
+        let end = match &range.1 {
+            Bound::Included(end) | Bound::Excluded(end) => {
+                (blocks.partition_point(|handle| handle.last_key < *end) + 1).min(blocks.len())
+            }
+            Bound::Unbounded => blocks.len(),
+        };
+
+        TableIter {
+            blocks: first..end.max(first),
+            table,
+            range,
+            reverse,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = if self.reverse { self.entries.next_back() } else { self.entries.next() };
+            match entry {
+                Some(entry) if self.range.contains(&entry.0) => return Some(Ok(entry)),
+                // The first and last blocks can hold keys outside of the range.
+                Some(_) => continue,
+                None => {}
+            }
+
+            let block = if self.reverse { self.blocks.next_back() } else { self.blocks.next() }?;
+            match self.table.read_block(block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    self.blocks = 0..0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// Merges sorted sources into one sorted stream. When several sources hold the same key, the
+// entry from the source listed first wins, so sources must be ordered from newest to oldest.
+struct MergeIter {
+    sources: Vec<Source>,
+    // The next entry of each source, or `None` once it is exhausted.
+    heads: Vec<Option<Entry>>,
+    reverse: bool,
+}
+
+impl MergeIter {
+    fn new(mut sources: Vec<Source>, reverse: bool) -> Result<MergeIter> {
+        let heads = sources.iter_mut().map(|source| source.next().transpose()).collect::<Result<_>>()?;
+        Ok(MergeIter {sources, heads, reverse})
+    }
+
+    // Moves the given source on to its next entry.
+    fn advance(&mut self, source: usize) -> Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut best: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((key, _)) = head else {
+                continue;
+            };
+            let better = match best.and_then(|best| self.heads[best].as_ref()) {
+                None => true,
+                Some((best_key, _)) if self.reverse => key > best_key,
+                Some((best_key, _)) => key < best_key,
+            };
+            if better {
+                best = Some(i);
+            }
+        }
+
+        let best = best?;
+        let entry = self.heads[best].take().expect("the chosen source has an entry");
+        let mut result = self.advance(best);
+        // Older versions of the same key in the other sources are skipped.
+        for i in 0..self.heads.len() {
+            if self.heads[i].as_ref().is_some_and(|(key, _)| *key == entry.0) {
+                result = result.and(self.advance(i));
+            }
+        }
+        Some(result.map(|()| entry))
+    }
+}
+
+// The on-disk record of which SSTables make up the store.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    // The ids of the SSTables in each level. Level 0 is ordered from oldest to newest, the
+    // other levels by key.
+    levels: Vec<Vec<u64>>,
+    // The generation of the newest WAL segment whose writes are all in SSTables.
+    flushed_wal: u64,
+}
+
+// One version of the set of SSTables. Versions are never modified once installed, so readers
+// can keep using one while a flush or compaction installs the next.
+#[derive(Clone)]
+struct Version {
+    // Level 0 holds flushed memtables, oldest first, whose key ranges may overlap. Every other
+    // level holds SSTables with disjoint key ranges, sorted by key.
+    levels: Vec<Vec<Arc<Table>>>,
+    flushed_wal: u64,
+}
+
+impl Version {
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            levels: self.levels.iter().map(|tables| tables.iter().map(|table| table.id).collect()).collect(),
+            flushed_wal: self.flushed_wal,
+        }
+    }
+
+    // Looks up a key, searching from the newest SSTable to the oldest. Returns `Some(None)`
+    // for a removed key.
+    fn get(&self, key: &[u8], counters: &FilterCounters) -> Result<Option<Option<Vec<u8>>>> {
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(key, counters)? {
+                return Ok(Some(value));
+            }
+        }
+        for tables in &self.levels[1..] {
+            let i = tables.partition_point(|table| table.last_key() < key);
+            if let Some(table) = tables.get(i)
+                && let Some(value) = table.get(key, counters)?
+            {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    // Adds a source for every SSTable in level 0 and one for each further level, newest first.
+    fn sources(&self, range: &KeyRange, reverse: bool, sources: &mut Vec<Source>) {
+        for table in self.levels[0].iter().rev() {
+            if table.overlaps(range) {
+                sources.push(Box::new(TableIter::new(Arc::clone(table), range.clone(), reverse)));
+            }
+        }
+        for tables in &self.levels[1..] {
+            let mut tables: Vec<Arc<Table>> = tables.iter().filter(|table| table.overlaps(range)).cloned().collect();
+            if reverse {
+                tables.reverse();
+            }
+            let range = range.clone();
+            sources.push(Box::new(tables.into_iter().flat_map(move |table| TableIter::new(table, range.clone(), reverse))));
+        }
+    }
+
+    fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|table| table.size).sum()
+    }
+
+    // Returns the level most in need of compaction, if any level is over its limit.
+    fn pick_compaction(&self) -> Option<usize> {
+        if self.levels[0].len() >= L0_COMPACTION_TRIGGER {
+            return Some(0);
+        }
+        let mut max_bytes = L1_MAX_BYTES;
+        for level in 1..self.levels.len() {
+            if self.level_size(level) > max_bytes {
+                return Some(level);
+            }
+            max_bytes = max_bytes.saturating_mul(LEVEL_SIZE_MULTIPLIER);
+        }
+        None
+    }
+}
+
+// Everything readers need to see a consistent state of the store.
+struct State {
+    // The memtable receiving new writes.
+    mem: Memtable,
+    // Full memtables waiting to be flushed, oldest first.
+    frozen: Vec<Arc<Memtable>>,
+    version: Arc<Version>,
+}
+
+/// A storage engine built as a log-structured merge tree, for write-heavy workloads with
+/// datasets much larger than memory.
+///
+/// Writes are appended to a write-ahead log, using the same segment files and sync policies
+/// as [`KvStore`](crate::KvStore), and applied to a sorted in-memory memtable. Once the
+/// memtable reaches the configured size, it is frozen and flushed in the background to an
+/// immutable SSTable (`{id}.sst`), after which its log segments are deleted. Each SSTable is
+/// a sequence of sorted data blocks followed by an index of their last keys, so a lookup
+/// reads at most one block per table.
+///
+/// SSTables are organized in levels. Flushed memtables land in level 0, and once enough have
+/// accumulated they are merged into level 1. Every deeper level may grow ten times larger than
+/// the one above it before one of its SSTables is merged into the next. A `MANIFEST` file
+/// records which SSTables belong to each level.
+///
+/// Cloning is a cheap, lightweight operation as it only increments atomic reference counts.
+#[derive(Clone)]
+pub struct LsmEngine {
+    // The directory holding the WAL segments, SSTables and manifest.
+    dir: Arc<PathBuf>,
+    // The WAL segment new writes are appended to. Its lock also serializes writes.
+    writer: Arc<Mutex<ActiveSegment>>,
+    syncer: Arc<Syncer>,
+    state: Arc<RwLock<State>>,
+    // The id to give the next WAL segment or SSTable. Both share one sequence.
+    next_id: Arc<AtomicU64>,
+    // Set while a background thread is flushing memtables or compacting.
+    work_scheduled: Arc<AtomicBool>,
+    // Held while the set of SSTables is being changed.
+    work_lock: Arc<Mutex<()>>,
+    filter_counters: Arc<FilterCounters>,
+    // When the last compaction of a level finished, in milliseconds since the UNIX epoch, or 0
+    // if there was none since the engine was opened.
+    last_compaction: Arc<AtomicU64>,
+    // The lock on the directory, held for as long as any handle to the engine is open.
+    _dir_lock: Arc<DirLock>,
+    options: Arc<KvStoreOptions>,
+}
+
+impl LsmEngine {
+    /// Opens an `LsmEngine` in the given directory, creating it if it doesn't exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmEngine> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens an `LsmEngine` in the given directory using the provided options.
+    ///
+    /// The sync policy applies to the write-ahead log, and the memtable size sets when
+    /// memtables are flushed. The other options are not used, and neither read-only mode nor
+    /// recovery targets are supported.
+    /// Errors with [`KvsError::AlreadyLocked`] if another process has the directory open already,
+    /// and with [`KvsError::UnknownFormat`] if any of its files is in a format this version does not know.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<LsmEngine> {
+        if options.read_only {
+            return Err(KvsError::Unsupported("read-only mode".into()));
+        }
+        if options.recovery_target.is_some() {
+            return Err(KvsError::Unsupported("point-in-time recovery".into()));
+        }
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let dir_lock = Arc::new(DirLock::acquire(&dir)?);
+
+        let manifest = read_manifest(&dir)?;
+        let mut levels = Vec::with_capacity(manifest.levels.len().max(1));
+        for ids in &manifest.levels {
+            let tables = ids.iter().map(|&id| Table::open(&dir, id, options.bloom_bits_per_key).map(Arc::new));
+            levels.push(tables.collect::<Result<Vec<_>>>()?);
+        }
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+
+        // SSTables missing from the manifest were being written by a flush or compaction that
+        // did not finish. Their contents are still in the WAL or the SSTables they would replace.
+        // Filters left without their SSTable are removed along with them.
+        let live: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+        let mut max_id = manifest.flushed_wal;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new(MANIFEST_TMP_EXTENSION)) {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let extension = path.extension();
+            if extension != Some(OsStr::new(TABLE_EXTENSION)) && extension != Some(OsStr::new(FILTER_EXTENSION)) {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(OsStr::to_str).and_then(|s| s.parse::<u64>().ok()) else {
+                continue;
+            };
+            if live.contains(&id) {
+                max_id = max_id.max(id);
+            } else {
+                warn!("Discarding unfinished file {}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        // Replay the WAL segments that were not flushed yet into the memtable.
+        let mut mem = Memtable::default();
+        let generations: Vec<u64> = sorted_generations(&dir)?;
+        let mut active = None;
+        for &generation in &generations {
+            let path = segment_path(&dir, generation);
+            if generation <= manifest.flushed_wal {
+                // The flush that made this segment obsolete stopped before deleting it.
+                fs::remove_file(&path)?;
+                continue;
+            }
+            max_id = max_id.max(generation);
+
+            let file = File::open(&path)?;
+            let file_len = file.metadata()?.len();
+            // There is no upgrade path for the WAL, which is only ever a few memtables long.
+            let data_start = match read_engine_file_header(&file, &path, ENGINE_NAME) {
+                Ok(header) => header.map(|(_, len)| len),
+                Err(KvsError::UpgradeRequired(version)) => {
+                    return Err(KvsError::UnknownFormat(format!("{} is in format version {}", path.display(), version)));
+                }
+                Err(e) => return Err(e),
+            };
+            // A segment without a complete header is what a crash right after it was started
+            // leaves behind, and holds no records.
+            let valid_len = match data_start {
+                Some(data_start) => {
+                    let mut reader = BufReader::new(file);
+                    reader.seek(SeekFrom::Start(data_start))?;
+                    read_segment(generation, reader, data_start, file_len, None, |cmd, _, _, _| {
+                        mem.apply(cmd);
+                        Ok(())
+                    })?
+                }
+                None => 0,
+            };
+
+            let sealed = fs::metadata(&path)?.permissions().readonly();
+            if valid_len < file_len {
+                // Only the newest segment can end in a torn write. Older ones were synced when sealed.
+                if Some(&generation) != generations.last() || sealed {
+                    return Err(KvsError::Corruption {segment: generation, offset: valid_len});
+                }
+                warn!(
+                    "Truncating {} bytes of incomplete records at the end of WAL segment {}",
+                    file_len - valid_len,
+                    generation
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+            active = (!sealed).then_some(generation);
+        }
+
+        let mut writer = match active {
+            Some(generation) => ActiveSegment::open(&dir, generation)?,
+            None => {
+                max_id += 1;
+                ActiveSegment::open(&dir, max_id)?
+            }
+        };
+        writer.start_with_header(ENGINE_NAME, CreationOptions::new(&options))?;
+        mem.wal = writer.generation;
+
+        let engine = LsmEngine {
+            dir: Arc::new(dir),
+            syncer: Arc::new(Syncer::new(options.sync_policy, Some(Arc::clone(&writer.file)))),
+            writer: Arc::new(Mutex::new(writer)),
+            state: Arc::new(RwLock::new(State {
+                mem,
+                frozen: Vec::new(),
+                version: Arc::new(Version {levels, flushed_wal: manifest.flushed_wal}),
+            })),
+            next_id: Arc::new(AtomicU64::new(max_id + 1)),
+            work_scheduled: Arc::new(AtomicBool::new(false)),
+            work_lock: Arc::new(Mutex::new(())),
+            filter_counters: Arc::new(FilterCounters::default()),
+            last_compaction: Arc::new(AtomicU64::new(0)),
+            _dir_lock: dir_lock,
+            options: Arc::new(options),
+        };
+        engine.maybe_schedule_work();
+        Ok(engine)
+    }
+
+    /// Freezes the memtable, flushes it to an SSTable and compacts every level that is over
+    /// its size limit. This normally happens in the background.
+    pub fn compact(&self) -> Result<()> {
+        {
+            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            self.freeze(&mut writer)?;
+        }
+        self.run_background_work()
+    }
+
+    /// Returns how often the bloom filters of the SSTables spared a lookup from reading a block,
+    /// and how often they failed to.
+    pub fn filter_stats(&self) -> FilterStats {
+        self.filter_counters.stats()
+    }
+
+    // Looks up the current value of a key, from the newest writes to the oldest.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let version = {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            for memtable in std::iter::once(&state.mem).chain(state.frozen.iter().rev().map(|memtable| &**memtable)) {
+                if let Some(value) = memtable.entries.get(key) {
+                    return Ok(value.clone());
+                }
+            }
+            Arc::clone(&state.version)
+        };
+        Ok(version.get(key, &self.filter_counters)?.flatten())
+    }
+
+    // Returns the entries within `range` from the memtables and SSTables merged into one stream,
+    // with tombstones left in.
+    fn merged(&self, range: &KeyRange, reverse: bool) -> Result<MergeIter> {
+        let mut sources: Vec<Source> = Vec::new();
+        let version = {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            sources.push(state.mem.source(range, reverse));
+            for memtable in state.frozen.iter().rev() {
+                sources.push(memtable.source(range, reverse));
+            }
+            Arc::clone(&state.version)
+        };
+        version.sources(range, reverse, &mut sources);
+        MergeIter::new(sources, reverse)
+    }
+
+    // Appends a command to the WAL and applies it to the memtable, freezing the memtable once
+    // it is full. Returns the ticket to wait on before the write may be acknowledged.
+    fn append(&self, writer: &mut ActiveSegment, cmd: Command) -> Result<u64> {
+        // Records in the WAL need no sequence number. Their order is all that matters.
+        writer.append(&cmd, 0)?;
+        let ticket = self.syncer.appended()?;
+
+        let full = {
+            let mut state = self.state.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            state.mem.apply(cmd);
+            state.mem.size >= self.options.memtable_size
+        };
+        if full {
+            self.freeze(writer)?;
+        }
+        Ok(ticket)
+    }
+
+    // Moves the memtable to the list of frozen memtables and starts a new one, along with a
+    // new WAL segment. Does nothing if the memtable is empty.
+    fn freeze(&self, writer: &mut ActiveSegment) -> Result<()> {
+        {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            if state.mem.entries.is_empty() {
+                return Ok(());
+            }
+        }
+
+        let generation = self.next_id.fetch_add(1, Ordering::SeqCst);
+        writer.roll(&self.dir, generation)?;
+        self.syncer.segment_rolled(Arc::clone(&writer.file))?;
+
+        let mut state = self.state.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let frozen = mem::replace(&mut state.mem, Memtable::new(generation));
+        state.frozen.push(Arc::new(frozen));
+        Ok(())
+    }
+
+    // Waits for a write to be synced, then starts background work it may have made necessary.
+    fn finish_write(&self, ticket: u64) -> Result<()> {
+        self.syncer.wait(ticket)?;
+        self.maybe_schedule_work();
+        Ok(())
+    }
+
+    // Spawns a background thread to flush frozen memtables and compact levels, if any need it.
+    fn maybe_schedule_work(&self) {
+        let needed = match self.state.read() {
+            Ok(state) => !state.frozen.is_empty() || state.version.pick_compaction().is_some(),
+            Err(_) => false,
+        };
+        if !needed || self.work_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let engine = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = engine.run_background_work() {
+                error!("LSM background work failed: {}", e);
+            }
+            engine.work_scheduled.store(false, Ordering::SeqCst);
+            // Writes made in the meantime may have filled another memtable.
+            engine.maybe_schedule_work();
+        });
+    }
+
+    // Flushes every frozen memtable, then compacts until no level is over its limit.
+    fn run_background_work(&self) -> Result<()> {
+        let _guard = self.work_lock.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        while self.flush_memtable()? || self.compact_level()? {}
+        Ok(())
+    }
+
+    // Writes the oldest frozen memtable to level 0. Returns `false` if there was none.
+    fn flush_memtable(&self) -> Result<bool> {
+        let (memtable, version) = {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            (state.frozen.first().cloned(), Arc::clone(&state.version))
+        };
+        let Some(memtable) = memtable else {
+            return Ok(false);
+        };
+
+        let entries = memtable.entries.iter().map(|(key, value)| Ok((key.clone(), value.clone())));
+        let tables = self.write_tables(entries, false)?;
+        let mut version = (*version).clone();
+        version.levels[0].extend(tables);
+        version.flushed_wal = memtable.wal;
+        self.install(version, |state| {
+            state.frozen.remove(0);
+        })?;
+
+        // The memtable's writes are safe in level 0 now, so its WAL segments can go.
+        for generation in sorted_generations(&self.dir)? {
+            if generation <= memtable.wal {
+                fs::remove_file(segment_path(&self.dir, generation))?;
+            }
+        }
+        debug!("Flushed memtable of WAL segment {} to level 0", memtable.wal);
+        Ok(true)
+    }
+
+    // Merges SSTables of the level most in need of compaction into the next level. Returns
+    // `false` if no level needed it.
+    //
+    // All of level 0 is compacted at once, since its SSTables overlap. From deeper levels,
+    // the oldest SSTable is merged with the SSTables it overlaps in the next level.
+    fn compact_level(&self) -> Result<bool> {
+        let version = {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            Arc::clone(&state.version)
+        };
+        let Some(level) = version.pick_compaction() else {
+            return Ok(false);
+        };
+
+        // The upper tables are ordered newest first, so their entries take precedence.
+        let upper: Vec<Arc<Table>> = if level == 0 {
+            version.levels[0].iter().rev().cloned().collect()
+        } else {
+            version.levels[level].iter().min_by_key(|table| table.id).cloned().into_iter().collect()
+        };
+        let first = upper.iter().map(|table| table.first_key()).min().expect("compactions have input").to_vec();
+        let last = upper.iter().map(|table| table.last_key()).max().expect("compactions have input").to_vec();
+        let span = (Bound::Included(first), Bound::Included(last));
+        let lower: Vec<Arc<Table>> = version
+            .levels
+            .get(level + 1)
+            .map_or_else(Vec::new, |tables| tables.iter().filter(|table| table.overlaps(&span)).cloned().collect());
+
+        let mut version = (*version).clone();
+        if version.levels.len() == level + 1 {
+            version.levels.push(Vec::new());
+        }
+        let outputs = if level > 0 && lower.is_empty() {
+            // Nothing to merge with, so the table moves down as it is.
+            upper.clone()
+        } else {
+            // Tombstones only have to be kept while older versions may exist further down.
+            let drop_tombstones = version.levels.iter().skip(level + 2).all(Vec::is_empty);
+            let sources = upper
+                .iter()
+                .chain(&lower)
+                .map(|table| Box::new(TableIter::new(Arc::clone(table), (Bound::Unbounded, Bound::Unbounded), false)) as Source)
+                .collect();
+            self.write_tables(MergeIter::new(sources, false)?, drop_tombstones)?
+        };
+
+        let inputs: HashSet<u64> = upper.iter().chain(&lower).map(|table| table.id).collect();
+        for tables in &mut version.levels[level..=level + 1] {
+            tables.retain(|table| !inputs.contains(&table.id));
+        }
+        version.levels[level + 1].extend(outputs.iter().cloned());
+        version.levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.install(version, |_| {})?;
+        self.last_compaction.store(now_millis(), Ordering::SeqCst);
+
+        // Readers still using the old version keep their own handles to the files.
+        let kept: HashSet<u64> = outputs.iter().map(|table| table.id).collect();
+        for &id in inputs.difference(&kept) {
+            fs::remove_file(table_path(&self.dir, id))?;
+            fs::remove_file(table_path(&self.dir, id).with_extension(FILTER_EXTENSION))?;
+        }
+        info!(
+            "Compacted {} SSTables from level {} into {} SSTables in level {}",
+            inputs.len(),
+            level,
+            outputs.len(),
+            level + 1
+        );
+        Ok(true)
+    }
+
+    // Writes sorted entries to new SSTables of about `TABLE_SIZE` each, leaving out tombstones
+    // if asked to.
+    fn write_tables(&self, entries: impl Iterator<Item = Result<Entry>>, drop_tombstones: bool) -> Result<Vec<Arc<Table>>> {
+        let mut tables = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in entries {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+
+            if builder.is_none() {
+                builder = Some(TableBuilder::create(&self.dir, self.next_id.fetch_add(1, Ordering::SeqCst), self.options.bloom_bits_per_key)?);
+            }
+            let current = builder.as_mut().expect("a builder was just created");
+            current.add(key, value)?;
+            if current.size() >= TABLE_SIZE
+                && let Some(full) = builder.take()
+            {
+                tables.push(Arc::new(full.finish(&self.dir)?));
+            }
+        }
+        if let Some(builder) = builder {
+            tables.push(Arc::new(builder.finish(&self.dir)?));
+        }
+        Ok(tables)
+    }
+
+    // Records a new version in the manifest, then makes it the current one. `update` makes any
+    // other changes to the state that must be seen together with it.
+    fn install(&self, version: Version, update: impl FnOnce(&mut State)) -> Result<()> {
+        write_manifest(&self.dir, &version.manifest())?;
+        let mut state = self.state.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        state.version = Arc::new(version);
+        update(&mut state);
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            self.append(&mut writer, Command::Set {key, value})?
+        };
+        self.finish_write(ticket)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lookup(key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            // Checking under the writer lock keeps a missing key from leaving a tombstone behind.
+            if self.lookup(key)?.is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            self.append(&mut writer, Command::Remove {key: key.to_vec()})?
+        };
+        self.finish_write(ticket)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let range: KeyRange = (range.start_bound().cloned(), range.end_bound().cloned());
+
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut entries = Vec::new();
+        for entry in self.merged(&range, options.reverse)? {
+            if entries.len() >= limit {
+                break;
+            }
+            if let (key, Some(value)) = entry? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let ticket = {
+            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            let mut lookup_error = None;
+            let checked = batch.check_removes(|key| match self.lookup(key) {
+                Ok(value) => value.is_some(),
+                Err(e) => {
+                    lookup_error.get_or_insert(e);
+                    false
+                }
+            });
+            if let Some(e) = lookup_error {
+                return Err(e);
+            }
+            checked?;
+
+            let commands = batch
+                .into_ops()
+                .into_iter()
+                .map(|op| match op {
+                    BatchOp::Set {key, value} => Command::Set {key, value},
+                    BatchOp::Remove {key} => Command::Remove {key},
+                })
+                .collect();
+            self.append(&mut writer, Command::Batch {commands})?
+        };
+        self.finish_write(ticket)
+    }
+
+    fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()> {
+        let ticket = {
+            // Holding the writer lock keeps the key from changing between the check and the write.
+            let mut writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+            let current = self.lookup(&key)?;
+            if !condition.matches(current.as_deref()) {
+                return Err(KvsError::ConditionFailed);
+            }
+            match new {
+                Some(value) => self.append(&mut writer, Command::Set {key, value})?,
+                None if current.is_some() => self.append(&mut writer, Command::Remove {key})?,
+                None => return Ok(()),
+            }
+        };
+        self.finish_write(ticket)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let writer = self.writer.lock().map_err(|_| KvsError::Internal("Mutex poisoned".into()))?;
+        writer.file.sync_data()?;
+        Ok(())
+    }
+
+    // Operations are not timed and nothing is compressed, so only the sizes and the bloom
+    // filters are reported. Counting the keys merges every memtable and SSTable, as removed
+    // keys may still have older versions in deeper levels.
+    fn stats(&self) -> Result<StoreStats> {
+        let (mut keys, mut live_bytes) = (0, 0);
+        for entry in self.merged(&(Bound::Unbounded, Bound::Unbounded), false)? {
+            if let (key, Some(value)) = entry? {
+                keys += 1;
+                live_bytes += (key.len() + value.len()) as u64;
+            }
+        }
+
+        let mut total_bytes = {
+            let state = self.state.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+            state.version.levels.iter().flatten().map(|table| table.size).sum()
+        };
+        let segments = sorted_generations(&self.dir)?;
+        for &generation in &segments {
+            match fs::metadata(segment_path(&self.dir, generation)) {
+                Ok(metadata) => total_bytes += metadata.len(),
+                // A flush may have removed the segment since the directory was listed.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let last_compaction = match self.last_compaction.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        };
+        Ok(StoreStats {
+            keys,
+            total_bytes,
+            live_bytes,
+            segments: segments.len() as u64,
+            last_compaction,
+            compression: CompressionStats::default(),
+            operations: Vec::new(),
+            filters: Some(self.filter_stats()),
+        })
+    }
+}
+
+// Frames a serialized block with a CRC32 of its contents and its length.
+fn frame(payload: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| KvsError::Internal(format!("Block of {} bytes is too large", payload.len())))?;
+
+    let mut buf = Vec::with_capacity(BLOCK_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(payload);
+    Ok(buf)
+}
+
+// Reads and verifies the framed block at the given location of file `id`, returning its contents.
+fn read_frame(file: &File, id: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let corrupt = KvsError::Corruption {segment: id, offset};
+    if len < BLOCK_HEADER_LEN as u64 {
+        return Err(corrupt);
+    }
+
+    let mut buf = vec![0; len as usize];
+    read_exact_at(file, &mut buf, offset)?;
+    let crc = u32::from_le_bytes(buf[..4].try_into().unwrap());
+    let payload_len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as u64;
+    if payload_len + BLOCK_HEADER_LEN as u64 != len || crc != crc32fast::hash(&buf[BLOCK_HEADER_LEN..]) {
+        return Err(corrupt);
+    }
+    buf.drain(..BLOCK_HEADER_LEN);
+    Ok(buf)
+}
+
+// Reads the manifest, or returns an empty one if the store is new.
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let buf = match fs::read(dir.join(MANIFEST_FILE)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Manifest::default()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let path = dir.join(MANIFEST_FILE);
+    if buf.len() < MANIFEST_PREFIX_LEN || buf[..4] != MANIFEST_MAGIC {
+        return Err(KvsError::UnknownFormat(format!("{} is not an LSM manifest", path.display())));
+    }
+    let version = u32::from_le_bytes(buf[4..MANIFEST_PREFIX_LEN].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(KvsError::UnknownFormat(format!("{} is in format version {}", path.display(), version)));
+    }
+
+    let frame = &buf[MANIFEST_PREFIX_LEN..];
+    let crc_matches = frame.len() >= BLOCK_HEADER_LEN
+        && u32::from_le_bytes(frame[4..8].try_into().unwrap()) as usize + BLOCK_HEADER_LEN == frame.len()
+        && u32::from_le_bytes(frame[..4].try_into().unwrap()) == crc32fast::hash(&frame[BLOCK_HEADER_LEN..]);
+    if !crc_matches {
+        return Err(KvsError::Corruption {segment: 0, offset: MANIFEST_PREFIX_LEN as u64});
+    }
+    Ok(bincode::deserialize(&frame[BLOCK_HEADER_LEN..])?)
+}
+
+// Replaces the manifest. The new one is written to a temporary file first and renamed into
+// place, so a crash leaves either the old or the new manifest behind.
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let tmp_path = path.with_extension(MANIFEST_TMP_EXTENSION);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&MANIFEST_MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&frame(&bincode::serialize(manifest)?)?)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)
+}
+
+// Reads the bloom filter of the SSTable with the given id. A filter in another format version
+// is an error, so that it is rebuilt.
+fn read_filter(dir: &Path, id: u64) -> Result<BloomFilter> {
+    let path = table_path(dir, id).with_extension(FILTER_EXTENSION);
+    let file = File::open(&path)?;
+    let len = file.metadata()?.len();
+    let (version, filter): (u32, BloomFilter) = bincode::deserialize(&read_frame(&file, id, 0, len)?)?;
+    if version != FORMAT_VERSION {
+        return Err(KvsError::UnknownFormat(format!("{} is in format version {}", path.display(), version)));
+    }
+    Ok(filter)
+}
+
+// Writes the bloom filter of the SSTable with the given id, tagged with the format version,
+// and syncs it.
+fn write_filter(dir: &Path, id: u64, filter: &BloomFilter) -> Result<()> {
+    let mut file = File::create(table_path(dir, id).with_extension(FILTER_EXTENSION))?;
+    file.write_all(&frame(&bincode::serialize(&(FORMAT_VERSION, filter))?)?)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Returns the path of the SSTable with the given id.
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", id, TABLE_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Returns the number of files in the directory with the given extension.
+    fn count_files(dir: &Path, extension: &str) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(OsStr::new(extension)))
+            .count()
+    }
+
+    #[test]
+    fn test_memtables_flush_and_compact() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().memtable_size(16 * 1024);
+        let engine = LsmEngine::open_with_options(temp_dir.path(), options.clone()).unwrap();
+
+        for i in 0..5000 {
+            engine.set(format!("key{:05}", i), format!("value{}", i)).unwrap();
+        }
+        for i in (0..5000).step_by(2) {
+            engine.set(format!("key{:05}", i), "even".to_owned()).unwrap();
+        }
+        for i in (0..5000).step_by(5) {
+            engine.remove(format!("key{:05}", i)).unwrap();
+        }
+        engine.compact().unwrap();
+
+        {
+            let state = engine.state.read().unwrap();
+            assert!(state.frozen.is_empty());
+            assert!(state.version.levels[0].len() < L0_COMPACTION_TRIGGER);
+            assert!(state.version.levels.len() > 1);
+        }
+        // Only the active WAL segment is left.
+        assert_eq!(count_files(temp_dir.path(), "log"), 1);
+
+        let check = |engine: &LsmEngine| {
+            assert_eq!(engine.get("key00000".to_owned()).unwrap(), None);
+            assert_eq!(engine.get("key00001".to_owned()).unwrap(), Some("value1".to_owned()));
+            assert_eq!(engine.get("key00002".to_owned()).unwrap(), Some("even".to_owned()));
+            assert_eq!(engine.get("key04999".to_owned()).unwrap(), Some("value4999".to_owned()));
+            assert_eq!(engine.scan(..).unwrap().len(), 4000);
+
+            let scanned = engine.scan_prefix(b"key0499", ScanOptions {limit: Some(3), reverse: true}).unwrap();
+            let keys: Vec<&[u8]> = scanned.iter().map(|(key, _)| key.as_slice()).collect();
+            assert_eq!(keys, vec![&b"key04999"[..], b"key04998", b"key04997"]);
+        };
+        check(&engine);
+
+        // Every SSTable has a bloom filter, which spares most lookups of missing keys a read.
+        assert_eq!(count_files(temp_dir.path(), "filter"), count_files(temp_dir.path(), "sst"));
+        for i in 0..1000 {
+            assert_eq!(engine.get(format!("key{:05}x", i)).unwrap(), None);
+        }
+        let stats = engine.filter_stats();
+        assert!(stats.skipped > 900);
+        assert!(stats.false_positive_rate() < 0.1);
+
+        // The filters are reported along with the sizes, so that they reach the server's clients.
+        let store_stats = KvsEngine::stats(&engine).unwrap();
+        assert_eq!(store_stats.filters, Some(stats));
+        assert_eq!((store_stats.keys, store_stats.segments), (4000, 1));
+        assert!(store_stats.total_bytes > store_stats.live_bytes && store_stats.live_bytes > 0);
+        assert!(store_stats.last_compaction.is_some());
+
+        drop(engine);
+        let engine = LsmEngine::open_with_options(temp_dir.path(), options).unwrap();
+        check(&engine);
+    }
+
+    #[test]
+    fn test_wal_is_replayed() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        {
+            let engine = LsmEngine::open(temp_dir.path()).unwrap();
+            engine.set("flushed".to_owned(), "1".to_owned()).unwrap();
+            engine.compact().unwrap();
+            engine.set("flushed".to_owned(), "2".to_owned()).unwrap();
+            engine.set("removed".to_owned(), "1".to_owned()).unwrap();
+            engine.remove("removed".to_owned()).unwrap();
+        }
+
+        // A torn write at the end of the WAL is dropped.
+        let wal = segment_path(temp_dir.path(), sorted_generations(temp_dir.path()).unwrap()[0]);
+        let mut file = OpenOptions::new().append(true).open(&wal).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+
+        let engine = LsmEngine::open(temp_dir.path()).unwrap();
+        assert_eq!(engine.get("flushed".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(engine.get("removed".to_owned()).unwrap(), None);
+        engine.set("after".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(engine.scan(..).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_unknown_formats() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        {
+            let engine = LsmEngine::open(temp_dir.path()).unwrap();
+            engine.set("flushed".to_owned(), "1".to_owned()).unwrap();
+            engine.compact().unwrap();
+            engine.set("logged".to_owned(), "1".to_owned()).unwrap();
+        }
+        let id = read_manifest(temp_dir.path()).unwrap().levels[0][0];
+        let table = table_path(temp_dir.path(), id);
+        let wal = segment_path(temp_dir.path(), *sorted_generations(temp_dir.path()).unwrap().last().unwrap());
+        let manifest = temp_dir.path().join(MANIFEST_FILE);
+
+        // A future format version of any file keeps the engine from opening.
+        let footer_version = fs::metadata(&table).unwrap().len() - FOOTER_LEN + 16;
+        for (path, offset) in [(&manifest, 4), (&table, footer_version), (&wal, 4)] {
+            let original = fs::read(path).unwrap();
+            let mut contents = original.clone();
+            contents[offset as usize..offset as usize + 4].copy_from_slice(&99u32.to_le_bytes());
+            fs::write(path, contents).unwrap();
+            match LsmEngine::open(temp_dir.path()) {
+                Err(KvsError::UnknownFormat(message)) => assert!(message.contains("version 99"), "{}", message),
+                other => panic!("expected an unknown format, got {:?}", other.map(|_| ())),
+            }
+            fs::write(path, original).unwrap();
+        }
+
+        // So does a WAL segment from before there was a file header.
+        let original = fs::read(&wal).unwrap();
+        let (_, data_start) = read_engine_file_header(&File::open(&wal).unwrap(), &wal, ENGINE_NAME).unwrap().unwrap();
+        fs::write(&wal, &original[data_start as usize..]).unwrap();
+        assert!(matches!(LsmEngine::open(temp_dir.path()), Err(KvsError::UnknownFormat(_))));
+        fs::write(&wal, original).unwrap();
+
+        // A bloom filter in another format is only rebuilt.
+        let filter = table.with_extension(FILTER_EXTENSION);
+        fs::write(&filter, frame(&bincode::serialize(&(99u32, BloomFilter::build(&[], 10))).unwrap()).unwrap()).unwrap();
+        let engine = LsmEngine::open(temp_dir.path()).unwrap();
+        assert_eq!(engine.get("flushed".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(engine.get("logged".to_owned()).unwrap(), Some("1".to_owned()));
+        assert!(read_filter(temp_dir.path(), id).is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::batch::BatchOp;
+use crate::engine::is_empty_range;
+use crate::{Condition, KvsEngine, KvsError, Result, ScanOptions, WriteBatch};
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock};
+
+/// A purely in-memory storage engine.
+///
+/// Nothing is written to disk, so all data is lost when the last clone is dropped.
+/// It is mainly useful for tests and as a reference implementation of [`KvsEngine`].
+#[derive(Clone, Default)]
+pub struct MemoryEngine {
+    map: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemoryEngine {
+    /// Creates a new, empty engine.
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        map.insert(key, value);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        Ok(map.get(key).cloned())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        map.remove(key).map(|_| ()).ok_or(KvsError::KeyNotFound)
+    }
+
+    fn scan_bytes(&self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let map = self.map.read().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let entries = map.range(range).map(|(k, v)| (k.clone(), v.clone()));
+        if options.reverse {
+            Ok(entries.rev().take(limit).collect())
+        } else {
+            Ok(entries.take(limit).collect())
+        }
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        batch.check_removes(|key| map.contains_key(key))?;
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set {key, value} => {
+                    map.insert(key, value);
+                }
+                BatchOp::Remove {key} => {
+                    map.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_if(&self, key: Vec<u8>, condition: Condition, new: Option<Vec<u8>>) -> Result<()> {
+        let mut map = self.map.write().map_err(|_| KvsError::Internal("RwLock poisoned".into()))?;
+        if !condition.matches(map.get(&key).map(Vec::as_slice)) {
+            return Err(KvsError::ConditionFailed);
+        }
+        match new {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,21 +1,75 @@
+use crate::{ScanOptions, StoreStats, WriteBatch};
 use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// Represents a request sent from a client to the key-value store server.
+///
+/// Keys and values are raw bytes. They are encoded exactly like the `String`s earlier
+/// versions of the protocol used, so old clients keep working.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     /// Get the value of a key.
-    Get { key: String },
+    Get { key: Vec<u8> },
     /// Set the value of a key.
-    Set { key: String, value: String },
+    Set { key: Vec<u8>, value: Vec<u8> },
     /// Remove a key.
-    Remove { key: String },
+    Remove { key: Vec<u8> },
+    /// Set the value of a key that expires once `ttl` has passed.
+    SetEx { key: Vec<u8>, value: Vec<u8>, ttl: Duration },
+    /// Get how long a key has left to live.
+    Ttl { key: Vec<u8> },
+    /// Apply a group of writes atomically.
+    Batch { batch: WriteBatch },
+    /// Replace the value of a key with `new` if it currently is `expected`.
+    /// `None` stands for a missing key.
+    CompareAndSwap { key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>> },
+    /// Set the value of a key only if it does not exist.
+    SetIfAbsent { key: Vec<u8>, value: Vec<u8> },
+    /// Set the value of a key only if it exists.
+    SetIfPresent { key: Vec<u8>, value: Vec<u8> },
+    /// List the key-value pairs with keys between `start` and `end`. The server answers with
+    /// one or more `ScanPage` responses. Prefix scans use the range from
+    /// [`prefix_range`](crate::engine::prefix_range).
+    ///
+    /// On engines that support snapshots, every page is read from one snapshot taken when the
+    /// request arrives. On the others each page is read separately, so pages may mix the
+    /// state of the store from before and after writes made while the scan is sent.
+    Scan { start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, options: ScanOptions },
+    /// Start a transaction. Until it is committed or aborted, the connection's `Get`, `Set`,
+    /// `Remove` and `Scan` requests run inside it.
+    Begin,
+    /// Commit the connection's transaction.
+    Commit,
+    /// Discard the connection's transaction.
+    Abort,
+    /// Back the store up into a directory on the server, which must be empty or not exist yet.
+    /// An admin request: `dir` is a relative name within the server's `--backup-root`, and the
+    /// server refuses it if it has none.
+    Backup { dir: PathBuf },
+    /// Replace the contents of the store with the backup in a directory on the server. An admin
+    /// request, with `dir` named like for `Backup`.
+    Restore { dir: PathBuf },
+    /// Get the statistics of the store.
+    Stats,
 }
 
 /// Represents a response sent from the server back to the client.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     /// A successful operation. Contains the value for `Get`, `None` otherwise.
-    Success(Option<String>),
+    Success(Option<Vec<u8>>),
     /// An error occurred during the operation.
     Error(String),
+    /// The time a key has left to live, or `None` if it does not expire. Answers `Ttl`.
+    Ttl(Option<Duration>),
+    /// A conditional write was not applied because the key's current value did not match.
+    ConditionFailed,
+    /// One page of the results of a `Scan`. `done` is set on the last page.
+    ScanPage { entries: Vec<(Vec<u8>, Vec<u8>)>, done: bool },
+    /// A transaction was not committed because another write conflicted with it.
+    Conflict,
+    /// The statistics of the store. Answers `Stats`.
+    Stats(StoreStats),
 }
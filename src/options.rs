@@ -0,0 +1,226 @@
+use crate::{Compression, EncryptionKey, KvStore, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Default number of stale bytes the log may accumulate before it is compacted.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Default size at which the active log segment is sealed and a new one is started.
+const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+/// Default time between two sweeps for expired keys.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Default size the memtable of an `LsmEngine` may grow to before it is flushed to an SSTable.
+const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+/// Default number of bits per key in the bloom filter of each SSTable, for about 1% false positives.
+const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+/// Default size a record must have before it is compressed.
+const DEFAULT_COMPRESSION_MIN_SIZE: usize = 256;
+
+/// Controls when writes are forced to stable storage with `fsync`.
+///
+/// Without a sync, a write only reaches the operating system's page cache and can be
+/// lost on power failure even though it was acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every write, before it returns.
+    Always,
+    /// Writers arriving within `max_delay` of each other share a single sync. Every write
+    /// still returns only once it is on disk, but may wait up to `max_delay` to get there.
+    GroupCommit { max_delay: Duration },
+    /// Sync after every `n` writes. Up to `n - 1` acknowledged writes may be lost on power failure.
+    EveryN(u64),
+    /// Never sync explicitly, leaving it to the operating system to write data back.
+    Never,
+}
+
+/// The point in the history of a `KvStore` to recover it to, as the last write to replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Replay every write up to and including the one with this sequence number, as returned
+    /// by [`Snapshot::seq`](crate::Snapshot::seq).
+    Seq(u64),
+    /// Replay every write made at or before this time.
+    Timestamp(SystemTime),
+}
+
+/// Configuration used when opening a [`KvStore`].
+///
+/// Options are set with builder-style methods and then passed to
+/// [`KvStore::open_with_options`], or used directly through [`KvStoreOptions::open`].
+/// [`LsmEngine::open_with_options`](crate::LsmEngine::open_with_options) accepts the same options.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) compaction_threshold: u64,
+    pub(crate) segment_size: u64,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) sweep_interval: Duration,
+    pub(crate) memtable_size: u64,
+    pub(crate) bloom_bits_per_key: usize,
+    pub(crate) checkpoint_interval: Option<Duration>,
+    pub(crate) compression: Compression,
+    pub(crate) compression_min_size: usize,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) retired_keys: Vec<EncryptionKey>,
+    pub(crate) key_file: Option<PathBuf>,
+    pub(crate) read_only: bool,
+    pub(crate) recovery_target: Option<RecoveryTarget>,
+    pub(crate) stats_interval: Option<Duration>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync_policy: SyncPolicy::Always,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            checkpoint_interval: None,
+            compression: Compression::None,
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            encryption_key: None,
+            retired_keys: Vec::new(),
+            key_file: None,
+            read_only: false,
+            recovery_target: None,
+            stats_interval: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates a new set of options with default values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many bytes of stale log records are tolerated before the
+    /// log is rewritten to contain only live entries.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the size in bytes at which the active log segment is sealed and
+    /// writes move on to a new segment.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Sets when writes are synced to disk. Defaults to [`SyncPolicy::Always`].
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets how often a background thread removes expired keys from the index.
+    ///
+    /// Expired keys are hidden from reads right away; sweeping only reclaims their space.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Sets the size in bytes the memtable of an [`LsmEngine`](crate::LsmEngine) may grow to
+    /// before it is frozen and flushed to an SSTable. Not used by `KvStore`.
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Sets how many bits per key the bloom filter of each new SSTable of an
+    /// [`LsmEngine`](crate::LsmEngine) uses. More bits let fewer lookups of missing keys
+    /// through, at the cost of memory; 0 turns the filters off. Not used by `KvStore`, whose
+    /// in-memory index already knows every key.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits;
+        self
+    }
+
+    /// Makes a `KvStore` write a checkpoint of every key in the background whenever `interval`
+    /// has passed since the last one, and drop the log it covers. The interval is checked as
+    /// writes are made, so an idle store writes no checkpoints. Off by default.
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Makes a `KvStore` log its [`StoreStats`](crate::StoreStats) at the info level through
+    /// `tracing` whenever `interval` has passed, for as long as it is open. Off by default.
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
+    }
+
+    /// Sets the codec new records in the log of a `KvStore` are compressed with. Records
+    /// written with a different codec, or none, still load. Defaults to [`Compression::None`].
+    pub fn compression(mut self, codec: Compression) -> Self {
+        self.compression = codec;
+        self
+    }
+
+    /// Sets the size in bytes a record must have before it is compressed. Smaller records are
+    /// stored as they are, as are records the codec does not shrink.
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.compression_min_size = bytes;
+        self
+    }
+
+    /// Encrypts the records, hint files and checkpoints a `KvStore` writes from now on with
+    /// `key`. Files written under another key need it to be supplied as a retired key until
+    /// they have been rewritten by a compaction or checkpoint.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Adds a key that is only used to read files written before the encryption key was rotated.
+    pub fn retired_key(mut self, key: EncryptionKey) -> Self {
+        self.retired_keys.push(key);
+        self
+    }
+
+    /// Reads keys from a file when the store is opened, one per line as the key's ID and its
+    /// 32 bytes in hex. The last key in the file encrypts new data unless
+    /// [`encryption_key`](KvStoreOptions::encryption_key) is set; the others are retired keys.
+    pub fn key_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.key_file = Some(path.into());
+        self
+    }
+
+    /// Opens a `KvStore` read-only. It does not lock the directory, so it can be opened while
+    /// another process is writing to it, but it only sees the data written before it was
+    /// opened. Every write through it fails with [`KvsError::ReadOnly`](crate::KvsError::ReadOnly).
+    /// Not supported by `LsmEngine`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens a `KvStore` read-only as it was at `target`, by replaying its log only up to there.
+    ///
+    /// Compactions and checkpoints discard the history before them, so opening fails with
+    /// [`KvsError::Unrecoverable`](crate::KvsError::Unrecoverable) if `target` is older than
+    /// the last one. Not supported by `LsmEngine`.
+    pub fn recovery_target(mut self, target: RecoveryTarget) -> Self {
+        self.recovery_target = Some(target);
+        self
+    }
+
+    /// Opens a `KvStore` in the given directory using these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.clone())
+    }
+}
+
+/// Controls the order and number of results returned by a scan.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanOptions {
+    /// The maximum number of key-value pairs to return, or `None` for all of them.
+    pub limit: Option<usize>,
+    /// Returns pairs in descending instead of ascending key order. The limit then keeps
+    /// the last keys of the range.
+    pub reverse: bool,
+}
@@ -0,0 +1,183 @@
+use crate::{CompressionStats, FilterStats, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+// The number of buckets in a latency histogram. Bucket `i` counts latencies below 2^i
+// microseconds, and the last one everything slower, which covers anything up to half an hour.
+const LATENCY_BUCKETS: usize = 32;
+
+/// The operations a [`KvStore`](crate::KvStore) counts and times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Operation {
+    /// Reads of a single key.
+    Get,
+    /// Writes of a single key, with or without an expiry.
+    Set,
+    /// Changes to the expiry of an existing key, made or removed.
+    Expire,
+    /// Writes and removals of a single key that depend on its current value.
+    WriteIf,
+    /// Removals of a single key.
+    Remove,
+    /// Range scans, timed until the matching keys are picked from the index.
+    Scan,
+    /// Atomic batches of writes.
+    Batch,
+}
+
+impl Operation {
+    /// Every operation, in the order [`StoreStats::operations`] lists them.
+    pub const ALL: [Operation; 7] = [
+        Operation::Get,
+        Operation::Set,
+        Operation::Expire,
+        Operation::WriteIf,
+        Operation::Remove,
+        Operation::Scan,
+        Operation::Batch,
+    ];
+}
+
+/// The size and contents of a store, and how it has been used since it was opened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// The number of keys that are set and have not expired.
+    pub keys: u64,
+    /// The size of the segments and checkpoints on disk, in bytes, or of the write-ahead log and
+    /// SSTables of an [`LsmEngine`](crate::LsmEngine).
+    pub total_bytes: u64,
+    /// The bytes of records that hold the current value of a key. An `LsmEngine` counts the
+    /// bytes of the keys and values themselves.
+    pub live_bytes: u64,
+    /// The number of segments the log, or write-ahead log, is made of.
+    pub segments: u64,
+    /// When the last compaction finished, or `None` if there was none since the store was opened.
+    /// It is only kept in memory, so a store that was reopened does not know of compactions
+    /// made before.
+    pub last_compaction: Option<SystemTime>,
+    /// How much compression has shrunk the records written since the store was opened.
+    pub compression: CompressionStats,
+    /// The counts and latencies of each operation, in the order of [`Operation::ALL`], or none
+    /// if the engine does not time its operations.
+    pub operations: Vec<OperationStats>,
+    /// How well the bloom filters of an [`LsmEngine`](crate::LsmEngine) are doing, or `None`
+    /// for engines without them.
+    pub filters: Option<FilterStats>,
+}
+
+impl StoreStats {
+    /// Returns the bytes on disk that do not hold the current value of a key, which are mostly
+    /// reclaimed by the next compaction.
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.live_bytes)
+    }
+
+    /// Returns the counts and latencies of one operation.
+    pub fn operation(&self, operation: Operation) -> Option<&OperationStats> {
+        self.operations.iter().find(|stats| stats.operation == operation)
+    }
+}
+
+/// How often an operation was called and how long it took.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationStats {
+    pub operation: Operation,
+    /// The number of calls, including failed ones.
+    pub count: u64,
+    /// The number of calls that returned an error, such as a missing key or a failed condition.
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// A histogram of latencies with buckets that double in width.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// Bucket `i` counts the latencies below [`LatencyHistogram::bucket_bound`]`(i)` that do
+    /// not fit an earlier bucket. The last bucket also counts every slower one.
+    pub buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    /// Returns the upper bound of a bucket.
+    pub fn bucket_bound(bucket: usize) -> Duration {
+        Duration::from_micros(1 << bucket)
+    }
+
+    /// Returns the number of latencies in the histogram.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns a latency at least as high as the given share of all latencies, between 0 and 1,
+    /// as the upper bound of the bucket it falls in, or `None` if the histogram is empty.
+    pub fn percentile(&self, share: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((share.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Self::bucket_bound(bucket));
+            }
+        }
+        Some(Self::bucket_bound(self.buckets.len() - 1))
+    }
+}
+
+impl fmt::Display for OperationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {} calls, {} errors", self.operation, self.count, self.errors)?;
+        if let (Some(p50), Some(p99)) = (self.latency.percentile(0.5), self.latency.percentile(0.99)) {
+            write!(f, ", p50 < {:?}, p99 < {:?}", p50, p99)?;
+        }
+        Ok(())
+    }
+}
+
+// The counters behind `OperationStats`, shared by every handle to a store.
+#[derive(Debug, Default)]
+pub(crate) struct OperationCounters {
+    operations: [Counters; Operation::ALL.len()],
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl OperationCounters {
+    // Records a call of an operation that started at `started` and returned `result`.
+    pub(crate) fn record<T>(&self, operation: Operation, started: Instant, result: &Result<T>) {
+        let counters = &self.operations[operation as usize];
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        // The first bucket whose bound is above the latency.
+        let micros = started.elapsed().as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        counters.buckets[bucket.min(LATENCY_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> Vec<OperationStats> {
+        Operation::ALL
+            .iter()
+            .zip(&self.operations)
+            .map(|(&operation, counters)| OperationStats {
+                operation,
+                count: counters.count.load(Ordering::Relaxed),
+                errors: counters.errors.load(Ordering::Relaxed),
+                latency: LatencyHistogram {
+                    buckets: counters.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+                },
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,122 @@
+use crate::engine::is_empty_range;
+use crate::{KvStore, KvsError, Result, ScanOptions, Snapshot, WriteBatch};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
+
+// An owned key range, as recorded by a scan.
+pub(crate) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// An interactive transaction with serializable isolation, created by [`KvStore::begin`].
+///
+/// Reads see a snapshot of the store taken when the transaction began, together with the
+/// transaction's own writes. Writes are buffered and applied atomically by
+/// [`Transaction::commit`], which fails with [`KvsError::Conflict`] if any key the
+/// transaction read was written by someone else in the meantime. Dropping a transaction
+/// without committing it discards its writes.
+pub struct Transaction {
+    store: KvStore,
+    snapshot: Snapshot,
+    // Keys whose value the transaction depends on.
+    reads: BTreeSet<Vec<u8>>,
+    // Ranges the transaction scanned. Any write into them after the snapshot is a conflict.
+    ranges: Vec<KeyRange>,
+    // Buffered writes. `None` removes the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(store: KvStore, snapshot: Snapshot) -> Transaction {
+        Transaction {
+            store,
+            snapshot,
+            reads: BTreeSet::new(),
+            ranges: Vec::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a key as seen by the transaction.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_vec());
+        self.snapshot.get_bytes(key)
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Removes a key when the transaction commits.
+    ///
+    /// Errors with [`KvsError::KeyNotFound`] if the key does not exist as seen by the transaction.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.reads.insert(key.to_vec());
+        let in_snapshot = self.snapshot.get_bytes(key)?.is_some();
+
+        match self.writes.get(key) {
+            Some(None) => Err(KvsError::KeyNotFound),
+            None if !in_snapshot => Err(KvsError::KeyNotFound),
+            // A key the transaction created itself only has to be forgotten.
+            Some(Some(_)) if !in_snapshot => {
+                self.writes.remove(key);
+                Ok(())
+            }
+            _ => {
+                self.writes.insert(key.to_vec(), None);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the key-value pairs within `range` as seen by the transaction, limited and
+    /// ordered as set by `options`.
+    pub fn scan(&mut self, range: impl RangeBounds<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+
+        let mut entries: BTreeMap<Vec<u8>, Vec<u8>> = self
+            .snapshot
+            .scan_bytes(range.clone(), ScanOptions::default())?
+            .into_iter()
+            .collect();
+        for (key, value) in self.writes.range::<Vec<u8>, _>((range.0.as_ref(), range.1.as_ref())) {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        self.ranges.push(range);
+
+        let limit = options.limit.unwrap_or(usize::MAX);
+        if options.reverse {
+            Ok(entries.into_iter().rev().take(limit).collect())
+        } else {
+            Ok(entries.into_iter().take(limit).collect())
+        }
+    }
+
+    /// Applies the transaction's writes atomically.
+    ///
+    /// Errors with [`KvsError::Conflict`], writing nothing, if a key the transaction read or
+    /// a range it scanned was written after the transaction began.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.store.commit_transaction(self.snapshot.seq(), &self.reads, &self.ranges, batch)
+    }
+
+    /// Discards the transaction's writes.
+    pub fn abort(self) {}
+}
@@ -0,0 +1,100 @@
+use crate::{KvsError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Name of the file locked by the process that has a data directory open for writing.
+const LOCK_FILE: &str = "LOCK";
+
+// An exclusive advisory lock on a data directory, released when it is dropped. The lock file
+// holds the PID of the process holding it, so others can report who has the directory open.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    // Locks the directory, failing with `KvsError::AlreadyLocked` if another process, or
+    // another handle in this one, already holds the lock.
+    pub(crate) fn acquire(dir: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                // The PID is missing if the holder has not written it yet.
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(KvsError::AlreadyLocked {pid: pid.trim().parse().ok()});
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        file.write_all(process::id().to_string().as_bytes())?;
+        Ok(DirLock {_file: file})
+    }
+}
+
+// Fills `buf` with bytes read from `file` starting at `offset`, without moving a shared cursor.
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.read_exact_at(buf, offset)?;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.seek_read(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                n => read += n,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes all of `buf` to `file` starting at `offset`, without moving a shared cursor.
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.write_all_at(buf, offset)?;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut written = 0;
+        while written < buf.len() {
+            match file.seek_write(&buf[written..], offset + written as u64)? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+                n => written += n,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Flushes the directory's entries to disk, so that renames and new files survive a crash.
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// Returns the current time in milliseconds since the UNIX epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}